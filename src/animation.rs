@@ -0,0 +1,779 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Keyframe animations of layer properties that the compositor runs by itself, so that they
+//! keep going while the thread that owns the content is busy. Timing follows CSS animations;
+//! see https://drafts.csswg.org/css-animations/ and https://drafts.csswg.org/web-animations/.
+
+use color::Color;
+
+use euclid::matrix::Matrix4;
+
+/// Where the jump of a `TimingFunction::Steps` happens within each step.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StepPosition {
+    Start,
+    End,
+}
+
+/// How the progress of an animation between two keyframes is eased, as in CSS
+/// `animation-timing-function`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TimingFunction {
+    Linear,
+
+    /// A cubic bezier curve from (0, 0) to (1, 1) with the two given control points. The x
+    /// coordinates of the control points must be in the range from 0.0 to 1.0.
+    CubicBezier(f32, f32, f32, f32),
+
+    /// A step function with the given number of equal steps.
+    Steps(u32, StepPosition),
+}
+
+impl TimingFunction {
+    pub fn ease() -> TimingFunction {
+        TimingFunction::CubicBezier(0.25, 0.1, 0.25, 1.0)
+    }
+
+    pub fn ease_in() -> TimingFunction {
+        TimingFunction::CubicBezier(0.42, 0.0, 1.0, 1.0)
+    }
+
+    pub fn ease_out() -> TimingFunction {
+        TimingFunction::CubicBezier(0.0, 0.0, 0.58, 1.0)
+    }
+
+    pub fn ease_in_out() -> TimingFunction {
+        TimingFunction::CubicBezier(0.42, 0.0, 0.58, 1.0)
+    }
+
+    /// Returns the eased progress for the input progress `x`, which is in the range from 0.0 to
+    /// 1.0. Cubic bezier curves may overshoot that range.
+    pub fn value(&self, x: f32) -> f32 {
+        match *self {
+            TimingFunction::Linear => x,
+            TimingFunction::CubicBezier(x1, y1, x2, y2) => {
+                let t = solve_bezier_for_x(x, x1, x2);
+                bezier_component(t, y1, y2)
+            }
+            TimingFunction::Steps(steps, position) => {
+                if x >= 1.0 {
+                    return 1.0;
+                }
+                let steps = steps.max(1) as f32;
+                let step = match position {
+                    StepPosition::Start => (x * steps).floor() + 1.0,
+                    StepPosition::End => (x * steps).floor(),
+                };
+                (step / steps).max(0.0).min(1.0)
+            }
+        }
+    }
+}
+
+/// Evaluates one component of a cubic bezier curve from 0.0 to 1.0 with the control point
+/// components `p1` and `p2` at the parameter `t`.
+fn bezier_component(t: f32, p1: f32, p2: f32) -> f32 {
+    let a = 1.0 - 3.0 * p2 + 3.0 * p1;
+    let b = 3.0 * p2 - 6.0 * p1;
+    let c = 3.0 * p1;
+    ((a * t + b) * t + c) * t
+}
+
+/// Finds the parameter of a cubic bezier curve at which its x component is `x`. Newton's
+/// method converges quickly for most curves, and bisection handles the rest.
+fn solve_bezier_for_x(x: f32, x1: f32, x2: f32) -> f32 {
+    const EPSILON: f32 = 1.0e-6;
+
+    let mut t = x;
+    for _ in 0..8 {
+        let error = bezier_component(t, x1, x2) - x;
+        if error.abs() < EPSILON {
+            return t;
+        }
+        let a = 1.0 - 3.0 * x2 + 3.0 * x1;
+        let b = 3.0 * x2 - 6.0 * x1;
+        let c = 3.0 * x1;
+        let derivative = (3.0 * a * t + 2.0 * b) * t + c;
+        if derivative.abs() < EPSILON {
+            break;
+        }
+        t -= error / derivative;
+    }
+
+    let (mut low, mut high) = (0.0, 1.0);
+    t = x.max(0.0).min(1.0);
+    for _ in 0..32 {
+        let value = bezier_component(t, x1, x2);
+        if (value - x).abs() < EPSILON {
+            break;
+        }
+        if value < x {
+            low = t;
+        } else {
+            high = t;
+        }
+        t = (low + high) * 0.5;
+    }
+    t
+}
+
+/// How an animation affects its property outside of its active interval, as in CSS
+/// `animation-fill-mode`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FillMode {
+    /// The property is only animated while the animation is active.
+    None,
+
+    /// The value at the end of the animation is kept after it finishes.
+    Forwards,
+
+    /// The value at the start of the animation is used during the delay.
+    Backwards,
+
+    Both,
+}
+
+/// A value of an animated layer property.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AnimatedValue {
+    Transform(Matrix4),
+    Opacity(f32),
+
+    /// A background color, with premultiplied alpha.
+    BackgroundColor(Color),
+}
+
+impl AnimatedValue {
+    /// Interpolates from this value to `other` by `progress`. Transforms are decomposed and
+    /// interpolated component-wise; values that can't be interpolated flip halfway through.
+    pub fn interpolate(&self, other: &AnimatedValue, progress: f32) -> AnimatedValue {
+        match (*self, *other) {
+            // Timing functions may overshoot, which must not take opacity or color out of
+            // range.
+            (AnimatedValue::Opacity(from), AnimatedValue::Opacity(to)) => {
+                AnimatedValue::Opacity(clamp_unit(lerp(from, to, progress)))
+            }
+            (AnimatedValue::BackgroundColor(from), AnimatedValue::BackgroundColor(to)) => {
+                // Colors are premultiplied, so no component may exceed the alpha.
+                let a = clamp_unit(lerp(from.a, to.a, progress));
+                AnimatedValue::BackgroundColor(Color {
+                    r: clamp_unit(lerp(from.r, to.r, progress)).min(a),
+                    g: clamp_unit(lerp(from.g, to.g, progress)).min(a),
+                    b: clamp_unit(lerp(from.b, to.b, progress)).min(a),
+                    a: a,
+                })
+            }
+            (AnimatedValue::Transform(from), AnimatedValue::Transform(to)) => {
+                match (DecomposedMatrix::new(&from), DecomposedMatrix::new(&to)) {
+                    (Some(from), Some(to)) => {
+                        AnimatedValue::Transform(from.interpolate(&to, progress).recompose())
+                    }
+                    _ if progress < 0.5 => *self,
+                    _ => *other,
+                }
+            }
+            _ if progress < 0.5 => *self,
+            _ => *other,
+        }
+    }
+}
+
+fn lerp(from: f32, to: f32, progress: f32) -> f32 {
+    from + (to - from) * progress
+}
+
+fn clamp_unit(value: f32) -> f32 {
+    value.max(0.0).min(1.0)
+}
+
+/// A 4x4 matrix indexed by row and column, with the same layout as `Matrix4`: points are row
+/// vectors multiplied on the left, so the translation is in the last row.
+type Matrix4Array = [[f32; 4]; 4];
+
+const IDENTITY_MATRIX: Matrix4Array = [[1.0, 0.0, 0.0, 0.0],
+                                       [0.0, 1.0, 0.0, 0.0],
+                                       [0.0, 0.0, 1.0, 0.0],
+                                       [0.0, 0.0, 0.0, 1.0]];
+
+fn matrix_to_array(m: &Matrix4) -> Matrix4Array {
+    [[m.m11, m.m12, m.m13, m.m14],
+     [m.m21, m.m22, m.m23, m.m24],
+     [m.m31, m.m32, m.m33, m.m34],
+     [m.m41, m.m42, m.m43, m.m44]]
+}
+
+fn array_to_matrix(a: &Matrix4Array) -> Matrix4 {
+    Matrix4::new(a[0][0], a[0][1], a[0][2], a[0][3],
+                 a[1][0], a[1][1], a[1][2], a[1][3],
+                 a[2][0], a[2][1], a[2][2], a[2][3],
+                 a[3][0], a[3][1], a[3][2], a[3][3])
+}
+
+fn multiply_matrices(a: &Matrix4Array, b: &Matrix4Array) -> Matrix4Array {
+    let mut result = [[0.0; 4]; 4];
+    for i in 0..4 {
+        for j in 0..4 {
+            result[i][j] = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    result
+}
+
+fn dot(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: &[f32; 3], b: &[f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1],
+     a[2] * b[0] - a[0] * b[2],
+     a[0] * b[1] - a[1] * b[0]]
+}
+
+/// Returns `a * a_scale + b * b_scale`.
+fn combine(a: &[f32; 3], b: &[f32; 3], a_scale: f32, b_scale: f32) -> [f32; 3] {
+    [a[0] * a_scale + b[0] * b_scale,
+     a[1] * a_scale + b[1] * b_scale,
+     a[2] * a_scale + b[2] * b_scale]
+}
+
+/// A transform split into the components that are interpolated separately, as described in
+/// https://drafts.csswg.org/css-transforms-2/#decomposing-a-3d-matrix. The transform is the
+/// scale, then the skew, the rotation, the translation and finally the perspective.
+#[derive(Clone, Copy, Debug)]
+struct DecomposedMatrix {
+    translation: [f32; 3],
+    scale: [f32; 3],
+
+    /// The XY, XZ and YZ shear factors.
+    skew: [f32; 3],
+
+    perspective: [f32; 4],
+
+    /// The rotation as a unit quaternion (x, y, z, w).
+    quaternion: [f32; 4],
+}
+
+impl DecomposedMatrix {
+    /// Decomposes `matrix`, or returns `None` if it is singular.
+    fn new(matrix: &Matrix4) -> Option<DecomposedMatrix> {
+        let mut m = matrix_to_array(matrix);
+        if m[3][3] == 0.0 {
+            return None;
+        }
+        let w = m[3][3];
+        for row in m.iter_mut() {
+            for value in row.iter_mut() {
+                *value /= w;
+            }
+        }
+
+        // The matrix without perspective, which is also used to solve for the perspective.
+        let mut affine = m;
+        for i in 0..3 {
+            affine[i][3] = 0.0;
+        }
+        affine[3][3] = 1.0;
+        let upper_rows = [[m[0][0], m[0][1], m[0][2]],
+                          [m[1][0], m[1][1], m[1][2]],
+                          [m[2][0], m[2][1], m[2][2]]];
+        if dot(&upper_rows[0], &cross(&upper_rows[1], &upper_rows[2])).abs() < 1.0e-8 {
+            return None;
+        }
+
+        let perspective = if m[0][3] != 0.0 || m[1][3] != 0.0 || m[2][3] != 0.0 {
+            let right_hand_side = [m[0][3], m[1][3], m[2][3], m[3][3]];
+            let inverse = matrix_to_array(&array_to_matrix(&affine).invert());
+            let mut perspective = [0.0; 4];
+            for i in 0..4 {
+                perspective[i] = (0..4).map(|j| inverse[i][j] * right_hand_side[j]).sum();
+            }
+            perspective
+        } else {
+            [0.0, 0.0, 0.0, 1.0]
+        };
+
+        let translation = [m[3][0], m[3][1], m[3][2]];
+
+        let mut rows = upper_rows;
+        let mut scale = [0.0; 3];
+        let mut skew = [0.0; 3];
+
+        scale[0] = dot(&rows[0], &rows[0]).sqrt();
+        rows[0] = combine(&rows[0], &rows[0], 1.0 / scale[0], 0.0);
+
+        skew[0] = dot(&rows[0], &rows[1]);
+        rows[1] = combine(&rows[1], &rows[0], 1.0, -skew[0]);
+
+        scale[1] = dot(&rows[1], &rows[1]).sqrt();
+        rows[1] = combine(&rows[1], &rows[1], 1.0 / scale[1], 0.0);
+        skew[0] /= scale[1];
+
+        skew[1] = dot(&rows[0], &rows[2]);
+        rows[2] = combine(&rows[2], &rows[0], 1.0, -skew[1]);
+        skew[2] = dot(&rows[1], &rows[2]);
+        rows[2] = combine(&rows[2], &rows[1], 1.0, -skew[2]);
+
+        scale[2] = dot(&rows[2], &rows[2]).sqrt();
+        rows[2] = combine(&rows[2], &rows[2], 1.0 / scale[2], 0.0);
+        skew[1] /= scale[2];
+        skew[2] /= scale[2];
+
+        // If the coordinate system is flipped, negate the scale and the rows.
+        if dot(&rows[0], &cross(&rows[1], &rows[2])) < 0.0 {
+            for i in 0..3 {
+                scale[i] = -scale[i];
+                rows[i] = combine(&rows[i], &rows[i], -1.0, 0.0);
+            }
+        }
+
+        let mut quaternion = [
+            0.5 * (1.0 + rows[0][0] - rows[1][1] - rows[2][2]).max(0.0).sqrt(),
+            0.5 * (1.0 - rows[0][0] + rows[1][1] - rows[2][2]).max(0.0).sqrt(),
+            0.5 * (1.0 - rows[0][0] - rows[1][1] + rows[2][2]).max(0.0).sqrt(),
+            0.5 * (1.0 + rows[0][0] + rows[1][1] + rows[2][2]).max(0.0).sqrt(),
+        ];
+        if rows[2][1] > rows[1][2] {
+            quaternion[0] = -quaternion[0];
+        }
+        if rows[0][2] > rows[2][0] {
+            quaternion[1] = -quaternion[1];
+        }
+        if rows[1][0] > rows[0][1] {
+            quaternion[2] = -quaternion[2];
+        }
+
+        Some(DecomposedMatrix {
+            translation: translation,
+            scale: scale,
+            skew: skew,
+            perspective: perspective,
+            quaternion: quaternion,
+        })
+    }
+
+    /// Interpolates each component linearly, except for the rotation, which is interpolated
+    /// along the shortest arc.
+    fn interpolate(&self, other: &DecomposedMatrix, progress: f32) -> DecomposedMatrix {
+        let mut result = *self;
+        for i in 0..3 {
+            result.translation[i] = lerp(self.translation[i], other.translation[i], progress);
+            result.scale[i] = lerp(self.scale[i], other.scale[i], progress);
+            result.skew[i] = lerp(self.skew[i], other.skew[i], progress);
+        }
+        for i in 0..4 {
+            result.perspective[i] = lerp(self.perspective[i], other.perspective[i], progress);
+        }
+
+        let (from, to) = (self.quaternion, other.quaternion);
+        let product = (0..4).map(|i| from[i] * to[i]).sum::<f32>().max(-1.0).min(1.0);
+        if product.abs() < 1.0 {
+            let theta = product.acos();
+            let w = (progress * theta).sin() / (1.0 - product * product).sqrt();
+            let from_scale = (progress * theta).cos() - product * w;
+            for i in 0..4 {
+                result.quaternion[i] = from[i] * from_scale + to[i] * w;
+            }
+        }
+        result
+    }
+
+    /// Builds the transform that this is the decomposition of.
+    fn recompose(&self) -> Matrix4 {
+        let mut scale = IDENTITY_MATRIX;
+        for i in 0..3 {
+            scale[i][i] = self.scale[i];
+        }
+
+        let mut skew = IDENTITY_MATRIX;
+        skew[1][0] = self.skew[0];
+        skew[2][0] = self.skew[1];
+        skew[2][1] = self.skew[2];
+
+        // The transpose of the usual rotation matrix of the quaternion, since points are row
+        // vectors.
+        let (x, y, z, w) = (self.quaternion[0], self.quaternion[1],
+                            self.quaternion[2], self.quaternion[3]);
+        let rotation = [[1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y + z * w),
+                         2.0 * (x * z - y * w), 0.0],
+                        [2.0 * (x * y - z * w), 1.0 - 2.0 * (x * x + z * z),
+                         2.0 * (y * z + x * w), 0.0],
+                        [2.0 * (x * z + y * w), 2.0 * (y * z - x * w),
+                         1.0 - 2.0 * (x * x + y * y), 0.0],
+                        [0.0, 0.0, 0.0, 1.0]];
+
+        let mut translation = IDENTITY_MATRIX;
+        for i in 0..3 {
+            translation[3][i] = self.translation[i];
+        }
+
+        let mut perspective = IDENTITY_MATRIX;
+        for i in 0..4 {
+            perspective[i][3] = self.perspective[i];
+        }
+
+        let matrix = multiply_matrices(&scale, &skew);
+        let matrix = multiply_matrices(&matrix, &rotation);
+        let matrix = multiply_matrices(&matrix, &translation);
+        array_to_matrix(&multiply_matrices(&matrix, &perspective))
+    }
+}
+
+/// The value of an animated property at a point in an iteration of an animation.
+#[derive(Clone, Copy, Debug)]
+pub struct Keyframe {
+    /// Where in the iteration the keyframe is, from 0.0 to 1.0.
+    pub offset: f32,
+
+    pub value: AnimatedValue,
+
+    /// How the progress towards the next keyframe is eased.
+    pub timing_function: TimingFunction,
+}
+
+impl Keyframe {
+    pub fn new(offset: f32, value: AnimatedValue, timing_function: TimingFunction) -> Keyframe {
+        Keyframe {
+            offset: offset,
+            value: value,
+            timing_function: timing_function,
+        }
+    }
+}
+
+/// A keyframe animation of a single layer property. Times are in seconds, on the same clock as
+/// the times passed to `Scene::tick`.
+#[derive(Clone, Debug)]
+pub struct Animation {
+    /// The keyframes in order of their offsets. They all hold values of the same property.
+    pub keyframes: Vec<Keyframe>,
+
+    /// When the animation was started.
+    pub start_time: f64,
+
+    /// How long the animation waits after being started before it becomes active.
+    pub delay: f64,
+
+    /// How long a single iteration lasts.
+    pub duration: f64,
+
+    /// How many times the animation repeats. It may be fractional, and is infinite for
+    /// animations that repeat forever.
+    pub iteration_count: f64,
+
+    pub fill_mode: FillMode,
+}
+
+impl Animation {
+    /// Creates an animation that runs through `keyframes` once, starting at `start_time`.
+    pub fn new(keyframes: Vec<Keyframe>, start_time: f64, duration: f64) -> Animation {
+        Animation {
+            keyframes: keyframes,
+            start_time: start_time,
+            delay: 0.0,
+            duration: duration,
+            iteration_count: 1.0,
+            fill_mode: FillMode::None,
+        }
+    }
+
+    fn active_duration(&self) -> f64 {
+        if self.duration <= 0.0 || self.iteration_count <= 0.0 {
+            return 0.0;
+        }
+        self.duration * self.iteration_count
+    }
+
+    fn fills_backwards(&self) -> bool {
+        self.fill_mode == FillMode::Backwards || self.fill_mode == FillMode::Both
+    }
+
+    /// Whether the value at the end of the animation is kept after it finishes.
+    pub fn fills_forwards(&self) -> bool {
+        self.fill_mode == FillMode::Forwards || self.fill_mode == FillMode::Both
+    }
+
+    /// Whether the animation has reached its end at `time`.
+    pub fn is_finished(&self, time: f64) -> bool {
+        time - self.start_time - self.delay >= self.active_duration()
+    }
+
+    /// Returns the progress through the current iteration at `time`, or `None` if the
+    /// animation doesn't apply at that time.
+    fn iteration_progress(&self, time: f64) -> Option<f32> {
+        let local_time = time - self.start_time - self.delay;
+        if local_time < 0.0 {
+            return if self.fills_backwards() { Some(0.0) } else { None };
+        }
+
+        let active_duration = self.active_duration();
+        if local_time >= active_duration {
+            if !self.fills_forwards() {
+                return None;
+            }
+            // The animation ends partway through an iteration if the count is fractional.
+            let fraction = self.iteration_count.fract();
+            return Some(if self.iteration_count > 0.0 && fraction != 0.0 {
+                fraction as f32
+            } else if self.iteration_count > 0.0 {
+                1.0
+            } else {
+                0.0
+            });
+        }
+
+        Some((local_time / self.duration).fract() as f32)
+    }
+
+    /// Returns the value of the animated property at `time`, or `None` if the animation doesn't
+    /// apply at that time.
+    pub fn sample(&self, time: f64) -> Option<AnimatedValue> {
+        let progress = match self.iteration_progress(time) {
+            Some(progress) => progress,
+            None => return None,
+        };
+
+        let (first, last) = match (self.keyframes.first(), self.keyframes.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return None,
+        };
+        if progress <= first.offset {
+            return Some(first.value);
+        }
+        if progress >= last.offset {
+            return Some(last.value);
+        }
+
+        for pair in self.keyframes.windows(2) {
+            let (from, to) = (&pair[0], &pair[1]);
+            if progress >= to.offset {
+                continue;
+            }
+            let length = to.offset - from.offset;
+            if length <= 0.0 {
+                return Some(to.value);
+            }
+            let eased = from.timing_function.value((progress - from.offset) / length);
+            return Some(from.value.interpolate(&to.value, eased));
+        }
+        Some(last.value)
+    }
+}
+
+/// The animations running on a layer, along with the values that the animated properties had
+/// before they were animated, which are restored once no animation applies to them.
+pub struct AnimationSet {
+    /// The animations in the order they were added. Later animations take precedence over
+    /// earlier ones that animate the same property.
+    pub animations: Vec<Animation>,
+
+    base_transform: Option<Matrix4>,
+    base_opacity: Option<f32>,
+    base_background_color: Option<Color>,
+}
+
+impl AnimationSet {
+    pub fn new() -> AnimationSet {
+        AnimationSet {
+            animations: vec!(),
+            base_transform: None,
+            base_opacity: None,
+            base_background_color: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.animations.is_empty()
+    }
+
+    /// Updates the animated properties to their values at `time`, and throws out animations that
+    /// have finished and don't fill forwards. Returns true if any animation is still running.
+    ///
+    /// Changes made to a property while it is animated are overwritten, and are lost once the
+    /// value it had before being animated is restored.
+    pub fn tick(&mut self,
+                time: f64,
+                transform: &mut Matrix4,
+                opacity: &mut f32,
+                background_color: &mut Color)
+                -> bool {
+        let mut transform_value = None;
+        let mut opacity_value = None;
+        let mut background_color_value = None;
+        let mut running = false;
+
+        for animation in self.animations.iter() {
+            match animation.sample(time) {
+                Some(AnimatedValue::Transform(value)) => transform_value = Some(value),
+                Some(AnimatedValue::Opacity(value)) => opacity_value = Some(value),
+                Some(AnimatedValue::BackgroundColor(value)) => {
+                    background_color_value = Some(value)
+                }
+                None => {}
+            }
+            running = running || !animation.is_finished(time);
+        }
+
+        self.animations.retain(|animation| !animation.is_finished(time) ||
+                                           animation.fills_forwards());
+
+        apply_animated_value(&mut self.base_transform, transform_value, transform);
+        apply_animated_value(&mut self.base_opacity, opacity_value, opacity);
+        apply_animated_value(&mut self.base_background_color,
+                             background_color_value,
+                             background_color);
+        running
+    }
+
+    /// Removes all animations and restores the values the animated properties had before.
+    pub fn clear(&mut self,
+                 transform: &mut Matrix4,
+                 opacity: &mut f32,
+                 background_color: &mut Color) {
+        self.animations.clear();
+        apply_animated_value(&mut self.base_transform, None, transform);
+        apply_animated_value(&mut self.base_opacity, None, opacity);
+        apply_animated_value(&mut self.base_background_color, None, background_color);
+    }
+}
+
+/// Sets `property` to `value`, remembering its previous value in `base` the first time. Without
+/// a value, the remembered value is restored.
+fn apply_animated_value<V: Copy>(base: &mut Option<V>, value: Option<V>, property: &mut V) {
+    match value {
+        Some(value) => {
+            if base.is_none() {
+                *base = Some(*property);
+            }
+            *property = value;
+        }
+        None => {
+            if let Some(base) = base.take() {
+                *property = base;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnimatedValue, Animation, DecomposedMatrix, Keyframe, StepPosition};
+    use super::{TimingFunction, matrix_to_array};
+    use color::Color;
+
+    use euclid::matrix::Matrix4;
+
+    fn assert_matrices_near(actual: &Matrix4, expected: &Matrix4) {
+        let (actual, expected) = (matrix_to_array(actual), matrix_to_array(expected));
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((actual[i][j] - expected[i][j]).abs() < 1.0e-4,
+                        "{:?} != {:?}", actual, expected);
+            }
+        }
+    }
+
+    fn rotation_z(degrees: f32) -> Matrix4 {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Matrix4::new(cos, sin, 0.0, 0.0,
+                     -sin, cos, 0.0, 0.0,
+                     0.0, 0.0, 1.0, 0.0,
+                     0.0, 0.0, 0.0, 1.0)
+    }
+
+    fn round_trip(matrix: &Matrix4) -> Matrix4 {
+        DecomposedMatrix::new(matrix).unwrap().recompose()
+    }
+
+    #[test]
+    fn decompose_and_recompose_translation_and_scale() {
+        let matrix = Matrix4::identity().translate(10.0, -20.0, 5.0).scale(2.0, 0.5, 3.0);
+        assert_matrices_near(&round_trip(&matrix), &matrix);
+    }
+
+    #[test]
+    fn decompose_and_recompose_rotation() {
+        let matrix = rotation_z(30.0).mul(&Matrix4::identity().translate(4.0, 8.0, 0.0));
+        assert_matrices_near(&round_trip(&matrix), &matrix);
+    }
+
+    #[test]
+    fn decompose_and_recompose_skew_and_flip() {
+        let matrix = Matrix4::new(-1.0, 0.0, 0.0, 0.0,
+                                  0.5, 2.0, 0.0, 0.0,
+                                  0.0, 0.0, 1.0, 0.0,
+                                  3.0, 4.0, 0.0, 1.0);
+        assert_matrices_near(&round_trip(&matrix), &matrix);
+    }
+
+    #[test]
+    fn decompose_and_recompose_perspective() {
+        let matrix = Matrix4::new(1.0, 0.0, 0.0, 0.0,
+                                  0.0, 1.0, 0.0, 0.0,
+                                  0.0, 0.0, 1.0, -0.001,
+                                  20.0, 30.0, 0.0, 1.0);
+        assert_matrices_near(&round_trip(&matrix), &matrix);
+    }
+
+    #[test]
+    fn singular_matrices_are_not_decomposed() {
+        let matrix = Matrix4::identity().scale(0.0, 1.0, 1.0);
+        assert!(DecomposedMatrix::new(&matrix).is_none());
+    }
+
+    #[test]
+    fn rotations_interpolate_along_the_shortest_arc() {
+        let value = AnimatedValue::Transform(Matrix4::identity())
+            .interpolate(&AnimatedValue::Transform(rotation_z(90.0)), 0.5);
+        match value {
+            AnimatedValue::Transform(matrix) => assert_matrices_near(&matrix, &rotation_z(45.0)),
+            _ => panic!("expected a transform"),
+        }
+    }
+
+    #[test]
+    fn interpolation_stays_in_range_when_overshooting() {
+        let opacity = AnimatedValue::Opacity(0.2).interpolate(&AnimatedValue::Opacity(1.0), 1.5);
+        assert_eq!(opacity, AnimatedValue::Opacity(1.0));
+        let opacity = AnimatedValue::Opacity(0.2).interpolate(&AnimatedValue::Opacity(1.0), -0.5);
+        assert_eq!(opacity, AnimatedValue::Opacity(0.0));
+
+        let from = AnimatedValue::BackgroundColor(Color { r: 0.0, g: 0.5, b: 0.0, a: 0.5 });
+        let to = AnimatedValue::BackgroundColor(Color { r: 1.0, g: 0.0, b: 0.0, a: 1.0 });
+        match from.interpolate(&to, 1.5) {
+            AnimatedValue::BackgroundColor(color) => {
+                assert_eq!(color, Color { r: 1.0, g: 0.0, b: 0.0, a: 1.0 });
+            }
+            _ => panic!("expected a color"),
+        }
+    }
+
+    #[test]
+    fn timing_functions() {
+        assert!(TimingFunction::ease().value(0.0).abs() < 1.0e-4);
+        assert!((TimingFunction::ease().value(1.0) - 1.0).abs() < 1.0e-4);
+        assert!((TimingFunction::ease_in_out().value(0.5) - 0.5).abs() < 1.0e-4);
+        assert_eq!(TimingFunction::Steps(4, StepPosition::End).value(0.3), 0.25);
+        assert_eq!(TimingFunction::Steps(4, StepPosition::Start).value(0.3), 0.5);
+    }
+
+    #[test]
+    fn animations_only_apply_while_active() {
+        let keyframes = vec!(Keyframe::new(0.0, AnimatedValue::Opacity(0.0),
+                                           TimingFunction::Linear),
+                             Keyframe::new(1.0, AnimatedValue::Opacity(1.0),
+                                           TimingFunction::Linear));
+        let animation = Animation::new(keyframes, 1.0, 2.0);
+        assert_eq!(animation.sample(0.5), None);
+        assert_eq!(animation.sample(2.0), Some(AnimatedValue::Opacity(0.5)));
+        assert_eq!(animation.sample(3.5), None);
+        assert!(!animation.is_finished(2.5));
+        assert!(animation.is_finished(3.0));
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A binary space partitioning tree used to order the layers of a 3d rendering context.
+//! Polygons that intersect each other are split, so that every fragment can be drawn
+//! strictly back-to-front.
+//!
+//! Polygons are given in screen space after the perspective divide. Projective transforms
+//! preserve planes, so splitting there is equivalent to splitting in world space. The viewer
+//! looks down the negative z axis, so larger z values are closer.
+
+use euclid::point::Point3D;
+
+/// Distances smaller than this are treated as lying on a plane.
+const PLANE_EPSILON: f32 = 1.0e-3;
+
+#[derive(Clone, Debug)]
+pub struct Polygon {
+    /// The vertices of this convex polygon, in order.
+    pub points: Vec<Point3D<f32>>,
+
+    /// An identifier for whatever this polygon was created from, which is kept by fragments.
+    pub anchor: usize,
+
+    /// Whether this polygon is a fragment produced by splitting.
+    pub is_split: bool,
+
+    /// The unit normal of the plane of this polygon.
+    normal: Point3D<f32>,
+
+    /// The plane offset, such that `dot(normal, p) + offset == 0` for points on the plane.
+    offset: f32,
+}
+
+enum Classification {
+    Front(Polygon),
+    Back(Polygon),
+    Coplanar(Polygon),
+    Split(Polygon, Polygon),
+}
+
+fn dot(a: &Point3D<f32>, b: &Point3D<f32>) -> f32 {
+    a.x * b.x + a.y * b.y + a.z * b.z
+}
+
+fn lerp(a: &Point3D<f32>, b: &Point3D<f32>, t: f32) -> Point3D<f32> {
+    Point3D::new(a.x + (b.x - a.x) * t,
+                 a.y + (b.y - a.y) * t,
+                 a.z + (b.z - a.z) * t)
+}
+
+impl Polygon {
+    /// Creates a polygon from a list of vertices. Returns `None` if the polygon is degenerate,
+    /// which is the case for layers that are seen exactly edge-on.
+    pub fn new(points: Vec<Point3D<f32>>, anchor: usize) -> Option<Polygon> {
+        if points.len() < 3 {
+            return None;
+        }
+
+        // Newell's method, which is robust for nearly degenerate polygons.
+        let mut normal = Point3D::new(0.0, 0.0, 0.0);
+        for i in 0..points.len() {
+            let current = &points[i];
+            let next = &points[(i + 1) % points.len()];
+            normal.x += (current.y - next.y) * (current.z + next.z);
+            normal.y += (current.z - next.z) * (current.x + next.x);
+            normal.z += (current.x - next.x) * (current.y + next.y);
+        }
+
+        let length = dot(&normal, &normal).sqrt();
+        if length < PLANE_EPSILON {
+            return None;
+        }
+        let normal = Point3D::new(normal.x / length, normal.y / length, normal.z / length);
+        let offset = -dot(&normal, &points[0]);
+
+        Some(Polygon {
+            points: points,
+            anchor: anchor,
+            is_split: false,
+            normal: normal,
+            offset: offset,
+        })
+    }
+
+    fn signed_distance(&self, point: &Point3D<f32>) -> f32 {
+        dot(&self.normal, point) + self.offset
+    }
+
+    /// Classifies `other` against the plane of this polygon, splitting it if it straddles
+    /// the plane.
+    fn classify(&self, other: Polygon) -> Classification {
+        let distances: Vec<f32> = other.points.iter().map(|p| self.signed_distance(p)).collect();
+
+        let in_front = distances.iter().any(|d| *d > PLANE_EPSILON);
+        let behind = distances.iter().any(|d| *d < -PLANE_EPSILON);
+
+        match (in_front, behind) {
+            (false, false) => return Classification::Coplanar(other),
+            (true, false) => return Classification::Front(other),
+            (false, true) => return Classification::Back(other),
+            (true, true) => {}
+        }
+
+        let mut front_points = vec!();
+        let mut back_points = vec!();
+        let count = other.points.len();
+        for i in 0..count {
+            let j = (i + 1) % count;
+            let (current, next) = (&other.points[i], &other.points[j]);
+            let (current_distance, next_distance) = (distances[i], distances[j]);
+
+            if current_distance >= -PLANE_EPSILON {
+                front_points.push(*current);
+            }
+            if current_distance <= PLANE_EPSILON {
+                back_points.push(*current);
+            }
+
+            // Add the intersection point if this edge crosses the plane.
+            if (current_distance > PLANE_EPSILON && next_distance < -PLANE_EPSILON) ||
+               (current_distance < -PLANE_EPSILON && next_distance > PLANE_EPSILON) {
+                let t = current_distance / (current_distance - next_distance);
+                let intersection = lerp(current, next, t);
+                front_points.push(intersection);
+                back_points.push(intersection);
+            }
+        }
+
+        let fragment = |points: Vec<Point3D<f32>>| {
+            Polygon {
+                points: points,
+                anchor: other.anchor,
+                is_split: true,
+                normal: other.normal,
+                offset: other.offset,
+            }
+        };
+        Classification::Split(fragment(front_points), fragment(back_points))
+    }
+}
+
+struct BspNode {
+    /// The polygons lying in the plane of this node, in insertion order.
+    polygons: Vec<Polygon>,
+    front: BspTree,
+    back: BspTree,
+}
+
+pub struct BspTree {
+    root: Option<Box<BspNode>>,
+}
+
+impl BspTree {
+    pub fn new() -> BspTree {
+        BspTree {
+            root: None,
+        }
+    }
+
+    /// Adds a polygon to the tree. Coplanar polygons are drawn in the order they are inserted,
+    /// so polygons should be inserted in paint order.
+    pub fn insert(&mut self, polygon: Polygon) {
+        if self.root.is_none() {
+            self.root = Some(Box::new(BspNode {
+                polygons: vec!(polygon),
+                front: BspTree::new(),
+                back: BspTree::new(),
+            }));
+            return;
+        }
+
+        let node = self.root.as_mut().unwrap();
+        let classification = node.polygons[0].classify(polygon);
+        match classification {
+            Classification::Coplanar(polygon) => node.polygons.push(polygon),
+            Classification::Front(polygon) => node.front.insert(polygon),
+            Classification::Back(polygon) => node.back.insert(polygon),
+            Classification::Split(front, back) => {
+                node.front.insert(front);
+                node.back.insert(back);
+            }
+        }
+    }
+
+    /// Returns all polygons and fragments in the tree, ordered from back to front.
+    pub fn back_to_front(&self) -> Vec<&Polygon> {
+        let mut result = vec!();
+        self.collect_back_to_front(&mut result);
+        result
+    }
+
+    fn collect_back_to_front<'a>(&'a self, result: &mut Vec<&'a Polygon>) {
+        let node = match self.root {
+            Some(ref node) => node,
+            None => return,
+        };
+
+        // The side of the plane facing the viewer has to be drawn last.
+        let (farther, nearer) = if node.polygons[0].normal.z >= 0.0 {
+            (&node.back, &node.front)
+        } else {
+            (&node.front, &node.back)
+        };
+
+        farther.collect_back_to_front(result);
+        for polygon in node.polygons.iter() {
+            result.push(polygon);
+        }
+        nearer.collect_back_to_front(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BspTree, Polygon};
+
+    use euclid::point::Point3D;
+
+    /// A unit square around the origin of the plane z = `z`.
+    fn square_at_depth(z: f32, anchor: usize) -> Polygon {
+        Polygon::new(vec!(Point3D::new(-1.0, -1.0, z),
+                          Point3D::new(1.0, -1.0, z),
+                          Point3D::new(1.0, 1.0, z),
+                          Point3D::new(-1.0, 1.0, z)),
+                     anchor).unwrap()
+    }
+
+    fn anchors(tree: &BspTree) -> Vec<usize> {
+        tree.back_to_front().iter().map(|polygon| polygon.anchor).collect()
+    }
+
+    #[test]
+    fn degenerate_polygons_are_rejected() {
+        assert!(Polygon::new(vec!(Point3D::new(0.0, 0.0, 0.0), Point3D::new(1.0, 0.0, 0.0)),
+                             0).is_none());
+        assert!(Polygon::new(vec!(Point3D::new(0.0, 0.0, 0.0),
+                                  Point3D::new(1.0, 0.0, 0.0),
+                                  Point3D::new(2.0, 0.0, 0.0)),
+                             0).is_none());
+    }
+
+    #[test]
+    fn parallel_polygons_are_ordered_by_depth() {
+        let mut tree = BspTree::new();
+        tree.insert(square_at_depth(10.0, 0));
+        tree.insert(square_at_depth(0.0, 1));
+        tree.insert(square_at_depth(5.0, 2));
+        assert_eq!(anchors(&tree), vec!(1, 2, 0));
+    }
+
+    #[test]
+    fn coplanar_polygons_keep_their_insertion_order() {
+        let mut tree = BspTree::new();
+        tree.insert(square_at_depth(0.0, 0));
+        tree.insert(square_at_depth(0.0, 1));
+        tree.insert(square_at_depth(0.0, 2));
+        assert_eq!(anchors(&tree), vec!(0, 1, 2));
+    }
+
+    #[test]
+    fn intersecting_polygons_are_split() {
+        let mut tree = BspTree::new();
+        tree.insert(square_at_depth(0.0, 0));
+        tree.insert(Polygon::new(vec!(Point3D::new(0.0, -1.0, -1.0),
+                                      Point3D::new(0.0, -1.0, 1.0),
+                                      Point3D::new(0.0, 1.0, 1.0),
+                                      Point3D::new(0.0, 1.0, -1.0)),
+                                 1).unwrap());
+
+        let polygons = tree.back_to_front();
+        assert_eq!(polygons.len(), 3);
+        assert_eq!(anchors(&tree), vec!(1, 0, 1));
+        assert!(!polygons[1].is_split);
+        assert!(polygons[0].is_split && polygons[2].is_split);
+        assert!(polygons[0].points.iter().all(|point| point.z <= 0.0));
+        assert!(polygons[2].points.iter().all(|point| point.z >= 0.0));
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Works out which part of the viewport changed between frames, so that only that part has
+//! to be composited and presented again.
+//!
+//! Layer properties are plain `RefCell`s without change notification, so the tracker keeps a
+//! snapshot of every layer from the last frame and compares it against the current tree.
+//! Changes to tiles are recorded by the tile grids as buffers come and go.
+
+use color::Color;
+use filters::{Filter, filters_outset};
+use geometry::LayerPixel;
+use layers::{BlendMode, BorderRadii, Layer};
+use util::{project_rect_to_screen, transform_scale_factor};
+
+use euclid::matrix::Matrix4;
+use euclid::point::{Point2D, TypedPoint2D};
+use euclid::rect::{Rect, TypedRect};
+use euclid::size::Size2D;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The state of a layer that affects how it is composited, as of the last frame.
+struct LayerSnapshot {
+    /// The area covered by the layer itself, in device pixels.
+    device_rect: Option<Rect<f32>>,
+
+    /// The area covered by the layer, its descendants and its mask, in device pixels.
+    subtree_rect: Option<Rect<f32>>,
+
+    final_transform: Matrix4,
+    world_rect: Rect<f32>,
+    background_color: Color,
+
+    // The properties below affect the descendants of the layer as well. The transform and
+    // geometry are compared even though they are reflected in the transform state, so that
+    // changes are noticed before the transform state is updated.
+    transform: Matrix4,
+    perspective: Matrix4,
+    bounds: TypedRect<LayerPixel, f32>,
+    content_offset: TypedPoint2D<LayerPixel, f32>,
+    opacity: f32,
+    blend_mode: BlendMode,
+    filters: Vec<Filter>,
+    masks_to_bounds: bool,
+    border_radii: BorderRadii,
+    mask_layer: Option<usize>,
+    children: Vec<usize>,
+}
+
+impl LayerSnapshot {
+    fn subtree_differs(&self, other: &LayerSnapshot) -> bool {
+        self.transform != other.transform ||
+        self.perspective != other.perspective ||
+        self.bounds != other.bounds ||
+        self.content_offset != other.content_offset ||
+        self.opacity != other.opacity ||
+        self.blend_mode != other.blend_mode ||
+        self.filters != other.filters ||
+        self.masks_to_bounds != other.masks_to_bounds ||
+        self.border_radii != other.border_radii ||
+        self.mask_layer != other.mask_layer ||
+        self.children != other.children
+    }
+
+    fn layer_differs(&self, other: &LayerSnapshot) -> bool {
+        self.device_rect != other.device_rect ||
+        self.final_transform != other.final_transform ||
+        self.world_rect != other.world_rect ||
+        self.background_color != other.background_color
+    }
+}
+
+/// The oldest back buffer whose contents are repaired from the damage of recent frames. Older
+/// buffers are redrawn completely.
+const MAX_BUFFER_AGE: usize = 8;
+
+pub struct DamageTracker {
+    /// The snapshots of all layers in the last frame, keyed by layer id.
+    snapshots: HashMap<usize, LayerSnapshot>,
+
+    /// The viewport and scale of the last frame, or `None` if the whole viewport has to be
+    /// redrawn.
+    last_frame: Option<(Rect<f32>, f32)>,
+
+    /// The damage of the most recently drawn frames, from the newest to the oldest. A back
+    /// buffer that is several frames old has to be repaired with all of it.
+    recent_damage: Vec<Rect<f32>>,
+}
+
+impl DamageTracker {
+    pub fn new() -> DamageTracker {
+        DamageTracker {
+            snapshots: HashMap::new(),
+            last_frame: None,
+            recent_damage: vec!(),
+        }
+    }
+
+    /// Makes the next frame damage the whole viewport, for instance because the window
+    /// contents were lost.
+    pub fn damage_all(&mut self) {
+        self.last_frame = None;
+        self.recent_damage.clear();
+    }
+
+    /// Returns the part of the viewport that has to be redrawn, in device pixels relative to
+    /// the viewport and rounded out to whole pixels, and remembers the current state of the
+    /// layer tree. Returns `None` if nothing changed, in which case nothing needs to be drawn.
+    ///
+    /// `buffer_age` is how many frames old the contents of the back buffer are, as reported
+    /// by `EGL_EXT_buffer_age`: 1 if it holds the last frame, 2 if it holds the one before,
+    /// and so on. The damage of all frames since then is redrawn. If it is `None` or 0, the
+    /// contents are unknown and the whole viewport is redrawn.
+    pub fn calculate_damage<T>(&mut self,
+                               root_layer: &Rc<Layer<T>>,
+                               viewport: &Rect<f32>,
+                               scale: f32,
+                               buffer_age: Option<usize>)
+                               -> Option<Rect<f32>> {
+        let (damage, snapshots) = self.compare(root_layer, viewport, scale, true);
+        self.snapshots = snapshots;
+        self.last_frame = Some((*viewport, scale));
+
+        let damage = match damage {
+            Some(damage) => damage,
+            None => return None,
+        };
+        self.recent_damage.insert(0, damage);
+        self.recent_damage.truncate(MAX_BUFFER_AGE);
+
+        let viewport_rect = Rect::new(Point2D::zero(), viewport.size);
+        match buffer_age {
+            Some(buffer_age) if buffer_age > 0 && buffer_age <= self.recent_damage.len() => {
+                let damage = self.recent_damage[..buffer_age].iter().fold(damage, |union, damage| {
+                    union.union(damage)
+                });
+                damage.intersection(&viewport_rect)
+            }
+            _ => Some(viewport_rect),
+        }
+    }
+
+    /// Whether anything in the viewport changed since the last call to `calculate_damage`.
+    /// Unlike `calculate_damage`, this doesn't record anything.
+    pub fn needs_composite<T>(&self,
+                              root_layer: &Rc<Layer<T>>,
+                              viewport: &Rect<f32>,
+                              scale: f32)
+                              -> bool {
+        self.compare(root_layer, viewport, scale, false).0.is_some()
+    }
+
+    /// Compares the layer tree with the last frame, returning the damage and the new
+    /// snapshots. If `take_tile_damage` is set, the damage recorded by tile grids is cleared.
+    fn compare<T>(&self,
+                  root_layer: &Rc<Layer<T>>,
+                  viewport: &Rect<f32>,
+                  scale: f32,
+                  take_tile_damage: bool)
+                  -> (Option<Rect<f32>>, HashMap<usize, LayerSnapshot>) {
+        let mut snapshots = HashMap::new();
+        let mut damage = None;
+        self.visit_layer(root_layer, scale, 0.0, take_tile_damage, &mut snapshots, &mut damage);
+
+        // Layers that are gone leave their old area behind.
+        for (key, snapshot) in self.snapshots.iter() {
+            if !snapshots.contains_key(key) {
+                damage = union_rects(damage, snapshot.subtree_rect);
+            }
+        }
+
+        let viewport_rect = Rect::new(Point2D::zero(), viewport.size);
+        if self.last_frame != Some((*viewport, scale)) {
+            damage = Some(viewport_rect);
+        }
+
+        let damage = damage.and_then(|damage| damage.intersection(&viewport_rect)).map(|damage| {
+            let x0 = damage.min_x().floor();
+            let y0 = damage.min_y().floor();
+            Rect::new(Point2D::new(x0, y0),
+                      Size2D::new(damage.max_x().ceil() - x0, damage.max_y().ceil() - y0))
+        });
+        (damage, snapshots)
+    }
+
+    /// Compares a layer and its descendants with their snapshots, adding what changed to
+    /// `damage`. `ancestor_outset` is how far the filters of ancestors spread content, in
+    /// device pixels. Returns the area covered by the subtree.
+    fn visit_layer<T>(&self,
+                      layer: &Rc<Layer<T>>,
+                      scale: f32,
+                      ancestor_outset: f32,
+                      take_tile_damage: bool,
+                      snapshots: &mut HashMap<usize, LayerSnapshot>,
+                      damage: &mut Option<Rect<f32>>)
+                      -> Option<Rect<f32>> {
+        let key = layer.id();
+        let filters = layer.filters.borrow().clone();
+        let ts = layer.transform_state.borrow();
+        let outset = ancestor_outset +
+                     filters_outset(&filters) * scale * transform_scale_factor(&ts.final_transform);
+
+        let device_rect = ts.screen_rect.as_ref().map(|screen_rect| {
+            to_device_rect(&screen_rect.rect, scale, outset)
+        });
+
+        let damaged_rect = if take_tile_damage {
+            layer.take_damaged_rect()
+        } else {
+            layer.damaged_rect()
+        };
+        if let Some(damaged_rect) = damaged_rect {
+            let damaged_rect = damaged_rect.translate(&ts.world_rect.origin);
+            if let Some(screen_rect) = project_rect_to_screen(&damaged_rect, &ts.final_transform) {
+                *damage = union_rects(*damage,
+                                      Some(to_device_rect(&screen_rect.rect, scale, outset)));
+            }
+        }
+
+        let mut subtree_rect = device_rect;
+        let mut children = vec!();
+        for child in layer.children().iter() {
+            children.push(child.id());
+            let child_rect = self.visit_layer(child,
+                                              scale,
+                                              outset,
+                                              take_tile_damage,
+                                              snapshots,
+                                              damage);
+            subtree_rect = union_rects(subtree_rect, child_rect);
+        }
+
+        let mask_layer = layer.mask_layer.borrow().clone();
+        if let Some(ref mask_layer) = mask_layer {
+            let mask_rect = self.visit_layer(mask_layer,
+                                             scale,
+                                             outset,
+                                             take_tile_damage,
+                                             snapshots,
+                                             damage);
+            subtree_rect = union_rects(subtree_rect, mask_rect);
+        }
+
+        let snapshot = LayerSnapshot {
+            device_rect: device_rect,
+            subtree_rect: subtree_rect,
+            final_transform: ts.final_transform,
+            world_rect: ts.world_rect,
+            background_color: *layer.background_color.borrow(),
+            transform: *layer.transform.borrow(),
+            perspective: *layer.perspective.borrow(),
+            bounds: *layer.bounds.borrow(),
+            content_offset: *layer.content_offset.borrow(),
+            opacity: *layer.opacity.borrow(),
+            blend_mode: *layer.blend_mode.borrow(),
+            filters: filters,
+            masks_to_bounds: *layer.masks_to_bounds.borrow(),
+            border_radii: *layer.border_radii.borrow(),
+            mask_layer: mask_layer.as_ref().map(|mask_layer| mask_layer.id()),
+            children: children,
+        };
+
+        match self.snapshots.get(&key) {
+            Some(old_snapshot) => {
+                if old_snapshot.subtree_differs(&snapshot) {
+                    *damage = union_rects(*damage, old_snapshot.subtree_rect);
+                    *damage = union_rects(*damage, subtree_rect);
+                } else if old_snapshot.layer_differs(&snapshot) {
+                    *damage = union_rects(*damage, old_snapshot.device_rect);
+                    *damage = union_rects(*damage, device_rect);
+                }
+            }
+            None => *damage = union_rects(*damage, subtree_rect),
+        }
+
+        snapshots.insert(key, snapshot);
+        subtree_rect
+    }
+}
+
+/// Scales a rect in unscaled screen coordinates to device pixels, and grows it by `outset`.
+fn to_device_rect(rect: &Rect<f32>, scale: f32, outset: f32) -> Rect<f32> {
+    Rect::new(Point2D::new(rect.origin.x * scale - outset, rect.origin.y * scale - outset),
+              Size2D::new(rect.size.width * scale + 2.0 * outset,
+                          rect.size.height * scale + 2.0 * outset))
+}
+
+fn union_rects(a: Option<Rect<f32>>, b: Option<Rect<f32>>) -> Option<Rect<f32>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.union(&b)),
+        (Some(a), None) => Some(a),
+        (None, b) => b,
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Filter effects that the compositor applies to a layer and its descendants, as in the CSS
+//! `filter` property. See https://drafts.fxtf.org/filter-effects/#FilterProperty.
+
+use color::Color;
+
+use euclid::point::Point2D;
+
+/// A color matrix, applied to unpremultiplied RGBA colors. Each of the four rows holds the
+/// factors for the red, green, blue and alpha input components followed by a constant offset.
+pub type ColorMatrix = [f32; 20];
+
+pub static IDENTITY_COLOR_MATRIX: ColorMatrix = [
+    1.0, 0.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0, 0.0,
+    0.0, 0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.0, 1.0, 0.0,
+];
+
+/// The extent of a gaussian blur in standard deviations, beyond which it is treated as zero.
+pub const BLUR_EXTENT: f32 = 3.0;
+
+/// The largest blur kernel radius, in pixels. Wider blurs are drawn on a downscaled copy of the
+/// content.
+pub const MAX_BLUR_RADIUS: usize = 32;
+
+/// The most times that content is halved in size before blurring it.
+const MAX_BLUR_DOWNSCALE_STEPS: u32 = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filter {
+    /// A gaussian blur with the given standard deviation, in layer pixels.
+    Blur(f32),
+
+    /// A blurred, offset copy of the alpha channel drawn in the given color behind the content.
+    /// The offset and the blur standard deviation are in layer pixels.
+    DropShadow(Point2D<f32>, f32, Color),
+
+    /// The amounts of these filters are clamped to the range from 0.0 to 1.0.
+    Grayscale(f32),
+    Sepia(f32),
+    Invert(f32),
+
+    /// Amounts above 1.0 over-saturate.
+    Saturate(f32),
+
+    /// A rotation of the hue, in degrees.
+    HueRotate(f32),
+
+    /// Linear multipliers, where 1.0 leaves the content unchanged.
+    Brightness(f32),
+    Contrast(f32),
+}
+
+impl Filter {
+    /// Returns the color matrix that implements this filter, or `None` for filters that sample
+    /// neighbouring pixels.
+    pub fn color_matrix(&self) -> Option<ColorMatrix> {
+        let matrix = match *self {
+            Filter::Blur(..) | Filter::DropShadow(..) => return None,
+            Filter::Grayscale(amount) => {
+                let a = 1.0 - clamp_amount(amount);
+                [0.2126 + 0.7874 * a, 0.7152 - 0.7152 * a, 0.0722 - 0.0722 * a, 0.0, 0.0,
+                 0.2126 - 0.2126 * a, 0.7152 + 0.2848 * a, 0.0722 - 0.0722 * a, 0.0, 0.0,
+                 0.2126 - 0.2126 * a, 0.7152 - 0.7152 * a, 0.0722 + 0.9278 * a, 0.0, 0.0,
+                 0.0, 0.0, 0.0, 1.0, 0.0]
+            }
+            Filter::Sepia(amount) => {
+                let a = 1.0 - clamp_amount(amount);
+                [0.393 + 0.607 * a, 0.769 - 0.769 * a, 0.189 - 0.189 * a, 0.0, 0.0,
+                 0.349 - 0.349 * a, 0.686 + 0.314 * a, 0.168 - 0.168 * a, 0.0, 0.0,
+                 0.272 - 0.272 * a, 0.534 - 0.534 * a, 0.131 + 0.869 * a, 0.0, 0.0,
+                 0.0, 0.0, 0.0, 1.0, 0.0]
+            }
+            Filter::Invert(amount) => {
+                let a = clamp_amount(amount);
+                [1.0 - 2.0 * a, 0.0, 0.0, 0.0, a,
+                 0.0, 1.0 - 2.0 * a, 0.0, 0.0, a,
+                 0.0, 0.0, 1.0 - 2.0 * a, 0.0, a,
+                 0.0, 0.0, 0.0, 1.0, 0.0]
+            }
+            Filter::Saturate(amount) => {
+                let s = amount.max(0.0);
+                [0.213 + 0.787 * s, 0.715 - 0.715 * s, 0.072 - 0.072 * s, 0.0, 0.0,
+                 0.213 - 0.213 * s, 0.715 + 0.285 * s, 0.072 - 0.072 * s, 0.0, 0.0,
+                 0.213 - 0.213 * s, 0.715 - 0.715 * s, 0.072 + 0.928 * s, 0.0, 0.0,
+                 0.0, 0.0, 0.0, 1.0, 0.0]
+            }
+            Filter::HueRotate(degrees) => {
+                let (sin, cos) = degrees.to_radians().sin_cos();
+                [0.213 + cos * 0.787 - sin * 0.213,
+                 0.715 - cos * 0.715 - sin * 0.715,
+                 0.072 - cos * 0.072 + sin * 0.928,
+                 0.0, 0.0,
+                 0.213 - cos * 0.213 + sin * 0.143,
+                 0.715 + cos * 0.285 + sin * 0.140,
+                 0.072 - cos * 0.072 - sin * 0.283,
+                 0.0, 0.0,
+                 0.213 - cos * 0.213 - sin * 0.787,
+                 0.715 - cos * 0.715 + sin * 0.715,
+                 0.072 + cos * 0.928 + sin * 0.072,
+                 0.0, 0.0,
+                 0.0, 0.0, 0.0, 1.0, 0.0]
+            }
+            Filter::Brightness(amount) => {
+                let b = amount.max(0.0);
+                [b, 0.0, 0.0, 0.0, 0.0,
+                 0.0, b, 0.0, 0.0, 0.0,
+                 0.0, 0.0, b, 0.0, 0.0,
+                 0.0, 0.0, 0.0, 1.0, 0.0]
+            }
+            Filter::Contrast(amount) => {
+                let c = amount.max(0.0);
+                let offset = 0.5 - 0.5 * c;
+                [c, 0.0, 0.0, 0.0, offset,
+                 0.0, c, 0.0, 0.0, offset,
+                 0.0, 0.0, c, 0.0, offset,
+                 0.0, 0.0, 0.0, 1.0, 0.0]
+            }
+        };
+        Some(matrix)
+    }
+
+    /// Returns how far this filter can move content outside of its original area, in layer
+    /// pixels.
+    pub fn outset(&self) -> f32 {
+        match *self {
+            Filter::Blur(std_deviation) => std_deviation.max(0.0) * BLUR_EXTENT,
+            Filter::DropShadow(ref offset, std_deviation, _) => {
+                offset.x.abs().max(offset.y.abs()) + std_deviation.max(0.0) * BLUR_EXTENT
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+fn clamp_amount(amount: f32) -> f32 {
+    amount.max(0.0).min(1.0)
+}
+
+/// Returns how far a chain of filters can move content outside of its original area, in layer
+/// pixels.
+pub fn filters_outset(filters: &[Filter]) -> f32 {
+    filters.iter().map(|filter| filter.outset()).sum()
+}
+
+/// Applies a color matrix to a premultiplied RGBA color.
+pub fn apply_color_matrix(matrix: &ColorMatrix, color: [f32; 4]) -> [f32; 4] {
+    if color[3] <= 0.0 {
+        // Offsets only affect the color channels, which are irrelevant when nothing is there.
+        return [0.0; 4];
+    }
+
+    let unpremultiplied = [color[0] / color[3], color[1] / color[3], color[2] / color[3], color[3]];
+    let mut result = [0.0; 4];
+    for row in 0..4 {
+        let mut value = matrix[row * 5 + 4];
+        for column in 0..4 {
+            value += matrix[row * 5 + column] * unpremultiplied[column];
+        }
+        result[row] = value.max(0.0).min(1.0);
+    }
+
+    let alpha = result[3];
+    [result[0] * alpha, result[1] * alpha, result[2] * alpha, alpha]
+}
+
+/// Returns how many times content must be halved in size before blurring it with a standard
+/// deviation in pixels, so that the kernel fits in `MAX_BLUR_RADIUS`. Each halving also halves
+/// the standard deviation.
+pub fn blur_downscale_steps(std_deviation: f32) -> u32 {
+    let mut steps = 0;
+    let mut std_deviation = std_deviation;
+    while (std_deviation * BLUR_EXTENT).ceil() > MAX_BLUR_RADIUS as f32 &&
+          steps < MAX_BLUR_DOWNSCALE_STEPS {
+        std_deviation /= 2.0;
+        steps += 1;
+    }
+    steps
+}
+
+/// Returns the normalized weights of one half of a gaussian kernel, starting at the center
+/// tap, for a standard deviation in pixels. At most `MAX_BLUR_RADIUS + 1` weights are returned,
+/// so wider blurs must be downscaled first; see `blur_downscale_steps`.
+pub fn gaussian_weights(std_deviation: f32) -> Vec<f32> {
+    if std_deviation <= 0.0 {
+        return vec!(1.0);
+    }
+
+    let radius = ((std_deviation * BLUR_EXTENT).ceil() as usize).min(MAX_BLUR_RADIUS);
+    let mut weights: Vec<f32> = (0..radius + 1).map(|i| {
+        let x = i as f32;
+        (-x * x / (2.0 * std_deviation * std_deviation)).exp()
+    }).collect();
+
+    let total = weights[0] + 2.0 * weights[1..].iter().fold(0.0, |sum, weight| sum + *weight);
+    for weight in weights.iter_mut() {
+        *weight /= total;
+    }
+    weights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_color_matrix, BLUR_EXTENT, blur_downscale_steps, ColorMatrix, Filter};
+    use super::filters_outset;
+    use super::{gaussian_weights, IDENTITY_COLOR_MATRIX, MAX_BLUR_RADIUS};
+    use color::Color;
+
+    use euclid::point::Point2D;
+
+    fn assert_colors_near(actual: [f32; 4], expected: [f32; 4]) {
+        for i in 0..4 {
+            assert!((actual[i] - expected[i]).abs() < 1.0e-4, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    fn assert_matrices_near(actual: &ColorMatrix, expected: &ColorMatrix) {
+        for i in 0..20 {
+            assert!((actual[i] - expected[i]).abs() < 1.0e-3,
+                    "{:?} != {:?}", &actual[..], &expected[..]);
+        }
+    }
+
+    #[test]
+    fn neutral_amounts_give_the_identity_matrix() {
+        let filters = [Filter::Grayscale(0.0), Filter::Sepia(0.0), Filter::Invert(0.0),
+                       Filter::Saturate(1.0), Filter::HueRotate(0.0), Filter::Brightness(1.0),
+                       Filter::Contrast(1.0)];
+        for filter in filters.iter() {
+            assert_matrices_near(&filter.color_matrix().unwrap(), &IDENTITY_COLOR_MATRIX);
+        }
+        assert!(Filter::Blur(1.0).color_matrix().is_none());
+    }
+
+    #[test]
+    fn full_grayscale_maps_colors_to_their_luminance() {
+        let matrix = Filter::Grayscale(1.0).color_matrix().unwrap();
+        assert_colors_near(apply_color_matrix(&matrix, [1.0, 0.0, 0.0, 1.0]),
+                           [0.2126, 0.2126, 0.2126, 1.0]);
+        assert_colors_near(apply_color_matrix(&matrix, [1.0, 1.0, 1.0, 1.0]),
+                           [1.0, 1.0, 1.0, 1.0]);
+
+        // Amounts beyond 1.0 are clamped.
+        assert_matrices_near(&Filter::Grayscale(2.0).color_matrix().unwrap(), &matrix);
+    }
+
+    #[test]
+    fn color_matrices_apply_to_unpremultiplied_colors() {
+        let matrix = Filter::Invert(1.0).color_matrix().unwrap();
+        assert_colors_near(apply_color_matrix(&matrix, [0.2, 0.4, 0.0, 0.5]),
+                           [0.3, 0.1, 0.5, 0.5]);
+        assert_colors_near(apply_color_matrix(&matrix, [0.0, 0.0, 0.0, 0.0]), [0.0; 4]);
+    }
+
+    #[test]
+    fn gaussian_weights_are_normalized() {
+        assert_eq!(gaussian_weights(0.0), vec!(1.0));
+
+        let weights = gaussian_weights(2.0);
+        assert_eq!(weights.len(), (2.0 * BLUR_EXTENT) as usize + 1);
+        let total = weights[0] + 2.0 * weights[1..].iter().fold(0.0, |sum, weight| sum + *weight);
+        assert!((total - 1.0).abs() < 1.0e-4);
+        assert!(weights.windows(2).all(|pair| pair[0] > pair[1]));
+
+        assert_eq!(gaussian_weights(1000.0).len(), MAX_BLUR_RADIUS + 1);
+    }
+
+    #[test]
+    fn wide_blurs_are_downscaled_until_the_kernel_fits() {
+        assert_eq!(blur_downscale_steps(0.0), 0);
+        assert_eq!(blur_downscale_steps(10.0), 0);
+        assert_eq!(blur_downscale_steps(11.0), 1);
+        assert_eq!(blur_downscale_steps(40.0), 2);
+
+        let std_deviation = 100.0;
+        let steps = blur_downscale_steps(std_deviation);
+        let scaled_std_deviation = std_deviation / (1 << steps) as f32;
+        assert!((scaled_std_deviation * BLUR_EXTENT).ceil() <= MAX_BLUR_RADIUS as f32);
+    }
+
+    #[test]
+    fn outsets_add_up_along_the_chain() {
+        let shadow_color = Color { r: 0.0, g: 0.0, b: 0.0, a: 1.0 };
+        let filters = [Filter::Blur(2.0),
+                       Filter::Grayscale(1.0),
+                       Filter::DropShadow(Point2D::new(3.0, -5.0), 1.0, shadow_color)];
+        assert_eq!(filters_outset(&filters), 2.0 * BLUR_EXTENT + 5.0 + BLUR_EXTENT);
+    }
+}
@@ -7,9 +7,12 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use animation::{Animation, AnimationSet};
 use color::Color;
+use filters::Filter;
 use geometry::{DevicePixel, LayerPixel};
-use tiling::{Tile, TileGrid};
+use texturegl::TexturePool;
+use tiling::{PrepaintMargins, Tile, TileGrid};
 
 use euclid::matrix::Matrix4;
 use euclid::scale_factor::ScaleFactor;
@@ -19,7 +22,8 @@ use euclid::rect::{Rect, TypedRect};
 use platform::surface::{NativeDisplay, NativeSurface};
 use std::cell::{RefCell, RefMut};
 use std::rc::Rc;
-use util::{project_rect_to_screen, ScreenRect};
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use util::{project_rect_to_screen, transform_scale_factor, ScreenRect};
 
 #[derive(Clone, Copy, PartialEq, PartialOrd)]
 pub struct ContentAge {
@@ -38,6 +42,109 @@ impl ContentAge {
     }
 }
 
+/// How a layer is blended with the content behind it, as in CSS `mix-blend-mode`. See
+/// https://drafts.fxtf.org/compositing-1/#blending for the definitions.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlendMode {
+    Normal = 0,
+    Multiply = 1,
+    Screen = 2,
+    Overlay = 3,
+    Darken = 4,
+    Lighten = 5,
+    ColorDodge = 6,
+    ColorBurn = 7,
+    HardLight = 8,
+    SoftLight = 9,
+    Difference = 10,
+    Exclusion = 11,
+    Hue = 12,
+    Saturation = 13,
+    Color = 14,
+    Luminosity = 15,
+}
+
+/// The smallest and largest scales that a layer's content is rasterized at relative to the scene,
+/// so that layers scaled to nothing or enormously by their transform keep sensible tiles.
+const MIN_RASTER_SCALE: f32 = 0.125;
+const MAX_RASTER_SCALE: f32 = 8.0;
+
+/// While the scale of a layer's transform is changing, its raster scale is only updated once
+/// the transform scales it by this factor more or less than the raster scale, so that animated
+/// layers aren't repainted every frame.
+const RASTER_SCALE_HYSTERESIS: f32 = 2.0;
+
+/// The scale that a layer's content is rasterized at relative to the scale of the scene, chosen
+/// from how its transform scales it on the screen.
+#[derive(Clone, Copy, Debug)]
+struct RasterScale {
+    /// The current raster scale.
+    scale: f32,
+
+    /// The scale of the layer's transform the last time the raster scale was updated.
+    last_transform_scale: f32,
+}
+
+impl RasterScale {
+    fn new() -> RasterScale {
+        RasterScale {
+            scale: 1.0,
+            last_transform_scale: 1.0,
+        }
+    }
+
+    /// Updates the raster scale for a transform that now scales the layer by `transform_scale`,
+    /// and returns it. The raster scale follows the transform as soon as it stops changing.
+    fn update(&mut self, transform_scale: f32) -> f32 {
+        let transform_scale = transform_scale.max(MIN_RASTER_SCALE).min(MAX_RASTER_SCALE);
+        let is_steady = (transform_scale - self.last_transform_scale).abs() < 1.0e-6;
+        if is_steady ||
+           transform_scale > self.scale * RASTER_SCALE_HYSTERESIS ||
+           transform_scale < self.scale / RASTER_SCALE_HYSTERESIS {
+            self.scale = transform_scale;
+        }
+        self.last_transform_scale = transform_scale;
+        self.scale
+    }
+}
+
+/// The radii of the corners of a layer, in layer pixels. Each corner may be elliptical, as in
+/// CSS `border-radius`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BorderRadii {
+    pub top_left: Size2D<f32>,
+    pub top_right: Size2D<f32>,
+    pub bottom_right: Size2D<f32>,
+    pub bottom_left: Size2D<f32>,
+}
+
+impl BorderRadii {
+    pub fn zero() -> BorderRadii {
+        BorderRadii {
+            top_left: Size2D::zero(),
+            top_right: Size2D::zero(),
+            bottom_right: Size2D::zero(),
+            bottom_left: Size2D::zero(),
+        }
+    }
+
+    pub fn uniform(radius: f32) -> BorderRadii {
+        let radius = Size2D::new(radius, radius);
+        BorderRadii {
+            top_left: radius,
+            top_right: radius,
+            bottom_right: radius,
+            bottom_left: radius,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        [self.top_left, self.top_right, self.bottom_right, self.bottom_left].iter().all(|radius| {
+            radius.width <= 0.0 || radius.height <= 0.0
+        })
+    }
+}
+
 pub struct TransformState {
     /// Final, concatenated transform + perspective matrix for this layer
     pub final_transform: Matrix4,
@@ -63,7 +170,13 @@ impl TransformState {
     }
 }
 
+/// The id of the next layer that is created.
+static NEXT_LAYER_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
 pub struct Layer<T> {
+    /// An id that no other layer created by this process has, even after this one is gone.
+    id: usize,
+
     pub children: RefCell<Vec<Rc<Layer<T>>>>,
     pub transform: RefCell<Matrix4>,
     pub perspective: RefCell<Matrix4>,
@@ -74,6 +187,16 @@ pub struct Layer<T> {
     /// The boundaries of this layer in the coordinate system of the parent layer.
     pub bounds: RefCell<TypedRect<LayerPixel, f32>>,
 
+    /// The area of this layer that has content to be tiled, in layer coordinates, if it isn't
+    /// the area of `bounds`. It may extend above and to the left of the layer origin, as
+    /// overscrolled content does, and its size may be infinite for content without scroll
+    /// limits. The origin must be finite: a rect starting at negative infinity has no far edge,
+    /// so content that is unbounded above or to the left should start far away instead.
+    pub content_rect: RefCell<Option<TypedRect<LayerPixel, f32>>>,
+
+    /// The scale that tiles of this layer are painted at relative to the scale of the scene.
+    raster_scale: RefCell<RasterScale>,
+
     /// A monotonically increasing counter that keeps track of the current content age.
     pub content_age: RefCell<ContentAge>,
 
@@ -83,15 +206,32 @@ pub struct Layer<T> {
     /// Whether this layer clips its children to its boundaries.
     pub masks_to_bounds: RefCell<bool>,
 
+    /// The radii of the corners of this layer, which round the clip of `masks_to_bounds`.
+    pub border_radii: RefCell<BorderRadii>,
+
     /// The background color for this layer.
     pub background_color: RefCell<Color>,
 
     /// The opacity of this layer, from 0.0 (fully transparent) to 1.0 (fully opaque).
     pub opacity: RefCell<f32>,
 
+    /// How this layer is blended with the content behind it.
+    pub blend_mode: RefCell<BlendMode>,
+
+    /// Filter effects applied to this layer and its descendants, in order.
+    pub filters: RefCell<Vec<Filter>>,
+
+    /// A layer whose alpha masks this layer and its descendants. Its bounds are in the
+    /// coordinate system of this layer, and it is never drawn by itself.
+    pub mask_layer: RefCell<Option<Rc<Layer<T>>>>,
+
     /// Whether this stacking context creates a new 3d rendering context.
     pub establishes_3d_context: bool,
 
+    /// The animations of the transform, opacity and background color of this layer that the
+    /// compositor runs by itself.
+    animations: RefCell<AnimationSet>,
+
     /// Collection of state related to transforms for this layer.
     pub transform_state: RefCell<TransformState>,
 }
@@ -105,23 +245,37 @@ impl<T> Layer<T> {
                data: T)
                -> Layer<T> {
         Layer {
+            id: NEXT_LAYER_ID.fetch_add(1, Ordering::SeqCst),
             children: RefCell::new(vec!()),
             transform: RefCell::new(Matrix4::identity()),
             perspective: RefCell::new(Matrix4::identity()),
             bounds: RefCell::new(bounds),
+            content_rect: RefCell::new(None),
+            raster_scale: RefCell::new(RasterScale::new()),
             tile_size: tile_size,
             extra_data: RefCell::new(data),
             tile_grid: RefCell::new(TileGrid::new(tile_size)),
             content_age: RefCell::new(ContentAge::new()),
             masks_to_bounds: RefCell::new(false),
+            border_radii: RefCell::new(BorderRadii::zero()),
             content_offset: RefCell::new(Point2D::zero()),
             background_color: RefCell::new(background_color),
             opacity: RefCell::new(opacity),
+            blend_mode: RefCell::new(BlendMode::Normal),
+            filters: RefCell::new(vec!()),
+            mask_layer: RefCell::new(None),
             establishes_3d_context: establishes_3d_context,
+            animations: RefCell::new(AnimationSet::new()),
             transform_state: RefCell::new(TransformState::new()),
         }
     }
 
+    /// Returns an id that identifies this layer across frames. Unlike its address, it is never
+    /// reused by a later layer.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
     pub fn children<'a>(&'a self) -> RefMut<'a,Vec<Rc<Layer<T>>>> {
         self.children.borrow_mut()
     }
@@ -135,30 +289,120 @@ impl<T> Layer<T> {
     }
 
     /// Returns buffer requests inside the given dirty rect, and simultaneously throws out tiles
-    /// outside the given viewport rect.
+    /// outside the given viewport rect. Both rects are grown by `prepaint_margins`, which are
+    /// in device pixels. Tiles inside the viewport are remembered as visible in `frame_number`.
+    ///
+    /// Tiles are requested at `scale` multiplied by the raster scale of the layer, which follows
+    /// how much the transform of the layer scales it on the screen.
     pub fn get_buffer_requests(&self,
                                rect_in_layer: TypedRect<LayerPixel, f32>,
                                viewport_in_layer: TypedRect<LayerPixel, f32>,
-                               scale: ScaleFactor<LayerPixel, DevicePixel, f32>)
+                               scale: ScaleFactor<LayerPixel, DevicePixel, f32>,
+                               prepaint_margins: &PrepaintMargins,
+                               frame_number: u64)
                                -> Vec<BufferRequest> {
+        let content_rect = match *self.content_rect.borrow() {
+            Some(content_rect) => content_rect,
+            None => Rect::new(Point2D::zero(), self.bounds.borrow().size),
+        };
+        debug_assert!(content_rect.origin.x.is_finite() && content_rect.origin.y.is_finite());
+
+        let transform_state = self.transform_state.borrow();
+        let raster_scale = self.raster_scale.borrow_mut().update(
+            transform_scale_factor(&transform_state.final_transform));
+        let resolution: ScaleFactor<LayerPixel, DevicePixel, f32> =
+            ScaleFactor::new(scale.get() * raster_scale);
+
+        // Tiles are laid out at the raster scale, so undo it before the layer transform to
+        // find where they are on the screen.
+        let tile_transform = transform_state.final_transform.scale(1.0 / raster_scale,
+                                                                   1.0 / raster_scale,
+                                                                   1.0);
+
         let mut tile_grid = self.tile_grid.borrow_mut();
         tile_grid.get_buffer_requests_in_rect(rect_in_layer * scale,
                                               viewport_in_layer * scale,
-                                              self.bounds.borrow().size * scale,
-                                              &(self.transform_state.borrow().world_rect.origin *
-                                                scale.get()),
-                                              &self.transform_state.borrow().final_transform,
-                                              *self.content_age.borrow())
+                                              content_rect * resolution,
+                                              &(transform_state.world_rect.origin *
+                                                resolution.get()),
+                                              &tile_transform,
+                                              *self.content_age.borrow(),
+                                              resolution.get(),
+                                              prepaint_margins,
+                                              frame_number)
+    }
+
+    /// Returns the scale that the tiles of this layer are painted at relative to the scale of
+    /// the scene.
+    pub fn raster_scale(&self) -> f32 {
+        self.raster_scale.borrow().scale
+    }
+
+    /// Starts `animation` on this layer. It takes precedence over animations added before it
+    /// that animate the same property.
+    pub fn add_animation(&self, animation: Animation) {
+        self.animations.borrow_mut().animations.push(animation);
+    }
+
+    /// Removes all animations from this layer, restoring the values that the animated
+    /// properties had before they were animated.
+    pub fn remove_animations(&self) {
+        self.animations.borrow_mut().clear(&mut *self.transform.borrow_mut(),
+                                           &mut *self.opacity.borrow_mut(),
+                                           &mut *self.background_color.borrow_mut());
+    }
+
+    /// Whether any animations are attached to this layer, including finished ones that still
+    /// hold their final values.
+    pub fn has_animations(&self) -> bool {
+        !self.animations.borrow().is_empty()
+    }
+
+    /// Updates the animated properties of this layer to their values at `time`, in seconds.
+    /// Returns true if any animation is still running.
+    pub fn tick_animations(&self, time: f64) -> bool {
+        self.animations.borrow_mut().tick(time,
+                                          &mut *self.transform.borrow_mut(),
+                                          &mut *self.opacity.borrow_mut(),
+                                          &mut *self.background_color.borrow_mut())
     }
 
     pub fn resize(&self, new_size: TypedSize2D<LayerPixel, f32>) {
         self.bounds.borrow_mut().size = new_size;
     }
 
+    /// Returns the index, last visible frame and memory usage of each tile with a buffer that
+    /// wasn't visible in `frame_number`.
+    pub fn evictable_tiles(&self, frame_number: u64) -> Vec<(Point2D<i32>, u64, usize)> {
+        self.tile_grid.borrow().evictable_tiles(frame_number)
+    }
+
+    /// Throws out a tile to free memory. Its buffer is returned by `collect_unused_buffers`.
+    pub fn evict_tile(&self, tile_index: &Point2D<i32>) {
+        self.tile_grid.borrow_mut().evict_tile(tile_index);
+    }
+
+    /// Throws out the tiles kept from a previous resolution to free memory, returning the
+    /// memory they used. Their buffers are returned by `collect_unused_buffers`.
+    pub fn evict_fallback_tiles(&self) -> usize {
+        self.tile_grid.borrow_mut().evict_fallback_tiles()
+    }
+
+    /// Forgets a request that was dropped before reaching the painter, so that the tile is
+    /// requested again.
+    pub fn cancel_buffer_request(&self, request: &BufferRequest) {
+        self.tile_grid.borrow_mut().cancel_buffer_request(request);
+    }
+
     pub fn add_buffer(&self, tile: Box<LayerBuffer>) {
         self.tile_grid.borrow_mut().add_buffer(tile);
     }
 
+    /// Adds a tile that the painter found to be a single color, in place of a buffer.
+    pub fn add_solid_color_tile(&self, tile: SolidColorTile) {
+        self.tile_grid.borrow_mut().add_solid_color_tile(tile);
+    }
+
     pub fn collect_unused_buffers(&self) -> Vec<Box<LayerBuffer>> {
         self.tile_grid.borrow_mut().take_unused_buffers()
     }
@@ -167,18 +411,55 @@ impl<T> Layer<T> {
         self.tile_grid.borrow_mut().collect_buffers()
     }
 
+    /// Returns the area where the displayed tiles changed since `take_damaged_rect` was last
+    /// called, in the same coordinates as `LayerBuffer::rect`.
+    pub fn damaged_rect(&self) -> Option<Rect<f32>> {
+        self.tile_grid.borrow().damaged_rect()
+    }
+
+    /// Returns the area where the displayed tiles changed since the last call, in the same
+    /// coordinates as `LayerBuffer::rect`.
+    pub fn take_damaged_rect(&self) -> Option<Rect<f32>> {
+        self.tile_grid.borrow_mut().take_damaged_rect()
+    }
+
     pub fn contents_changed(&self) {
         self.content_age.borrow_mut().next();
+        self.tile_grid.borrow_mut().invalidate_all(*self.content_age.borrow());
+    }
+
+    /// Marks only the tiles overlapping `rect` as stale, so that the rest of the layer isn't
+    /// painted again.
+    pub fn invalidate_rect(&self, rect: TypedRect<LayerPixel, f32>) {
+        self.content_age.borrow_mut().next();
+        self.tile_grid.borrow_mut().invalidate_rect(&rect.to_untyped(),
+                                                    *self.content_age.borrow());
+    }
+
+    /// Returns the tiles of this layer, for backends to prepare before drawing them.
+    pub fn tile_grid<'a>(&'a self) -> RefMut<'a, TileGrid> {
+        self.tile_grid.borrow_mut()
     }
 
-    pub fn create_textures(&self, display: &NativeDisplay) {
-        self.tile_grid.borrow_mut().create_textures(display);
+    pub fn create_textures(&self, display: &NativeDisplay, texture_pool: &mut TexturePool) {
+        self.tile_grid.borrow_mut().create_textures(display, texture_pool);
     }
 
     pub fn do_for_all_tiles<F: FnMut(&Tile)>(&self, f: F) {
         self.tile_grid.borrow().do_for_all_tiles(f);
     }
 
+    /// Calls `f` with the rect of each tile that has nothing to draw yet, in layer coordinates.
+    pub fn do_for_all_missing_tiles<F: FnMut(&Rect<f32>)>(&self, f: F) {
+        self.tile_grid.borrow().do_for_all_missing_tiles(f);
+    }
+
+    /// Calls `f` for each tile painted at a previous resolution along with the part of it
+    /// that should be drawn in place of missing tiles, in layer coordinates.
+    pub fn do_for_all_fallback_tiles<F: FnMut(&Tile, &Rect<f32>)>(&self, f: F) {
+        self.tile_grid.borrow().do_for_all_fallback_tiles(f);
+    }
+
     pub fn update_transform_state(&self,
                                   parent_transform: &Matrix4,
                                   parent_perspective: &Matrix4,
@@ -218,6 +499,13 @@ impl<T> Layer<T> {
                                          &perspective_transform,
                                          &rect_without_scroll.origin);
         }
+
+        // The mask lies in the plane of this layer.
+        if let Some(ref mask_layer) = *self.mask_layer.borrow() {
+            mask_layer.update_transform_state(&ts.final_transform,
+                                              &Matrix4::identity(),
+                                              &rect_without_scroll.origin);
+        }
     }
 
     /// Calculate the amount of memory used by this layer and all its children.
@@ -226,14 +514,32 @@ impl<T> Layer<T> {
         let size_of_children : usize = self.children().iter().map(|ref child| -> usize {
             child.get_memory_usage()
         }).sum();
-        size_of_children + self.tile_grid.borrow().get_memory_usage()
+        let size_of_mask = self.mask_layer.borrow().as_ref().map_or(0, |mask_layer| {
+            mask_layer.get_memory_usage()
+        });
+        size_of_children + size_of_mask + self.tile_grid.borrow().get_memory_usage()
     }
 }
 
+/// How urgently a buffer is needed, from the most to the least urgent.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum BufferRequestPriority {
+    /// The tile is visible and there is nothing to draw in its place.
+    Visible,
+
+    /// The tile is visible, and a tile painted at a previous resolution is drawn in its place
+    /// for now.
+    LowResolutionFallback,
+
+    /// The tile is outside the viewport, and is painted ahead of being scrolled into view.
+    Prefetch,
+}
+
 /// A request from the compositor to the renderer for tiles that need to be (re)displayed.
 pub struct BufferRequest {
-    /// The rect in pixels that will be drawn to the screen
-    pub screen_rect: Rect<usize>,
+    /// The rect in pixels that will be drawn to the screen. It may have a negative origin for
+    /// content above or to the left of the layer origin.
+    pub screen_rect: Rect<i32>,
 
     /// The rect in page coordinates that this tile represents
     pub page_rect: Rect<f32>,
@@ -241,18 +547,35 @@ pub struct BufferRequest {
     /// The content age of that this BufferRequest corresponds to.
     pub content_age: ContentAge,
 
-    /// A cached NativeSurface that can be used to avoid allocating a new one.
+    /// A cached NativeSurface that can be used to avoid allocating a new one. It is taken
+    /// from an unused buffer, which marks it as not leaking first, so the painting task owns
+    /// it from here on: it must either paint into it and send it back in a `LayerBuffer`, or
+    /// destroy it.
     pub native_surface: Option<NativeSurface>,
+
+    /// How urgently the buffer is needed.
+    pub priority: BufferRequestPriority,
+
+    /// How far the tile is from the viewport in device pixels, or zero if it's visible.
+    pub distance_to_viewport: f32,
+
+    /// The scale that the buffer should be painted at, which is the scale of the scene
+    /// multiplied by the raster scale of the layer. Buffers painted at another scale are
+    /// thrown out.
+    pub resolution: f32,
 }
 
 impl BufferRequest {
-    pub fn new(screen_rect: Rect<usize>, page_rect: Rect<f32>, content_age: ContentAge)
+    pub fn new(screen_rect: Rect<i32>, page_rect: Rect<f32>, content_age: ContentAge)
                -> BufferRequest {
         BufferRequest {
             screen_rect: screen_rect,
             page_rect: page_rect,
             content_age: content_age,
             native_surface: None,
+            priority: BufferRequestPriority::Visible,
+            distance_to_viewport: 0.0,
+            resolution: 1.0,
         }
     }
 }
@@ -266,7 +589,7 @@ pub struct LayerBuffer {
     pub rect: Rect<f32>,
 
     /// The rect in pixels that will be drawn to the screen.
-    pub screen_pos: Rect<usize>,
+    pub screen_pos: Rect<i32>,
 
     /// The scale at which this tile is rendered
     pub resolution: f32,
@@ -291,7 +614,7 @@ impl LayerBuffer {
 
     /// Returns the Size2D of the tile
     pub fn get_size_2d(&self) -> Size2D<usize> {
-        self.screen_pos.size
+        Size2D::new(self.screen_pos.size.width as usize, self.screen_pos.size.height as usize)
     }
 
     /// Marks the layer buffer as not leaking. See comments on
@@ -307,6 +630,44 @@ impl LayerBuffer {
     }
 }
 
+/// A painter's answer to a `BufferRequest` for a tile that is a single color. It is drawn
+/// without a native surface or a texture.
+#[derive(Clone, Copy)]
+pub struct SolidColorTile {
+    /// The color of the tile, with premultiplied alpha.
+    pub color: Color,
+
+    /// The rect in the containing RenderLayer that this represents.
+    pub rect: Rect<f32>,
+
+    /// The rect in pixels that will be drawn to the screen.
+    pub screen_pos: Rect<i32>,
+
+    /// The scale at which this tile was painted.
+    pub resolution: f32,
+
+    /// The content age of the buffer request this answers.
+    pub content_age: ContentAge,
+}
+
+impl SolidColorTile {
+    /// Answers `request` with a tile painted in `color`.
+    pub fn new(request: &BufferRequest, color: Color) -> SolidColorTile {
+        SolidColorTile {
+            color: color,
+            rect: request.page_rect,
+            screen_pos: request.screen_rect,
+            resolution: request.resolution,
+            content_age: request.content_age,
+        }
+    }
+
+    /// Returns true if the tile is displayable at the given scale.
+    pub fn is_valid(&self, scale: f32) -> bool {
+        (self.resolution - scale).abs() < 1.0e-6
+    }
+}
+
 /// A set of layer buffers. This is an atomic unit used to switch between the front and back
 /// buffers.
 pub struct LayerBufferSet {
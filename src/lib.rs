@@ -40,10 +40,16 @@ extern crate glx;
 #[cfg(target_os="android")]
 extern crate egl;
 
+pub mod animation;
+pub mod bsp;
 pub mod color;
+pub mod damage;
+pub mod filters;
 pub mod geometry;
 pub mod layers;
+pub mod renderer;
 pub mod rendergl;
+pub mod rendersw;
 pub mod scene;
 pub mod texturegl;
 pub mod tiling;
@@ -203,6 +203,19 @@ impl NativeSurface {
         size.width as usize * size.height as usize
     }
 
+    /// Returns the surface as a `MemoryBufferNativeSurface` if its pixels live in CPU memory.
+    pub fn as_memory_buffer(&self) -> Option<&MemoryBufferNativeSurface> {
+        match *self {
+            NativeSurface::MemoryBuffer(ref surface) => Some(surface),
+            #[cfg(target_os="linux")]
+            NativeSurface::Pixmap(_) => None,
+            #[cfg(target_os="macos")]
+            NativeSurface::IOSurface(_) => None,
+            #[cfg(target_os="android")]
+            NativeSurface::EGLImage(_) => None,
+        }
+    }
+
     /// Get the size of this native surface.
     pub fn get_size(&self) -> Size2D<i32> {
         native_surface_property!(self size)
@@ -223,6 +236,12 @@ impl MemoryBufferNativeSurface {
         }
     }
 
+    /// Returns the pixel data of this surface in BGRA order with premultiplied alpha. This is
+    /// empty if nothing has been uploaded yet.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
     /// This may only be called on the compositor side.
     #[cfg(not(target_os="android"))]
     pub fn bind_to_texture(&self, _: &NativeDisplay, texture: &Texture) {
@@ -0,0 +1,1208 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Backend-neutral compositing. This walks the layer tree, builds and orders the 3d rendering
+//! contexts and clips layers, then hands the resulting draw items to a `Renderer`.
+
+use bsp::{BspTree, Polygon};
+use color::Color;
+use filters::{Filter, filters_outset};
+use layers::{BlendMode, BorderRadii, Layer};
+use scene::Scene;
+use tiling::{Tile, TileGrid};
+use util::{clip_layer_polygon_to_screen_polygon, intersect_convex_polygons};
+use util::{polygon_as_rect, polygon_bounding_rect, project_rect_to_polygon, rect_to_polygon};
+use util::{project_rect_to_screen, screen_to_plane_homography, unproject_point_to_plane};
+use util::transform_scale_factor;
+
+use euclid::matrix::Matrix4;
+use euclid::Matrix2D;
+use euclid::point::Point2D;
+use euclid::rect::Rect;
+use euclid::size::Size2D;
+use std::cell::Cell;
+use std::rc::Rc;
+
+static TILE_DEBUG_BORDER_COLOR: Color = Color { r: 0., g: 1., b: 1., a: 1.0 };
+static TILE_DEBUG_BORDER_THICKNESS: usize = 1;
+static LAYER_DEBUG_BORDER_COLOR: Color = Color { r: 1., g: 0.5, b: 0., a: 1.0 };
+static LAYER_DEBUG_BORDER_THICKNESS: usize = 2;
+static LAYER_AABB_DEBUG_BORDER_COLOR: Color = Color { r: 1., g: 0.0, b: 0., a: 1.0 };
+static LAYER_AABB_DEBUG_BORDER_THICKNESS: usize = 1;
+
+/// How tiles that have no content yet are drawn.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CheckerboardStyle {
+    pub color: Color,
+
+    /// The color of every other square, or `None` to fill missing tiles with `color` only.
+    pub alternate_color: Option<Color>,
+
+    /// The size of the squares, in layer pixels.
+    pub square_size: f32,
+}
+
+/// How much content was missing in the last composited frame.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CheckerboardCounters {
+    /// The number of visible tiles that had nothing to draw.
+    pub missing_tiles: usize,
+
+    /// The area covered by those tiles, in device pixels. Overlapping layers are counted
+    /// separately.
+    pub checkerboarded_area: f32,
+}
+
+impl CheckerboardCounters {
+    pub fn new() -> CheckerboardCounters {
+        CheckerboardCounters {
+            missing_tiles: 0,
+            checkerboarded_area: 0.0,
+        }
+    }
+}
+
+/// State shared by everything drawn in a frame.
+struct FrameState {
+    /// The visible area, in unscaled screen coordinates.
+    viewport: Rect<f32>,
+
+    /// The factor from screen coordinates to device pixels.
+    scale: f32,
+
+    checkerboard_style: Option<CheckerboardStyle>,
+    checkerboard_counters: Cell<CheckerboardCounters>,
+}
+
+/// The largest number of rounded clips applied to an item. Only the innermost ones are kept.
+pub const MAX_ROUNDED_CLIPS: usize = 4;
+
+/// A rounded rectangle that items are clipped to, with anti-aliased edges. This comes from an
+/// ancestor that masks to its bounds and has rounded corners.
+#[derive(Clone)]
+pub struct RoundedClip {
+    /// The rectangle, in world coordinates on the plane of the clipping layer.
+    pub rect: Rect<f32>,
+
+    pub radii: BorderRadii,
+
+    /// Maps unscaled screen points `(x, y, 1)` to homogeneous points on the plane of the
+    /// clipping layer, as a row-major 3x3 matrix.
+    pub screen_to_plane: [f32; 9],
+}
+
+impl RoundedClip {
+    /// Returns the rounded clip of a layer, or `None` if its plane is seen edge-on.
+    pub fn new<T>(layer: &Layer<T>) -> Option<RoundedClip> {
+        let ts = layer.transform_state.borrow();
+        screen_to_plane_homography(&ts.final_transform).map(|screen_to_plane| {
+            RoundedClip {
+                rect: ts.world_rect,
+                radii: *layer.border_radii.borrow(),
+                screen_to_plane: screen_to_plane,
+            }
+        })
+    }
+
+    fn screen_to_plane(&self, point: &Point2D<f32>) -> Option<Point2D<f32>> {
+        let m = &self.screen_to_plane;
+        let x = m[0] * point.x + m[1] * point.y + m[2];
+        let y = m[3] * point.x + m[4] * point.y + m[5];
+        let w = m[6] * point.x + m[7] * point.y + m[8];
+        if w <= 0.0 {
+            None
+        } else {
+            Some(Point2D::new(x / w, y / w))
+        }
+    }
+
+    /// Returns the signed distance from a point on the plane to the edge of the rounded
+    /// rectangle, which is negative inside. The distance to elliptical corners is approximated.
+    fn signed_distance(&self, point: &Point2D<f32>) -> f32 {
+        let rect = &self.rect;
+        let corners = [
+            (self.radii.top_left, Point2D::new(rect.min_x(), rect.min_y()), 1.0, 1.0),
+            (self.radii.top_right, Point2D::new(rect.max_x(), rect.min_y()), -1.0, 1.0),
+            (self.radii.bottom_right, Point2D::new(rect.max_x(), rect.max_y()), -1.0, -1.0),
+            (self.radii.bottom_left, Point2D::new(rect.min_x(), rect.max_y()), 1.0, -1.0),
+        ];
+
+        for &(radius, corner, direction_x, direction_y) in corners.iter() {
+            if radius.width <= 0.0 || radius.height <= 0.0 {
+                continue;
+            }
+
+            // The center of the corner ellipse, and the point relative to it in units of the
+            // radii.
+            let center = Point2D::new(corner.x + direction_x * radius.width,
+                                      corner.y + direction_y * radius.height);
+            let x = (point.x - center.x) / radius.width;
+            let y = (point.y - center.y) / radius.height;
+            if x * direction_x >= 0.0 || y * direction_y >= 0.0 {
+                continue; // Not in the corner region.
+            }
+
+            let length = (x * x + y * y).sqrt();
+            let gradient_x = x / radius.width;
+            let gradient_y = y / radius.height;
+            let gradient_length = (gradient_x * gradient_x + gradient_y * gradient_y).sqrt();
+            if gradient_length <= 0.0 {
+                return -radius.width.min(radius.height);
+            }
+            return (length - 1.0) * length / gradient_length;
+        }
+
+        (rect.min_x() - point.x).max(point.x - rect.max_x())
+                                .max(rect.min_y() - point.y)
+                                .max(point.y - rect.max_y())
+    }
+
+    /// Whether a point in unscaled screen coordinates is inside the clip.
+    pub fn contains(&self, screen_point: &Point2D<f32>) -> bool {
+        match self.screen_to_plane(screen_point) {
+            Some(point) => self.signed_distance(&point) <= 0.0,
+            None => false,
+        }
+    }
+
+    /// Returns how much of the pixel centered at `screen_point` is inside the clip, from 0.0 to
+    /// 1.0. `pixel_size` is the size of a device pixel in unscaled screen coordinates.
+    pub fn coverage(&self, screen_point: &Point2D<f32>, pixel_size: f32) -> f32 {
+        let point = match self.screen_to_plane(screen_point) {
+            Some(point) => point,
+            None => return 0.0,
+        };
+
+        // Measure the distance in device pixels, using the size of a pixel on the plane.
+        let neighbors = [Point2D::new(screen_point.x + pixel_size, screen_point.y),
+                         Point2D::new(screen_point.x, screen_point.y + pixel_size)];
+        let mut plane_pixel_size: f32 = 0.0;
+        for neighbor in neighbors.iter() {
+            if let Some(neighbor) = self.screen_to_plane(neighbor) {
+                let (dx, dy) = (neighbor.x - point.x, neighbor.y - point.y);
+                plane_pixel_size = plane_pixel_size.max((dx * dx + dy * dy).sqrt());
+            }
+        }
+        if plane_pixel_size <= 0.0 {
+            return 1.0;
+        }
+
+        (0.5 - self.signed_distance(&point) / plane_pixel_size).max(0.0).min(1.0)
+    }
+}
+
+/// A rectangle filled with a single color.
+pub struct SolidQuad {
+    /// The rectangle to fill, in world coordinates.
+    pub rect: Rect<f32>,
+
+    /// The transform from world coordinates to unscaled screen coordinates.
+    pub transform: Matrix4,
+
+    /// The premultiplied fill color.
+    pub color: Color,
+
+    /// How the quad is blended with what has been drawn before.
+    pub blend_mode: BlendMode,
+
+    /// If set, only the part of `rect` inside this convex polygon (in world coordinates) is
+    /// drawn.
+    pub clip_polygon: Option<Vec<Point2D<f32>>>,
+    /// Rounded rectangles the quad is clipped to, from the outermost to the innermost.
+    pub rounded_clips: Vec<RoundedClip>,
+}
+
+/// A rectangle textured with (part of) the contents of a tile.
+pub struct TileQuad<'a> {
+    /// The tile whose contents are drawn. It always has a buffer.
+    pub tile: &'a Tile,
+
+    /// The rectangle to fill, in world coordinates.
+    pub rect: Rect<f32>,
+
+    /// The part of the tile that is mapped onto `rect`, in normalized texture coordinates.
+    pub texture_rect: Rect<f32>,
+
+    /// The transform from world coordinates to unscaled screen coordinates.
+    pub transform: Matrix4,
+
+    /// The opacity to draw the tile with.
+    pub opacity: f32,
+
+    /// How the quad is blended with what has been drawn before.
+    pub blend_mode: BlendMode,
+
+    /// If set, only the part of `rect` inside this convex polygon (in world coordinates) is
+    /// drawn.
+    pub clip_polygon: Option<Vec<Point2D<f32>>>,
+    /// Rounded rectangles the quad is clipped to, from the outermost to the innermost.
+    pub rounded_clips: Vec<RoundedClip>,
+}
+
+/// The outline of a rectangle, used for debugging.
+pub struct DebugLines {
+    /// The rectangle to outline, in world coordinates.
+    pub rect: Rect<f32>,
+
+    /// The transform from world coordinates to unscaled screen coordinates.
+    pub transform: Matrix4,
+
+    pub color: Color,
+    pub thickness: usize,
+}
+
+/// Effects applied to a group of layers as a whole, after compositing them offscreen.
+pub struct GroupEffects {
+    /// The filters to apply, in order.
+    pub filters: Vec<Filter>,
+
+    /// The opacity to composite the group with.
+    pub opacity: f32,
+
+    /// How the group is blended with what has been drawn before.
+    pub blend_mode: BlendMode,
+}
+
+/// A compositing backend. The traversal in `composite_scene` calls these methods in paint
+/// order, so a backend only needs to know how to draw each kind of item.
+pub trait Renderer {
+    /// Called before anything is drawn. `viewport` is the area being composited, in device
+    /// pixels, and `scale` is the factor from screen coordinates to device pixels.
+    fn begin_frame(&mut self, viewport: &Rect<f32>, scale: f32);
+
+    /// Called before the layers of each 3d rendering context are drawn. Layers within a context
+    /// are depth tested against each other, but not against layers of other contexts.
+    fn begin_3d_context(&mut self);
+
+    /// Called once for each layer with its tiles before any of its items are drawn. Backends
+    /// can use this to upload tile contents.
+    fn prepare_layer(&mut self, _tile_grid: &mut TileGrid) {
+    }
+
+    fn draw_solid_quad(&mut self, quad: &SolidQuad);
+
+    fn draw_tile_quad(&mut self, quad: &TileQuad);
+
+    /// Starts an offscreen group. Items drawn until the matching `end_group` go into a new,
+    /// transparent surface covering `bounds`, in unscaled screen coordinates. Groups nest.
+    fn begin_group(&mut self, bounds: &Rect<f32>);
+
+    /// Starts drawing the mask of the innermost group. Items drawn until `end_mask` go into a
+    /// new, transparent surface covering the same area as the group, whose alpha channel
+    /// later multiplies the group.
+    fn begin_mask(&mut self);
+
+    fn end_mask(&mut self);
+
+    /// Applies `effects` and the mask, if one was drawn, to the innermost group and composites
+    /// it into the enclosing surface.
+    fn end_group(&mut self, effects: &GroupEffects);
+
+    /// Backends that can't draw lines may ignore this.
+    fn draw_debug_lines(&mut self, _lines: &DebugLines) {
+    }
+
+    /// Whether the traversal should produce debug borders for layers and tiles.
+    fn show_debug_borders(&self) -> bool {
+        false
+    }
+
+    /// Called after everything has been drawn.
+    fn end_frame(&mut self) {
+    }
+}
+
+pub struct RenderContextChild<T> {
+    pub layer: Option<Rc<Layer<T>>>,
+    pub context: Option<RenderContext3D<T>>,
+
+    /// Whether this is the layer of a group context. Its opacity and blend mode are then
+    /// applied to the group as a whole rather than to the layer.
+    pub is_group_layer: bool,
+}
+
+/// One step of drawing a 3d rendering context, referring to a child by its index.
+pub enum DrawStep {
+    /// Draw the layer of a child. If a polygon is given, only the part of the layer inside it
+    /// (in world coordinates) is drawn; this happens when the layer was split by another one.
+    Layer(usize, Option<Vec<Point2D<f32>>>),
+
+    /// Draw the nested 3d rendering context of a child.
+    Context(usize),
+}
+
+pub struct RenderContext3D<T>{
+    pub children: Vec<RenderContextChild<T>>,
+
+    /// The region that the children of this context are clipped to by ancestors that mask to
+    /// their bounds, as a convex polygon in screen coordinates. An empty polygon means that
+    /// everything is clipped away.
+    pub clip_polygon: Option<Vec<Point2D<f32>>>,
+
+    /// The rounded clips of ancestors that apply to the children of this context, from the
+    /// outermost to the innermost.
+    pub rounded_clips: Vec<RoundedClip>,
+
+    /// The children of this context, split and ordered back-to-front.
+    pub draw_order: Vec<DrawStep>,
+
+    /// If set, this context holds a layer and its descendants that are composited offscreen
+    /// with these effects. The layer itself is then drawn as part of this context, rather than
+    /// by its parent.
+    pub effects: Option<GroupEffects>,
+
+    /// The layer whose tiles mask a group context, after its effects have been applied.
+    pub mask_layer: Option<Rc<Layer<T>>>,
+
+    /// Whether fully transparent layers are kept, which hit testing needs since they still
+    /// receive events. They are left out when drawing.
+    keeps_transparent_layers: bool,
+
+    /// Whether visible tiles without content are drawn as a checkerboard, in which case they
+    /// overlap the background of their layer.
+    draws_checkerboard: bool,
+}
+
+impl<T> RenderContext3D<T> {
+    pub fn new(layer: Rc<Layer<T>>) -> RenderContext3D<T> {
+        RenderContext3D::build_root(layer, false, false)
+    }
+
+    /// Builds the context for drawing a frame in which visible tiles without content are
+    /// drawn as a checkerboard.
+    pub fn new_with_checkerboard(layer: Rc<Layer<T>>) -> RenderContext3D<T> {
+        RenderContext3D::build_root(layer, false, true)
+    }
+
+    /// Builds the context for hit testing, which unlike drawing includes fully transparent
+    /// layers.
+    pub fn new_for_hit_testing(layer: Rc<Layer<T>>) -> RenderContext3D<T> {
+        RenderContext3D::build_root(layer, true, false)
+    }
+
+    fn build_root(layer: Rc<Layer<T>>,
+                  keeps_transparent_layers: bool,
+                  draws_checkerboard: bool)
+                  -> RenderContext3D<T> {
+        let mut render_context = RenderContext3D {
+            children: vec!(),
+            clip_polygon: RenderContext3D::calculate_context_clip(layer.clone(), None),
+            rounded_clips: RenderContext3D::calculate_rounded_clips(&*layer, &[]),
+            draw_order: vec!(),
+            effects: None,
+            mask_layer: None,
+            keeps_transparent_layers: keeps_transparent_layers,
+            draws_checkerboard: draws_checkerboard,
+        };
+        layer.build(&mut render_context);
+        render_context.split_children();
+        render_context
+    }
+
+    /// Returns the layer that a group context was created for, if it is drawn.
+    fn group_layer(&self) -> Option<&Rc<Layer<T>>> {
+        self.children.iter().filter(|child| child.is_group_layer).filter_map(|child| {
+            child.layer.as_ref()
+        }).next()
+    }
+
+    fn build_child(layer: Rc<Layer<T>>,
+                   parent: &RenderContext3D<T>)
+                   -> Option<RenderContext3D<T>> {
+        let parent_clip_polygon = parent.clip_polygon.as_ref();
+        let parent_rounded_clips = &parent.rounded_clips;
+        let clip_polygon =
+            RenderContext3D::calculate_context_clip(layer.clone(), parent_clip_polygon);
+        if let Some(ref clip_polygon) = clip_polygon {
+            if clip_polygon.is_empty() {
+                return None;
+            }
+        }
+
+        let mut render_context = RenderContext3D {
+            children: vec!(),
+            clip_polygon: clip_polygon,
+            rounded_clips: RenderContext3D::calculate_rounded_clips(&*layer, parent_rounded_clips),
+            draw_order: vec!(),
+            effects: None,
+            mask_layer: None,
+            keeps_transparent_layers: parent.keeps_transparent_layers,
+            draws_checkerboard: parent.draws_checkerboard,
+        };
+
+        for child in layer.children().iter() {
+            child.build(&mut render_context);
+        }
+
+        render_context.split_children();
+        Some(render_context)
+    }
+
+    /// Builds the context for a layer whose subtree is composited offscreen. Returns `None` if
+    /// nothing in the subtree is visible.
+    fn build_group(layer: Rc<Layer<T>>,
+                   parent: &RenderContext3D<T>,
+                   effects: GroupEffects)
+                   -> Option<RenderContext3D<T>> {
+        let mut render_context = RenderContext3D {
+            children: vec!(),
+            clip_polygon: parent.clip_polygon.clone(),
+            rounded_clips: parent.rounded_clips.clone(),
+            draw_order: vec!(),
+            effects: None,
+            mask_layer: None,
+            keeps_transparent_layers: parent.keeps_transparent_layers,
+            draws_checkerboard: parent.draws_checkerboard,
+        };
+
+        layer.build_ungrouped(&mut render_context, true);
+        if render_context.children.is_empty() {
+            return None;
+        }
+
+        render_context.split_children();
+        render_context.effects = Some(effects);
+        render_context.mask_layer = layer.mask_layer.borrow().clone();
+        Some(render_context)
+    }
+
+    /// Whether this child is drawn as an offscreen group.
+    fn child_is_group(&self, index: usize) -> bool {
+        match self.children[index].context {
+            Some(ref context) => context.effects.is_some(),
+            None => false,
+        }
+    }
+
+    /// Returns the bounding rect of all layers in this context and nested contexts, in
+    /// unscaled screen coordinates.
+    fn bounds(&self) -> Option<Rect<f32>> {
+        let mut bounds: Option<Rect<f32>> = None;
+        for child in self.children.iter() {
+            let layer_bounds = child.layer.as_ref().and_then(|layer| {
+                layer.transform_state.borrow().screen_rect.as_ref().map(|screen_rect| {
+                    screen_rect.rect
+                })
+            });
+            let context_bounds = child.context.as_ref().and_then(|context| context.bounds());
+            for rect in layer_bounds.iter().chain(context_bounds.iter()) {
+                bounds = Some(bounds.map_or(*rect, |bounds| bounds.union(rect)));
+            }
+        }
+        bounds
+    }
+
+    /// Orders the children with a BSP tree, splitting layers that intersect each other so
+    /// that every fragment can be drawn back-to-front. Layers that don't intersect are drawn
+    /// whole, and coplanar layers are drawn in paint order.
+    fn split_children(&mut self) {
+        let mut tree = BspTree::new();
+        let mut fragment_counts = vec![0; self.children.len()];
+
+        for (index, child) in self.children.iter().enumerate() {
+            let layer = match child.layer {
+                Some(ref layer) => layer,
+                None => continue,
+            };
+
+            let ts = layer.transform_state.borrow();
+            let polygon = project_rect_to_polygon(&ts.world_rect, &ts.final_transform)
+                .and_then(|points| Polygon::new(points, index));
+            if let Some(polygon) = polygon {
+                tree.insert(polygon);
+            }
+        }
+
+        let polygons = tree.back_to_front();
+        for polygon in polygons.iter() {
+            fragment_counts[polygon.anchor] += 1;
+        }
+
+        let mut draw_order = vec!();
+        for polygon in polygons.iter() {
+            let index = polygon.anchor;
+            let child = &self.children[index];
+
+            let clip_polygon = if polygon.is_split {
+                let layer = child.layer.as_ref().unwrap();
+                fragment_in_world_space(polygon, &layer.transform_state.borrow().final_transform)
+            } else {
+                None
+            };
+            // The layer of a group is drawn along with the rest of the group.
+            if !self.child_is_group(index) {
+                draw_order.push(DrawStep::Layer(index, clip_polygon));
+            }
+
+            // Descendants in a nested context are drawn after the last fragment of their layer.
+            fragment_counts[index] -= 1;
+            if fragment_counts[index] == 0 && child.context.is_some() {
+                draw_order.push(DrawStep::Context(index));
+            }
+        }
+
+        // A layer that is clipped away or seen edge-on isn't in the tree, but its nested
+        // context can still be visible. These are drawn on top, in paint order.
+        for (index, child) in self.children.iter().enumerate() {
+            let in_tree = polygons.iter().any(|polygon| polygon.anchor == index);
+            if !in_tree && child.context.is_some() {
+                draw_order.push(DrawStep::Context(index));
+            }
+        }
+
+        self.draw_order = draw_order;
+    }
+
+    fn calculate_context_clip(layer: Rc<Layer<T>>,
+                              parent_clip_polygon: Option<&Vec<Point2D<f32>>>)
+                              -> Option<Vec<Point2D<f32>>> {
+        // TODO(gw): This doesn't work for iframes that are transformed.
+        if !*layer.masks_to_bounds.borrow() {
+            return parent_clip_polygon.cloned();
+        }
+
+        // Use the projected outline of the layer rather than its bounding box, so that
+        // ancestors with 3d transforms clip exactly.
+        let ts = layer.transform_state.borrow();
+        let layer_clip: Vec<Point2D<f32>> =
+            match project_rect_to_polygon(&ts.world_rect, &ts.final_transform) {
+                Some(points) => points.iter().map(|point| Point2D::new(point.x, point.y)).collect(),
+                None => return Some(vec!()), // Layer is entirely clipped away.
+            };
+
+        let parent_clip_polygon = match parent_clip_polygon {
+            Some(parent_clip_polygon) => parent_clip_polygon,
+            None => return Some(layer_clip),
+        };
+
+        // Intersect rectangles directly so that nested 2d clips stay exact rectangles.
+        match (polygon_as_rect(&layer_clip), polygon_as_rect(parent_clip_polygon)) {
+            (Some(layer_clip), Some(parent_clip)) => match layer_clip.intersection(&parent_clip) {
+                Some(intersected_clip) => Some(rect_to_polygon(&intersected_clip)),
+                None => Some(vec!()), // No intersection.
+            },
+            _ => Some(intersect_convex_polygons(&layer_clip, parent_clip_polygon)),
+        }
+    }
+
+    /// Adds the rounded clip of a layer, if it has one, to the clips of its parent.
+    fn calculate_rounded_clips(layer: &Layer<T>, parent_rounded_clips: &[RoundedClip])
+                               -> Vec<RoundedClip> {
+        let mut rounded_clips = parent_rounded_clips.to_vec();
+        if !*layer.masks_to_bounds.borrow() || layer.border_radii.borrow().is_zero() {
+            return rounded_clips;
+        }
+
+        if let Some(rounded_clip) = RoundedClip::new(layer) {
+            rounded_clips.push(rounded_clip);
+            if rounded_clips.len() > MAX_ROUNDED_CLIPS {
+                rounded_clips.remove(0);
+            }
+        }
+        rounded_clips
+    }
+
+    fn add_child(&mut self,
+                 layer: Option<Rc<Layer<T>>>,
+                 child_context: Option<RenderContext3D<T>>,
+                 is_group_layer: bool) {
+        self.children.push(RenderContextChild {
+            layer: layer,
+            context: child_context,
+            is_group_layer: is_group_layer,
+        });
+    }
+}
+
+/// Maps a fragment produced by the BSP tree back onto the plane of its layer, giving a
+/// polygon in world coordinates. Returns `None` if that is not possible, in which case the
+/// whole layer is drawn.
+fn fragment_in_world_space(polygon: &Polygon, transform: &Matrix4) -> Option<Vec<Point2D<f32>>> {
+    let mut points = vec!();
+    for point in polygon.points.iter() {
+        match unproject_point_to_plane(&Point2D::new(point.x, point.y), transform) {
+            Some((world_point, _)) => points.push(world_point),
+            None => return None,
+        }
+    }
+    Some(points)
+}
+
+pub trait RenderContext3DBuilder<T> {
+    fn build(&self, current_context: &mut RenderContext3D<T>);
+}
+
+trait GroupBuilder<T> {
+    /// Builds a layer into a context as if it had no group effects. `is_group_layer` is true
+    /// when the layer is the one that the context was created for.
+    fn build_ungrouped(&self, current_context: &mut RenderContext3D<T>, is_group_layer: bool);
+}
+
+/// Returns the effects that require a layer and its descendants to be composited offscreen,
+/// or `None` if the layer can be drawn directly. Opacity and blend modes apply to the subtree
+/// as a whole, so they need a group unless the layer draws a single item at every point.
+fn group_effects<T>(layer: &Layer<T>, draws_checkerboard: bool) -> Option<GroupEffects> {
+    let filters = layer.filters.borrow();
+    let opacity = *layer.opacity.borrow();
+    let blend_mode = *layer.blend_mode.borrow();
+    let needs_flattening = (opacity < 1.0 || blend_mode != BlendMode::Normal) &&
+                           !draws_at_most_once(layer, draws_checkerboard);
+    if filters.is_empty() && layer.mask_layer.borrow().is_none() && !needs_flattening {
+        return None;
+    }
+
+    Some(GroupEffects {
+        filters: filters.clone(),
+        opacity: opacity,
+        blend_mode: blend_mode,
+    })
+}
+
+/// Whether no two items drawn for a layer and its descendants overlap, in which case applying
+/// the opacity or blend mode to every item gives the same result as applying it to the group.
+/// Missing tiles only count when `draws_checkerboard` is set, since they draw nothing otherwise.
+fn draws_at_most_once<T>(layer: &Layer<T>, draws_checkerboard: bool) -> bool {
+    if !layer.children.borrow().is_empty() {
+        return false;
+    }
+
+    // Tiles don't overlap each other, but they do overlap the background.
+    if layer.background_color.borrow().a == 0.0 {
+        return true;
+    }
+    let mut has_tiles = false;
+    layer.do_for_all_tiles(|tile: &Tile| {
+        has_tiles = has_tiles || tile.has_content();
+    });
+    layer.do_for_all_fallback_tiles(|_: &Tile, _: &Rect<f32>| has_tiles = true);
+    if draws_checkerboard {
+        layer.do_for_all_missing_tiles(|_: &Rect<f32>| has_tiles = true);
+    }
+    !has_tiles
+}
+
+impl<T> RenderContext3DBuilder<T> for Rc<Layer<T>> {
+    fn build(&self, current_context: &mut RenderContext3D<T>) {
+        // Nothing in a fully transparent subtree can be seen.
+        if *self.opacity.borrow() <= 0.0 && !current_context.keeps_transparent_layers {
+            return;
+        }
+
+        if let Some(effects) = group_effects(&**self, current_context.draws_checkerboard) {
+            let group = RenderContext3D::build_group(self.clone(), current_context, effects);
+            if group.is_some() {
+                let layer = match self.transform_state.borrow().screen_rect {
+                    Some(_) => Some(self.clone()),
+                    None => None,
+                };
+                current_context.add_child(layer, group, false);
+            }
+            return;
+        }
+
+        self.build_ungrouped(current_context, false);
+    }
+}
+
+impl<T> GroupBuilder<T> for Rc<Layer<T>> {
+    fn build_ungrouped(&self, current_context: &mut RenderContext3D<T>, is_group_layer: bool) {
+        let layer = match self.transform_state.borrow().screen_rect {
+            Some(_) => Some(self.clone()),
+            None => None, // Layer is entirely clipped.
+        };
+
+        if !self.children.borrow().is_empty() && self.establishes_3d_context {
+            let child_context = RenderContext3D::build_child(self.clone(), current_context);
+            if child_context.is_some() {
+                current_context.add_child(layer, child_context, is_group_layer);
+                return;
+            }
+        };
+
+        // If we are completely clipped out, don't add anything to this context.
+        if layer.is_none() {
+            return;
+        }
+
+        current_context.add_child(layer, None, is_group_layer);
+
+        for child in self.children().iter() {
+            child.build(current_context);
+        }
+    }
+}
+
+/// The part of a layer that is left after clipping, in world coordinates.
+enum LayerClip {
+    Unclipped,
+    Rect(Rect<f32>),
+    Polygon(Vec<Point2D<f32>>),
+    Invisible,
+}
+
+/// Works out which part of a layer to draw, given the clip of its context in screen space and
+/// the BSP fragment being drawn (if the layer was split).
+fn layer_clip<T>(layer: &Layer<T>,
+                 context_clip: Option<&Vec<Point2D<f32>>>,
+                 fragment: Option<&Vec<Point2D<f32>>>)
+                 -> LayerClip {
+    let ts = layer.transform_state.borrow();
+    let m = ts.final_transform;
+
+    let context_clip = match context_clip {
+        Some(context_clip) => context_clip,
+        None => return match fragment {
+            Some(fragment) => LayerClip::Polygon(fragment.clone()),
+            None => LayerClip::Unclipped,
+        },
+    };
+
+    if context_clip.is_empty() {
+        return LayerClip::Invisible;
+    }
+
+    // See https://drafts.csswg.org/css-transforms/#2d-matrix
+    let is_3d_transform = m.m31 != 0.0 || m.m32 != 0.0 ||
+                          m.m13 != 0.0 || m.m23 != 0.0 ||
+                          m.m43 != 0.0 || m.m14 != 0.0 ||
+                          m.m24 != 0.0 || m.m34 != 0.0 ||
+                          m.m33 != 1.0 || m.m44 != 1.0;
+    let is_axis_aligned = !is_3d_transform && m.m12 == 0.0 && m.m21 == 0.0;
+
+    // Axis-aligned layers clipped by a rectangle are by far the most common case, and can be
+    // clipped as rectangles.
+    if fragment.is_none() && is_axis_aligned {
+        if let Some(context_clip_rect) = polygon_as_rect(context_clip) {
+            // Invert the transform and back-transform the clip rect into world space.
+            let transform = m.invert();
+            let xform_2d = Matrix2D::new(transform.m11, transform.m12,
+                                         transform.m21, transform.m22,
+                                         transform.m41, transform.m42);
+            return LayerClip::Rect(xform_2d.transform_rect(&context_clip_rect));
+        }
+    }
+
+    let layer_polygon = match fragment {
+        Some(fragment) => fragment.clone(),
+        None => rect_to_polygon(&ts.world_rect),
+    };
+    let polygon = clip_layer_polygon_to_screen_polygon(&layer_polygon, &m, context_clip);
+    if polygon.is_empty() {
+        LayerClip::Invisible
+    } else {
+        LayerClip::Polygon(polygon)
+    }
+}
+
+/// How the items of a layer are drawn.
+struct DrawState<'a> {
+    /// If set, only the part of the layer inside this rectangle (in world coordinates) is drawn.
+    clip_rect: Option<Rect<f32>>,
+
+    /// If set, only the part of the layer inside this convex polygon (in world coordinates) is
+    /// drawn.
+    clip_polygon: Option<&'a Vec<Point2D<f32>>>,
+
+    rounded_clips: &'a [RoundedClip],
+    opacity: f32,
+    blend_mode: BlendMode,
+}
+
+fn render_layer<T, R: Renderer + ?Sized>(renderer: &mut R,
+                                         layer: Rc<Layer<T>>,
+                                         state: &DrawState,
+                                         frame: &FrameState) {
+    let ts = layer.transform_state.borrow();
+    let transform = ts.final_transform;
+    let background_color = *layer.background_color.borrow();
+    let opacity = state.opacity;
+
+    renderer.prepare_layer(&mut *layer.tile_grid());
+
+    let layer_rect = state.clip_rect.map_or(ts.world_rect, |clip_rect| {
+        match clip_rect.intersection(&ts.world_rect) {
+            Some(layer_rect) => layer_rect,
+            None => Rect::zero(),
+        }
+    });
+
+    if layer_rect.is_empty() {
+        return;
+    }
+
+    if background_color.a != 0.0 {
+        renderer.draw_solid_quad(&SolidQuad {
+            rect: layer_rect,
+            transform: transform,
+            color: Color {
+                r: background_color.r * opacity,
+                g: background_color.g * opacity,
+                b: background_color.b * opacity,
+                a: background_color.a * opacity,
+            },
+            blend_mode: state.blend_mode,
+            clip_polygon: state.clip_polygon.cloned(),
+            rounded_clips: state.rounded_clips.to_vec(),
+        });
+    }
+
+    // Tiles from a previous resolution stand in for the current tiles that are still being
+    // painted, underneath the ones that are ready.
+    layer.do_for_all_fallback_tiles(|tile: &Tile, rect: &Rect<f32>| {
+        let fallback_rect = rect.translate(&ts.world_rect.origin);
+        let clip_rect = match state.clip_rect {
+            Some(clip_rect) => clip_rect.intersection(&fallback_rect),
+            None => Some(fallback_rect),
+        };
+        if let Some(clip_rect) = clip_rect {
+            let fallback_state = DrawState {
+                clip_rect: Some(clip_rect),
+                clip_polygon: state.clip_polygon,
+                rounded_clips: state.rounded_clips,
+                opacity: state.opacity,
+                blend_mode: state.blend_mode,
+            };
+            render_tile(renderer, tile, &ts.world_rect.origin, &transform, &fallback_state);
+        }
+    });
+
+    layer.do_for_all_tiles(|tile: &Tile| {
+        render_tile(renderer, tile, &ts.world_rect.origin, &transform, state);
+    });
+
+    layer.do_for_all_missing_tiles(|rect: &Rect<f32>| {
+        let missing_rect = rect.translate(&ts.world_rect.origin).intersection(&layer_rect);
+        if let Some(missing_rect) = missing_rect {
+            render_missing_tile(renderer, &missing_rect, &transform, state, frame);
+        }
+    });
+
+    if renderer.show_debug_borders() {
+        renderer.draw_debug_lines(&DebugLines {
+            rect: layer_rect,
+            transform: transform,
+            color: LAYER_DEBUG_BORDER_COLOR,
+            thickness: LAYER_DEBUG_BORDER_THICKNESS,
+        });
+
+        renderer.draw_debug_lines(&DebugLines {
+            rect: ts.screen_rect.as_ref().unwrap().rect,
+            transform: Matrix4::identity(),
+            color: LAYER_AABB_DEBUG_BORDER_COLOR,
+            thickness: LAYER_AABB_DEBUG_BORDER_THICKNESS,
+        });
+    }
+}
+
+fn render_tile<R: Renderer + ?Sized>(renderer: &mut R,
+                                     tile: &Tile,
+                                     layer_origin: &Point2D<f32>,
+                                     transform: &Matrix4,
+                                     state: &DrawState) {
+    let tile_rect = match (tile.buffer(), tile.solid_color()) {
+        (Some(buffer), _) => buffer.rect.translate(layer_origin),
+        (None, Some(solid_color)) => solid_color.rect.translate(layer_origin),
+        (None, None) => return,
+    };
+
+    let clipped_tile_rect = state.clip_rect.map_or(tile_rect, |clip_rect| {
+        match clip_rect.intersection(&tile_rect) {
+            Some(clipped_tile_rect) => clipped_tile_rect,
+            None => Rect::zero(),
+        }
+    });
+
+    if clipped_tile_rect.is_empty() {
+       return;
+    }
+
+    // Tiles of a single color don't have a texture.
+    if let Some(solid_color) = tile.solid_color() {
+        let color = solid_color.color;
+        renderer.draw_solid_quad(&SolidQuad {
+            rect: clipped_tile_rect,
+            transform: *transform,
+            color: Color {
+                r: color.r * state.opacity,
+                g: color.g * state.opacity,
+                b: color.b * state.opacity,
+                a: color.a * state.opacity,
+            },
+            blend_mode: state.blend_mode,
+            clip_polygon: state.clip_polygon.cloned(),
+            rounded_clips: state.rounded_clips.to_vec(),
+        });
+        return;
+    }
+
+    let texture_rect_origin = clipped_tile_rect.origin - tile_rect.origin;
+    let texture_rect = Rect::new(
+        Point2D::new(texture_rect_origin.x / tile_rect.size.width,
+                     texture_rect_origin.y / tile_rect.size.height),
+        Size2D::new(clipped_tile_rect.size.width / tile_rect.size.width,
+                    clipped_tile_rect.size.height / tile_rect.size.height));
+
+    if renderer.show_debug_borders() {
+        renderer.draw_debug_lines(&DebugLines {
+            rect: clipped_tile_rect,
+            transform: *transform,
+            color: TILE_DEBUG_BORDER_COLOR,
+            thickness: TILE_DEBUG_BORDER_THICKNESS,
+        });
+    }
+
+    renderer.draw_tile_quad(&TileQuad {
+        tile: tile,
+        rect: clipped_tile_rect,
+        texture_rect: texture_rect,
+        transform: *transform,
+        opacity: state.opacity,
+        blend_mode: state.blend_mode,
+        clip_polygon: state.clip_polygon.cloned(),
+        rounded_clips: state.rounded_clips.to_vec(),
+    });
+}
+
+/// Counts a visible tile that has nothing to draw, and draws the checkerboard in its place.
+/// `rect` is the part of the tile inside the layer, in world coordinates.
+fn render_missing_tile<R: Renderer + ?Sized>(renderer: &mut R,
+                                             rect: &Rect<f32>,
+                                             transform: &Matrix4,
+                                             state: &DrawState,
+                                             frame: &FrameState) {
+    let visible_rect = project_rect_to_screen(rect, transform).and_then(|screen_rect| {
+        screen_rect.rect.intersection(&frame.viewport)
+    });
+    let visible_rect = match visible_rect {
+        Some(visible_rect) => visible_rect,
+        None => return,
+    };
+
+    let mut counters = frame.checkerboard_counters.get();
+    counters.missing_tiles += 1;
+    counters.checkerboarded_area +=
+        visible_rect.size.width * visible_rect.size.height * frame.scale * frame.scale;
+    frame.checkerboard_counters.set(counters);
+
+    let style = match frame.checkerboard_style {
+        Some(style) => style,
+        None => return,
+    };
+
+    let draw_rect = |renderer: &mut R, rect: Rect<f32>, color: &Color| {
+        renderer.draw_solid_quad(&SolidQuad {
+            rect: rect,
+            transform: *transform,
+            color: Color {
+                r: color.r * state.opacity,
+                g: color.g * state.opacity,
+                b: color.b * state.opacity,
+                a: color.a * state.opacity,
+            },
+            blend_mode: state.blend_mode,
+            clip_polygon: state.clip_polygon.cloned(),
+            rounded_clips: state.rounded_clips.to_vec(),
+        });
+    };
+
+    let alternate_color = match style.alternate_color {
+        Some(alternate_color) if style.square_size > 0.0 => alternate_color,
+        _ => {
+            draw_rect(renderer, *rect, &style.color);
+            return;
+        }
+    };
+
+    // Squares are aligned to the world origin, so that neighbouring tiles line up. Each square
+    // is drawn once in its own color, so that none of them blends over another when the layer
+    // is translucent.
+    let size = style.square_size;
+    let first_column = (rect.min_x() / size).floor() as i32;
+    let first_row = (rect.min_y() / size).floor() as i32;
+    let last_column = (rect.max_x() / size).ceil() as i32;
+    let last_row = (rect.max_y() / size).ceil() as i32;
+    for row in first_row..last_row {
+        for column in first_column..last_column {
+            let color = if (row + column) % 2 == 0 {
+                &style.color
+            } else {
+                &alternate_color
+            };
+            let square = Rect::new(Point2D::new(column as f32 * size, row as f32 * size),
+                                   Size2D::new(size, size));
+            if let Some(square) = square.intersection(rect) {
+                draw_rect(renderer, square, color);
+            }
+        }
+    }
+}
+
+/// Draws a group context offscreen and composites it with its effects.
+fn render_group<T, R: Renderer + ?Sized>(renderer: &mut R,
+                                         context: &RenderContext3D<T>,
+                                         effects: &GroupEffects,
+                                         frame: &FrameState) {
+    let content_bounds = match context.bounds() {
+        Some(content_bounds) => content_bounds,
+        None => return,
+    };
+
+    // Filters can pull in content from outside the visible area, so keep a margin around it.
+    // The outset is in layer pixels, so it grows with the transform of the group's layer.
+    let transform_scale = context.group_layer().map_or(1.0, |layer| {
+        transform_scale_factor(&layer.transform_state.borrow().final_transform)
+    });
+    let outset = filters_outset(&effects.filters) * transform_scale;
+    let mut visible_bounds = inflate_rect(&frame.viewport, outset);
+    if let Some(ref clip_polygon) = context.clip_polygon {
+        if clip_polygon.is_empty() {
+            return;
+        }
+        visible_bounds = match visible_bounds.intersection(&polygon_bounding_rect(clip_polygon)) {
+            Some(visible_bounds) => inflate_rect(&visible_bounds, outset),
+            None => return,
+        };
+    }
+
+    let mut bounds = match inflate_rect(&content_bounds, outset).intersection(&visible_bounds) {
+        Some(bounds) => bounds,
+        None => return,
+    };
+
+    // Nothing outside of the mask layer is visible.
+    if let Some(ref mask_layer) = context.mask_layer {
+        bounds = match mask_layer.transform_state.borrow().screen_rect {
+            Some(ref screen_rect) => match bounds.intersection(&screen_rect.rect) {
+                Some(bounds) => bounds,
+                None => return,
+            },
+            None => return,
+        };
+    }
+
+    renderer.begin_group(&bounds);
+    render_3d_context(renderer, context, frame);
+    if let Some(ref mask_layer) = context.mask_layer {
+        renderer.begin_mask();
+        render_mask_layer(renderer, mask_layer.clone());
+        renderer.end_mask();
+    }
+    renderer.end_group(effects);
+}
+
+/// Draws the background and tiles of a mask layer, which are used only for their alpha.
+fn render_mask_layer<T, R: Renderer + ?Sized>(renderer: &mut R, mask_layer: Rc<Layer<T>>) {
+    let ts = mask_layer.transform_state.borrow();
+    let background_color = *mask_layer.background_color.borrow();
+    let state = DrawState {
+        clip_rect: None,
+        clip_polygon: None,
+        rounded_clips: &[],
+        opacity: 1.0,
+        blend_mode: BlendMode::Normal,
+    };
+
+    renderer.prepare_layer(&mut *mask_layer.tile_grid());
+    if background_color.a > 0.0 {
+        renderer.draw_solid_quad(&SolidQuad {
+            rect: ts.world_rect,
+            transform: ts.final_transform,
+            color: background_color,
+            blend_mode: BlendMode::Normal,
+            clip_polygon: None,
+            rounded_clips: vec!(),
+        });
+    }
+
+    mask_layer.do_for_all_tiles(|tile: &Tile| {
+        render_tile(renderer, tile, &ts.world_rect.origin, &ts.final_transform, &state);
+    });
+}
+
+fn inflate_rect(rect: &Rect<f32>, amount: f32) -> Rect<f32> {
+    Rect::new(Point2D::new(rect.origin.x - amount, rect.origin.y - amount),
+              Size2D::new(rect.size.width + 2.0 * amount, rect.size.height + 2.0 * amount))
+}
+
+fn render_3d_context<T, R: Renderer + ?Sized>(renderer: &mut R,
+                                              context: &RenderContext3D<T>,
+                                              frame: &FrameState) {
+    if context.children.is_empty() {
+        return;
+    }
+
+    // TODO(gw): Potential optimization here if there are no
+    //           layer intersections to disable z-buffering and
+    //           avoid clear.
+    renderer.begin_3d_context();
+
+    // Render child layers with z-testing.
+    for step in &context.draw_order {
+        match *step {
+            DrawStep::Layer(index, ref fragment) => {
+                let child = &context.children[index];
+                let layer = child.layer.as_ref().unwrap();
+                let (clip_rect, clip_polygon) =
+                    match layer_clip(&**layer, context.clip_polygon.as_ref(), fragment.as_ref()) {
+                        LayerClip::Unclipped => (None, None),
+                        LayerClip::Rect(rect) => (Some(rect), None),
+                        LayerClip::Polygon(polygon) => {
+                            (Some(polygon_bounding_rect(&polygon)), Some(polygon))
+                        }
+                        LayerClip::Invisible => continue,
+                    };
+
+                let (opacity, blend_mode) = if child.is_group_layer {
+                    (1.0, BlendMode::Normal)
+                } else {
+                    (*layer.opacity.borrow(), *layer.blend_mode.borrow())
+                };
+                render_layer(renderer, layer.clone(), &DrawState {
+                    clip_rect: clip_rect,
+                    clip_polygon: clip_polygon.as_ref(),
+                    rounded_clips: &context.rounded_clips,
+                    opacity: opacity,
+                    blend_mode: blend_mode,
+                }, frame);
+            }
+            DrawStep::Context(index) => {
+                let child_context = context.children[index].context.as_ref().unwrap();
+                match child_context.effects {
+                    Some(ref effects) => render_group(renderer, child_context, effects, frame),
+                    None => render_3d_context(renderer, child_context, frame),
+                }
+            }
+        }
+    }
+}
+
+/// Composites the scene rooted at `root_layer` with the given backend.
+pub fn composite_scene<T, R: Renderer + ?Sized>(root_layer: Rc<Layer<T>>,
+                                                scene: &Scene<T>,
+                                                renderer: &mut R) {
+    let scale = scene.scale.get();
+    let viewport = scene.viewport.to_untyped();
+    renderer.begin_frame(&viewport, scale);
+
+    let frame = FrameState {
+        viewport: Rect::new(Point2D::zero(),
+                            Size2D::new(viewport.size.width / scale,
+                                        viewport.size.height / scale)),
+        scale: scale,
+        checkerboard_style: scene.checkerboard_style,
+        checkerboard_counters: Cell::new(CheckerboardCounters::new()),
+    };
+    let context = match scene.checkerboard_style {
+        Some(_) => RenderContext3D::new_with_checkerboard(root_layer),
+        None => RenderContext3D::new(root_layer),
+    };
+    render_3d_context(renderer, &context, &frame);
+    renderer.end_frame();
+    scene.set_checkerboard_counters(frame.checkerboard_counters.get());
+}
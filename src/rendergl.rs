@@ -8,18 +8,23 @@
 // except according to those terms.
 
 use color::Color;
-use layers::Layer;
+use filters::{blur_downscale_steps, ColorMatrix, Filter, gaussian_weights};
+use filters::{IDENTITY_COLOR_MATRIX, MAX_BLUR_RADIUS};
+use geometry::DevicePixel;
+use layers::{BlendMode, Layer};
+use renderer::{composite_scene, DebugLines, GroupEffects, Renderer, SolidQuad, TileQuad};
+use renderer::{MAX_ROUNDED_CLIPS, RoundedClip};
 use scene::Scene;
-use texturegl::Texture;
+use texturegl::{Texture, TexturePool};
 use texturegl::Flip::VerticalFlip;
 use texturegl::TextureTarget::{TextureTarget2D, TextureTargetRectangle};
-use tiling::Tile;
+use tiling::TileGrid;
+use util::{clip_convex_polygon_to_rect, project_rect_to_screen, rect_to_polygon};
 use platform::surface::NativeDisplay;
 
 use euclid::matrix::Matrix4;
-use euclid::Matrix2D;
 use euclid::point::Point2D;
-use euclid::rect::Rect;
+use euclid::rect::{Rect, TypedRect};
 use euclid::size::Size2D;
 use libc::c_int;
 use gleam::gl;
@@ -27,7 +32,6 @@ use gleam::gl::{GLenum, GLfloat, GLint, GLsizei, GLuint};
 use std::fmt;
 use std::mem;
 use std::rc::Rc;
-use std::cmp::Ordering;
 
 #[derive(Copy, Clone, Debug)]
 pub struct ColorVertex {
@@ -66,10 +70,6 @@ impl TextureVertex {
 const ORTHO_NEAR_PLANE: f32 = -1000000.0;
 const ORTHO_FAR_PLANE: f32 = 1000000.0;
 
-fn create_ortho(scene_size: &Size2D<f32>) -> Matrix4 {
-    Matrix4::ortho(0.0, scene_size.width, scene_size.height, 0.0, ORTHO_NEAR_PLANE, ORTHO_FAR_PLANE)
-}
-
 static TEXTURE_FRAGMENT_SHADER_SOURCE: &'static str = "
     #ifdef GL_ES
         precision mediump float;
@@ -80,7 +80,11 @@ static TEXTURE_FRAGMENT_SHADER_SOURCE: &'static str = "
     uniform float uOpacity;
 
     void main(void) {
-        vec4 lFragColor = uOpacity * samplerFunction(uSampler, vTextureCoord);
+        vec4 lFragColor = uOpacity * roundedClipCoverage() *
+                          samplerFunction(uSampler, vTextureCoord);
+    #ifdef BLEND_WITH_BACKDROP
+        lFragColor = blendWithBackdrop(lFragColor);
+    #endif
         gl_FragColor = lFragColor;
     }
 ";
@@ -92,7 +96,231 @@ static SOLID_COLOR_FRAGMENT_SHADER_SOURCE: &'static str = "
 
     uniform vec4 uColor;
     void main(void) {
-        gl_FragColor = uColor;
+        vec4 lFragColor = roundedClipCoverage() * uColor;
+    #ifdef BLEND_WITH_BACKDROP
+        lFragColor = blendWithBackdrop(lFragColor);
+    #endif
+        gl_FragColor = lFragColor;
+    }
+";
+
+// Prepended to a fragment shader to blend its output with a copy of the framebuffer, for blend
+// modes that can't be expressed with glBlendFunc. The values of uBlendMode are those of
+// `BlendMode`. See https://drafts.fxtf.org/compositing-1/#blending for the formulas.
+static BLEND_FRAGMENT_SHADER_SOURCE: &'static str = "
+    #define BLEND_WITH_BACKDROP
+
+    #ifdef GL_ES
+        precision mediump float;
+    #endif
+
+    uniform sampler2D uBackdrop;
+    uniform vec2 uBackdropOrigin;
+    uniform vec2 uBackdropSize;
+    uniform int uBlendMode;
+
+    float luminosity(vec3 c) {
+        return dot(c, vec3(0.3, 0.59, 0.11));
+    }
+
+    float saturation(vec3 c) {
+        return max(c.r, max(c.g, c.b)) - min(c.r, min(c.g, c.b));
+    }
+
+    vec3 clipColor(vec3 c) {
+        float l = luminosity(c);
+        float n = min(c.r, min(c.g, c.b));
+        float x = max(c.r, max(c.g, c.b));
+        if (n < 0.0) {
+            c = l + (c - l) * l / (l - n);
+        }
+        if (x > 1.0) {
+            c = l + (c - l) * (1.0 - l) / (x - l);
+        }
+        return c;
+    }
+
+    vec3 setLuminosity(vec3 c, float l) {
+        return clipColor(c + (l - luminosity(c)));
+    }
+
+    vec3 setSaturation(vec3 c, float s) {
+        float n = min(c.r, min(c.g, c.b));
+        float x = max(c.r, max(c.g, c.b));
+        if (x <= n) {
+            return vec3(0.0);
+        }
+        return (c - n) * s / (x - n);
+    }
+
+    vec3 multiply(vec3 cb, vec3 cs) {
+        return cb * cs;
+    }
+
+    vec3 screen(vec3 cb, vec3 cs) {
+        return cb + cs - cb * cs;
+    }
+
+    vec3 hardLight(vec3 cb, vec3 cs) {
+        return mix(multiply(cb, 2.0 * cs), screen(cb, 2.0 * cs - 1.0), step(0.5, cs));
+    }
+
+    float colorDodge(float cb, float cs) {
+        if (cb == 0.0) {
+            return 0.0;
+        }
+        if (cs >= 1.0) {
+            return 1.0;
+        }
+        return min(1.0, cb / (1.0 - cs));
+    }
+
+    float colorBurn(float cb, float cs) {
+        if (cb >= 1.0) {
+            return 1.0;
+        }
+        if (cs <= 0.0) {
+            return 0.0;
+        }
+        return 1.0 - min(1.0, (1.0 - cb) / cs);
+    }
+
+    float softLight(float cb, float cs) {
+        if (cs <= 0.5) {
+            return cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb);
+        }
+        float d = cb <= 0.25 ? ((16.0 * cb - 12.0) * cb + 4.0) * cb : sqrt(cb);
+        return cb + (2.0 * cs - 1.0) * (d - cb);
+    }
+
+    vec3 blendColors(vec3 cb, vec3 cs) {
+        if (uBlendMode == 1) {
+            return multiply(cb, cs);
+        } else if (uBlendMode == 2) {
+            return screen(cb, cs);
+        } else if (uBlendMode == 3) {
+            return hardLight(cs, cb);
+        } else if (uBlendMode == 4) {
+            return min(cb, cs);
+        } else if (uBlendMode == 5) {
+            return max(cb, cs);
+        } else if (uBlendMode == 6) {
+            return vec3(colorDodge(cb.r, cs.r), colorDodge(cb.g, cs.g), colorDodge(cb.b, cs.b));
+        } else if (uBlendMode == 7) {
+            return vec3(colorBurn(cb.r, cs.r), colorBurn(cb.g, cs.g), colorBurn(cb.b, cs.b));
+        } else if (uBlendMode == 8) {
+            return hardLight(cb, cs);
+        } else if (uBlendMode == 9) {
+            return vec3(softLight(cb.r, cs.r), softLight(cb.g, cs.g), softLight(cb.b, cs.b));
+        } else if (uBlendMode == 10) {
+            return abs(cb - cs);
+        } else if (uBlendMode == 11) {
+            return cb + cs - 2.0 * cb * cs;
+        } else if (uBlendMode == 12) {
+            return setLuminosity(setSaturation(cs, saturation(cb)), luminosity(cb));
+        } else if (uBlendMode == 13) {
+            return setLuminosity(setSaturation(cb, saturation(cs)), luminosity(cb));
+        } else if (uBlendMode == 14) {
+            return setLuminosity(cs, luminosity(cb));
+        } else if (uBlendMode == 15) {
+            return setLuminosity(cb, luminosity(cs));
+        }
+        return cs;
+    }
+
+    vec4 blendWithBackdrop(vec4 source) {
+        vec4 backdrop = texture2D(uBackdrop, (gl_FragCoord.xy - uBackdropOrigin) / uBackdropSize);
+        vec3 cs = source.a > 0.0 ? source.rgb / source.a : vec3(0.0);
+        vec3 cb = backdrop.a > 0.0 ? backdrop.rgb / backdrop.a : vec3(0.0);
+        vec3 color = source.rgb * (1.0 - backdrop.a) +
+                     backdrop.rgb * (1.0 - source.a) +
+                     source.a * backdrop.a * blendColors(cb, cs);
+        return vec4(color, source.a + backdrop.a - source.a * backdrop.a);
+    }
+";
+
+/// Anti-aliased clipping to rounded rectangles, included in the texture and solid color
+/// fragment shaders. `MAX_ROUNDED_CLIPS` is defined when the shader is built.
+static ROUNDED_CLIP_FRAGMENT_SHADER_SOURCE: &'static str = "
+    #ifdef GL_ES
+        precision mediump float;
+    #endif
+
+    uniform int uRoundedClipCount;
+
+    // Map window coordinates to homogeneous points on the plane of each clip.
+    uniform mat3 uRoundedClipTransforms[MAX_ROUNDED_CLIPS];
+
+    // The clip rectangles, as (min x, min y, max x, max y).
+    uniform vec4 uRoundedClipRects[MAX_ROUNDED_CLIPS];
+
+    // The radii of the top left and top right corners, then of the bottom right and bottom
+    // left corners.
+    uniform vec4 uRoundedClipRadiiTop[MAX_ROUNDED_CLIPS];
+    uniform vec4 uRoundedClipRadiiBottom[MAX_ROUNDED_CLIPS];
+
+    // Returns the distance to the corner ellipse if the point is in the corner region, or
+    // `distance` otherwise.
+    float cornerDistance(vec2 point, vec2 corner, vec2 radius, vec2 direction, float distance) {
+        if (radius.x <= 0.0 || radius.y <= 0.0) {
+            return distance;
+        }
+
+        vec2 position = (point - (corner + direction * radius)) / radius;
+        if (position.x * direction.x >= 0.0 || position.y * direction.y >= 0.0) {
+            return distance;
+        }
+
+        float gradientLength = length(position / radius);
+        if (gradientLength <= 0.0) {
+            return -min(radius.x, radius.y);
+        }
+        return (length(position) - 1.0) * length(position) / gradientLength;
+    }
+
+    float roundedRectDistance(vec2 point, vec4 rect, vec4 radiiTop, vec4 radiiBottom) {
+        float distance = max(max(rect.x - point.x, point.x - rect.z),
+                             max(rect.y - point.y, point.y - rect.w));
+        distance = cornerDistance(point, rect.xy, radiiTop.xy, vec2(1.0, 1.0), distance);
+        distance = cornerDistance(point, rect.zy, radiiTop.zw, vec2(-1.0, 1.0), distance);
+        distance = cornerDistance(point, rect.zw, radiiBottom.xy, vec2(-1.0, -1.0), distance);
+        distance = cornerDistance(point, rect.xw, radiiBottom.zw, vec2(1.0, -1.0), distance);
+        return distance;
+    }
+
+    float roundedClipCoverage() {
+        float coverage = 1.0;
+        for (int i = 0; i < MAX_ROUNDED_CLIPS; i++) {
+            if (i >= uRoundedClipCount) {
+                break;
+            }
+
+            vec3 point = uRoundedClipTransforms[i] * vec3(gl_FragCoord.xy, 1.0);
+            if (point.z <= 0.0) {
+                return 0.0;
+            }
+
+            // Measure the distance in pixels, using the size of a pixel on the plane.
+            vec3 right = uRoundedClipTransforms[i] * vec3(gl_FragCoord.xy + vec2(1.0, 0.0), 1.0);
+            vec3 up = uRoundedClipTransforms[i] * vec3(gl_FragCoord.xy + vec2(0.0, 1.0), 1.0);
+            float pixelSize = 0.0;
+            if (right.z > 0.0) {
+                pixelSize = max(pixelSize, length(right.xy / right.z - point.xy / point.z));
+            }
+            if (up.z > 0.0) {
+                pixelSize = max(pixelSize, length(up.xy / up.z - point.xy / point.z));
+            }
+            if (pixelSize <= 0.0) {
+                continue;
+            }
+
+            float distance = roundedRectDistance(point.xy / point.z,
+                                                 uRoundedClipRects[i],
+                                                 uRoundedClipRadiiTop[i],
+                                                 uRoundedClipRadiiBottom[i]);
+            coverage *= clamp(0.5 - distance / pixelSize, 0.0, 1.0);
+        }
+        return coverage;
     }
 ";
 
@@ -123,12 +351,125 @@ static SOLID_COLOR_VERTEX_SHADER_SOURCE: &'static str = "
     }
 ";
 
-static TILE_DEBUG_BORDER_COLOR: Color = Color { r: 0., g: 1., b: 1., a: 1.0 };
-static TILE_DEBUG_BORDER_THICKNESS: usize = 1;
-static LAYER_DEBUG_BORDER_COLOR: Color = Color { r: 1., g: 0.5, b: 0., a: 1.0 };
-static LAYER_DEBUG_BORDER_THICKNESS: usize = 2;
-static LAYER_AABB_DEBUG_BORDER_COLOR: Color = Color { r: 1., g: 0.0, b: 0., a: 1.0 };
-static LAYER_AABB_DEBUG_BORDER_THICKNESS: usize = 1;
+// Filter passes cover the whole destination surface with a unit square.
+static FILTER_VERTEX_SHADER_SOURCE: &'static str = "
+    attribute vec2 aVertexPosition;
+
+    varying vec2 vTextureCoord;
+
+    void main(void) {
+        gl_Position = vec4(aVertexPosition * 2.0 - 1.0, 0.0, 1.0);
+        vTextureCoord = aVertexPosition;
+    }
+";
+
+// The offsets are applied to unpremultiplied colors, like in `filters::apply_color_matrix`.
+static COLOR_MATRIX_FRAGMENT_SHADER_SOURCE: &'static str = "
+    #ifdef GL_ES
+        precision mediump float;
+    #endif
+
+    varying vec2 vTextureCoord;
+    uniform sampler2D uSampler;
+    uniform mat4 uMatrix;
+    uniform vec4 uOffset;
+
+    void main(void) {
+        vec4 color = texture2D(uSampler, vTextureCoord);
+        if (color.a <= 0.0) {
+            gl_FragColor = vec4(0.0);
+            return;
+        }
+        color = clamp(uMatrix * vec4(color.rgb / color.a, color.a) + uOffset, 0.0, 1.0);
+        gl_FragColor = vec4(color.rgb * color.a, color.a);
+    }
+";
+
+// One direction of a separable gaussian blur. uWeights holds half of the kernel, starting at
+// the center tap.
+static BLUR_FRAGMENT_SHADER_SOURCE: &'static str = "
+    #ifdef GL_ES
+        precision mediump float;
+    #endif
+
+    varying vec2 vTextureCoord;
+    uniform sampler2D uSampler;
+    uniform vec2 uStep;
+    uniform int uRadius;
+    uniform float uWeights[MAX_BLUR_RADIUS + 1];
+
+    bool inside(vec2 coordinate) {
+        return all(greaterThanEqual(coordinate, vec2(0.0))) &&
+               all(lessThanEqual(coordinate, vec2(1.0)));
+    }
+
+    vec4 fetch(vec2 coordinate) {
+        return inside(coordinate) ? texture2D(uSampler, coordinate) : vec4(0.0);
+    }
+
+    void main(void) {
+        vec4 color = fetch(vTextureCoord) * uWeights[0];
+        for (int i = 1; i <= MAX_BLUR_RADIUS; i++) {
+            if (i > uRadius) {
+                break;
+            }
+            vec2 offset = uStep * float(i);
+            color += (fetch(vTextureCoord + offset) + fetch(vTextureCoord - offset)) * uWeights[i];
+        }
+        gl_FragColor = color;
+    }
+";
+
+// The offset alpha channel of the source, filled with a premultiplied color.
+static DROP_SHADOW_FRAGMENT_SHADER_SOURCE: &'static str = "
+    #ifdef GL_ES
+        precision mediump float;
+    #endif
+
+    varying vec2 vTextureCoord;
+    uniform sampler2D uSampler;
+    uniform vec2 uOffset;
+    uniform vec4 uColor;
+
+    void main(void) {
+        vec2 coordinate = vTextureCoord + uOffset;
+        float alpha = 0.0;
+        if (all(greaterThanEqual(coordinate, vec2(0.0))) &&
+            all(lessThanEqual(coordinate, vec2(1.0)))) {
+            alpha = texture2D(uSampler, coordinate).a;
+        }
+        gl_FragColor = uColor * alpha;
+    }
+";
+
+// The source multiplied by the alpha channel of a mask of the same size.
+static MASK_FRAGMENT_SHADER_SOURCE: &'static str = "
+    #ifdef GL_ES
+        precision mediump float;
+    #endif
+
+    varying vec2 vTextureCoord;
+    uniform sampler2D uSampler;
+    uniform sampler2D uMask;
+
+    void main(void) {
+        gl_FragColor = texture2D(uSampler, vTextureCoord) * texture2D(uMask, vTextureCoord).a;
+    }
+";
+
+fn blend_shader_prefix(blend_with_backdrop: bool) -> &'static str {
+    if blend_with_backdrop {
+        BLEND_FRAGMENT_SHADER_SOURCE
+    } else {
+        ""
+    }
+}
+
+fn rounded_clip_shader_prefix() -> String {
+    fmt::format(format_args!("#define MAX_ROUNDED_CLIPS {}\n{}",
+                             MAX_ROUNDED_CLIPS,
+                             ROUNDED_CLIP_FRAGMENT_SHADER_SOURCE))
+}
 
 #[derive(Copy, Clone)]
 struct Buffers {
@@ -176,6 +517,170 @@ impl ShaderProgram {
     }
 }
 
+/// How a blend mode is implemented.
+enum BlendStrategy {
+    /// The blend mode can be expressed with `glBlendFunc`.
+    FixedFunction(GLenum, GLenum),
+
+    /// The framebuffer has to be copied and blended in the fragment shader.
+    Backdrop,
+}
+
+fn blend_strategy(blend_mode: BlendMode) -> BlendStrategy {
+    // Premultiplied screen is `src + dst - src * dst` for every channel, including alpha.
+    match blend_mode {
+        BlendMode::Normal => BlendStrategy::FixedFunction(gl::ONE, gl::ONE_MINUS_SRC_ALPHA),
+        BlendMode::Screen => BlendStrategy::FixedFunction(gl::ONE, gl::ONE_MINUS_SRC_COLOR),
+        _ => BlendStrategy::Backdrop,
+    }
+}
+
+/// A copy of part of the framebuffer, used as the backdrop for blend modes that need to read
+/// the destination.
+#[derive(Copy, Clone)]
+struct Backdrop {
+    texture: GLuint,
+
+    /// The size of the texture, which always matches the viewport.
+    size: Size2D<GLsizei>,
+
+    /// The window coordinates of the bottom left corner of the copied region.
+    origin: Point2D<GLint>,
+}
+
+#[derive(Copy, Clone)]
+struct BackdropUniforms {
+    sampler_uniform: c_int,
+    origin_uniform: c_int,
+    size_uniform: c_int,
+    blend_mode_uniform: c_int,
+}
+
+impl BackdropUniforms {
+    fn new(program: &ShaderProgram) -> BackdropUniforms {
+        BackdropUniforms {
+            sampler_uniform: program.get_uniform_location("uBackdrop"),
+            origin_uniform: program.get_uniform_location("uBackdropOrigin"),
+            size_uniform: program.get_uniform_location("uBackdropSize"),
+            blend_mode_uniform: program.get_uniform_location("uBlendMode"),
+        }
+    }
+
+    /// Binds the backdrop texture to texture unit 1.
+    fn bind(&self, backdrop: &Backdrop, blend_mode: BlendMode) {
+        gl::active_texture(gl::TEXTURE1);
+        gl::bind_texture(gl::TEXTURE_2D, backdrop.texture);
+        gl::active_texture(gl::TEXTURE0);
+
+        gl::uniform_1i(self.sampler_uniform, 1);
+        gl::uniform_2f(self.origin_uniform,
+                       backdrop.origin.x as GLfloat,
+                       backdrop.origin.y as GLfloat);
+        gl::uniform_2f(self.size_uniform,
+                       backdrop.size.width as GLfloat,
+                       backdrop.size.height as GLfloat);
+        gl::uniform_1i(self.blend_mode_uniform, blend_mode as GLint);
+    }
+
+    fn unbind(&self) {
+        gl::active_texture(gl::TEXTURE1);
+        gl::bind_texture(gl::TEXTURE_2D, 0);
+        gl::active_texture(gl::TEXTURE0);
+    }
+}
+
+/// The uniform values for the rounded clips of a draw, laid out as the shaders expect them.
+struct RoundedClipValues {
+    count: GLint,
+    transforms: Vec<GLfloat>,
+    rects: Vec<GLfloat>,
+    radii_top: Vec<GLfloat>,
+    radii_bottom: Vec<GLfloat>,
+}
+
+impl RoundedClipValues {
+    fn none() -> RoundedClipValues {
+        RoundedClipValues {
+            count: 0,
+            transforms: vec!(),
+            rects: vec!(),
+            radii_top: vec!(),
+            radii_bottom: vec!(),
+        }
+    }
+
+    /// `window_to_screen` maps window coordinates to unscaled screen coordinates, as a
+    /// row-major 3x3 matrix.
+    fn new(rounded_clips: &[RoundedClip], window_to_screen: &[f32; 9]) -> RoundedClipValues {
+        let mut values = RoundedClipValues::none();
+        for rounded_clip in rounded_clips.iter().take(MAX_ROUNDED_CLIPS) {
+            let m = multiply_3x3(&rounded_clip.screen_to_plane, window_to_screen);
+
+            // GL expects matrices in column-major order.
+            values.transforms.extend([m[0], m[3], m[6],
+                                      m[1], m[4], m[7],
+                                      m[2], m[5], m[8]].iter().cloned());
+
+            let rect = &rounded_clip.rect;
+            values.rects.extend([rect.min_x(), rect.min_y(),
+                                 rect.max_x(), rect.max_y()].iter().cloned());
+
+            let radii = &rounded_clip.radii;
+            values.radii_top.extend([radii.top_left.width, radii.top_left.height,
+                                     radii.top_right.width, radii.top_right.height]
+                                    .iter().cloned());
+            values.radii_bottom.extend([radii.bottom_right.width, radii.bottom_right.height,
+                                        radii.bottom_left.width, radii.bottom_left.height]
+                                       .iter().cloned());
+            values.count += 1;
+        }
+        values
+    }
+}
+
+fn multiply_3x3(a: &[f32; 9], b: &[f32; 9]) -> [f32; 9] {
+    let mut result = [0.0; 9];
+    for row in 0..3 {
+        for column in 0..3 {
+            result[row * 3 + column] = (0..3).map(|i| a[row * 3 + i] * b[i * 3 + column]).sum();
+        }
+    }
+    result
+}
+
+#[derive(Copy, Clone)]
+struct RoundedClipUniforms {
+    count_uniform: c_int,
+    transforms_uniform: c_int,
+    rects_uniform: c_int,
+    radii_top_uniform: c_int,
+    radii_bottom_uniform: c_int,
+}
+
+impl RoundedClipUniforms {
+    fn new(program: &ShaderProgram) -> RoundedClipUniforms {
+        RoundedClipUniforms {
+            count_uniform: program.get_uniform_location("uRoundedClipCount"),
+            transforms_uniform: program.get_uniform_location("uRoundedClipTransforms"),
+            rects_uniform: program.get_uniform_location("uRoundedClipRects"),
+            radii_top_uniform: program.get_uniform_location("uRoundedClipRadiiTop"),
+            radii_bottom_uniform: program.get_uniform_location("uRoundedClipRadiiBottom"),
+        }
+    }
+
+    fn bind(&self, values: &RoundedClipValues) {
+        gl::uniform_1i(self.count_uniform, values.count);
+        if values.count == 0 {
+            return;
+        }
+
+        gl::uniform_matrix_3fv(self.transforms_uniform, false, &values.transforms);
+        gl::uniform_4fv(self.rects_uniform, &values.rects);
+        gl::uniform_4fv(self.radii_top_uniform, &values.radii_top);
+        gl::uniform_4fv(self.radii_bottom_uniform, &values.radii_bottom);
+    }
+}
+
 #[derive(Copy, Clone)]
 struct TextureProgram {
     program: ShaderProgram,
@@ -186,14 +691,20 @@ struct TextureProgram {
     sampler_uniform: c_int,
     texture_space_transform_uniform: c_int,
     opacity_uniform: c_int,
+    backdrop_uniforms: BackdropUniforms,
+    rounded_clip_uniforms: RoundedClipUniforms,
 }
 
 impl TextureProgram {
-    fn new(sampler_function: &str, sampler_type: &str) -> TextureProgram {
+    fn new(sampler_function: &str, sampler_type: &str, blend_with_backdrop: bool)
+           -> TextureProgram {
         let fragment_shader_source
-             = fmt::format(format_args!("#define samplerFunction {}\n#define samplerType {}\n{}",
+             = fmt::format(format_args!("#define samplerFunction {}\n\
+                                         #define samplerType {}\n{}{}{}",
                                         sampler_function,
                                         sampler_type,
+                                        rounded_clip_shader_prefix(),
+                                        blend_shader_prefix(blend_with_backdrop),
                                         TEXTURE_FRAGMENT_SHADER_SOURCE));
         let program = ShaderProgram::new(TEXTURE_VERTEX_SHADER_SOURCE, &fragment_shader_source);
         TextureProgram {
@@ -205,11 +716,13 @@ impl TextureProgram {
             sampler_uniform: program.get_uniform_location("uSampler"),
             texture_space_transform_uniform: program.get_uniform_location("uTextureSpaceTransform"),
             opacity_uniform: program.get_uniform_location("uOpacity"),
+            backdrop_uniforms: BackdropUniforms::new(&program),
+            rounded_clip_uniforms: RoundedClipUniforms::new(&program),
         }
     }
 
     fn bind_uniforms_and_attributes(&self,
-                                    vertices: &[TextureVertex; 4],
+                                    vertices: &[TextureVertex],
                                     transform: &Matrix4,
                                     projection_matrix: &Matrix4,
                                     texture_space_transform: &Matrix4,
@@ -243,18 +756,19 @@ impl TextureProgram {
         gl::disable_vertex_attrib_array(self.vertex_position_attr as GLuint);
     }
 
-    fn create_2d_program() -> TextureProgram {
-        TextureProgram::new("texture2D", "sampler2D")
+    fn create_2d_program(blend_with_backdrop: bool) -> TextureProgram {
+        TextureProgram::new("texture2D", "sampler2D", blend_with_backdrop)
     }
 
     #[cfg(target_os="macos")]
-    fn create_rectangle_program_if_necessary() -> Option<TextureProgram> {
+    fn create_rectangle_program_if_necessary(blend_with_backdrop: bool)
+                                             -> Option<TextureProgram> {
         gl::enable(gl::TEXTURE_RECTANGLE_ARB);
-        Some(TextureProgram::new("texture2DRect", "sampler2DRect"))
+        Some(TextureProgram::new("texture2DRect", "sampler2DRect", blend_with_backdrop))
     }
 
     #[cfg(not(target_os="macos"))]
-    fn create_rectangle_program_if_necessary() -> Option<TextureProgram> {
+    fn create_rectangle_program_if_necessary(_: bool) -> Option<TextureProgram> {
         None
     }
 }
@@ -266,18 +780,27 @@ struct SolidColorProgram {
     modelview_uniform: c_int,
     projection_uniform: c_int,
     color_uniform: c_int,
+    backdrop_uniforms: BackdropUniforms,
+    rounded_clip_uniforms: RoundedClipUniforms,
 }
 
 impl SolidColorProgram {
-    fn new() -> SolidColorProgram {
+    fn new(blend_with_backdrop: bool) -> SolidColorProgram {
+        let fragment_shader_source
+             = fmt::format(format_args!("{}{}{}",
+                                        rounded_clip_shader_prefix(),
+                                        blend_shader_prefix(blend_with_backdrop),
+                                        SOLID_COLOR_FRAGMENT_SHADER_SOURCE));
         let program = ShaderProgram::new(SOLID_COLOR_VERTEX_SHADER_SOURCE,
-                                         SOLID_COLOR_FRAGMENT_SHADER_SOURCE);
+                                         &fragment_shader_source);
         SolidColorProgram {
             program: program,
             vertex_position_attr: program.get_attribute_location("aVertexPosition"),
             modelview_uniform: program.get_uniform_location("uMVMatrix"),
             projection_uniform: program.get_uniform_location("uPMatrix"),
             color_uniform: program.get_uniform_location("uColor"),
+            backdrop_uniforms: BackdropUniforms::new(&program),
+            rounded_clip_uniforms: RoundedClipUniforms::new(&program),
         }
     }
 
@@ -308,7 +831,7 @@ impl SolidColorProgram {
     }
 
     fn bind_uniforms_and_attributes_for_quad(&self,
-                                             vertices: &[ColorVertex; 4],
+                                             vertices: &[ColorVertex],
                                              transform: &Matrix4,
                                              projection_matrix: &Matrix4,
                                              buffers: &Buffers,
@@ -329,136 +852,29 @@ impl SolidColorProgram {
     }
 }
 
-struct RenderContextChild<T> {
-    layer: Option<Rc<Layer<T>>>,
-    context: Option<RenderContext3D<T>>,
-    paint_order: usize,
-    z_center: f32,
-}
-
-pub struct RenderContext3D<T>{
-    children: Vec<RenderContextChild<T>>,
-    clip_rect: Option<Rect<f32>>,
-}
-
-impl<T> RenderContext3D<T> {
-    fn new(layer: Rc<Layer<T>>) -> RenderContext3D<T> {
-        let mut render_context = RenderContext3D {
-            children: vec!(),
-            clip_rect: RenderContext3D::calculate_context_clip(layer.clone(), None),
-        };
-        layer.build(&mut render_context);
-        render_context.sort_children();
-        render_context
-    }
-
-    fn build_child(layer: Rc<Layer<T>>,
-                   parent_clip_rect: Option<Rect<f32>>)
-                   -> Option<RenderContext3D<T>> {
-        let clip_rect = RenderContext3D::calculate_context_clip(layer.clone(), parent_clip_rect);
-        if let Some(ref clip_rect) = clip_rect {
-            if clip_rect.is_empty() {
-                return None;
-            }
-        }
-
-        let mut render_context = RenderContext3D {
-            children: vec!(),
-            clip_rect: clip_rect,
-        };
-
-        for child in layer.children().iter() {
-            child.build(&mut render_context);
-        }
-
-        render_context.sort_children();
-        Some(render_context)
-    }
-
-    fn sort_children(&mut self) {
-        // TODO(gw): This is basically what FF does, which breaks badly
-        // when there are intersecting polygons. Need to split polygons
-        // to handle this case correctly (Blink uses a BSP tree).
-        self.children.sort_by(|a, b| {
-            if a.z_center < b.z_center {
-                Ordering::Less
-            } else if a.z_center > b.z_center {
-                Ordering::Greater
-            } else if a.paint_order < b.paint_order {
-                Ordering::Less
-            } else if a.paint_order > b.paint_order {
-                Ordering::Greater
-            } else {
-                Ordering::Equal
-            }
-        });
-    }
-
-    fn calculate_context_clip(layer: Rc<Layer<T>>,
-                              parent_clip_rect: Option<Rect<f32>>)
-                              -> Option<Rect<f32>> {
-        // TODO(gw): This doesn't work for iframes that are transformed.
-        if !*layer.masks_to_bounds.borrow() {
-            return parent_clip_rect;
-        }
-
-        let layer_clip = match layer.transform_state.borrow().screen_rect.as_ref() {
-            Some(screen_rect) => screen_rect.rect,
-            None => return Some(Rect::zero()), // Layer is entirely clipped away.
-        };
-
-        match parent_clip_rect {
-            Some(parent_clip_rect) => match layer_clip.intersection(&parent_clip_rect) {
-                Some(intersected_clip) => Some(intersected_clip),
-                None => Some(Rect::zero()), // No intersection.
-            },
-            None => Some(layer_clip),
-        }
-    }
-
-    fn add_child(&mut self,
-                 layer: Option<Rc<Layer<T>>>,
-                 child_context: Option<RenderContext3D<T>>,
-                 z_center: f32) {
-        let paint_order = self.children.len();
-        self.children.push(RenderContextChild {
-            layer: layer,
-            context: child_context,
-            z_center: z_center,
-            paint_order: paint_order,
-        });
-    }
-}
-
-pub trait RenderContext3DBuilder<T> {
-    fn build(&self, current_context: &mut RenderContext3D<T>);
+#[derive(Copy, Clone)]
+struct FilterPrograms {
+    color_matrix_program: ShaderProgram,
+    blur_program: ShaderProgram,
+    drop_shadow_program: ShaderProgram,
+    mask_program: ShaderProgram,
 }
 
-impl<T> RenderContext3DBuilder<T> for Rc<Layer<T>> {
-    fn build(&self, current_context: &mut RenderContext3D<T>) {
-        let (layer, z_center) = match self.transform_state.borrow().screen_rect {
-            Some(ref rect) => (Some(self.clone()), rect.z_center),
-            None => (None, 0.), // Layer is entirely clipped.
-        };
-
-        if !self.children.borrow().is_empty() && self.establishes_3d_context {
-            let child_context =
-                RenderContext3D::build_child(self.clone(), current_context.clip_rect);
-            if child_context.is_some() {
-                current_context.add_child(layer, child_context, z_center);
-                return;
-            }
-        };
-
-        // If we are completely clipped out, don't add anything to this context.
-        if layer.is_none() {
-            return;
-        }
-
-        current_context.add_child(layer, None, z_center);
-
-        for child in self.children().iter() {
-            child.build(current_context);
+impl FilterPrograms {
+    fn new() -> FilterPrograms {
+        let blur_fragment_shader_source =
+            fmt::format(format_args!("#define MAX_BLUR_RADIUS {}\n{}",
+                                     MAX_BLUR_RADIUS,
+                                     BLUR_FRAGMENT_SHADER_SOURCE));
+        FilterPrograms {
+            color_matrix_program: ShaderProgram::new(FILTER_VERTEX_SHADER_SOURCE,
+                                                     COLOR_MATRIX_FRAGMENT_SHADER_SOURCE),
+            blur_program: ShaderProgram::new(FILTER_VERTEX_SHADER_SOURCE,
+                                             &blur_fragment_shader_source),
+            drop_shadow_program: ShaderProgram::new(FILTER_VERTEX_SHADER_SOURCE,
+                                                    DROP_SHADOW_FRAGMENT_SHADER_SOURCE),
+            mask_program: ShaderProgram::new(FILTER_VERTEX_SHADER_SOURCE,
+                                             MASK_FRAGMENT_SHADER_SOURCE),
         }
     }
 }
@@ -468,6 +884,13 @@ pub struct RenderContext {
     texture_2d_program: TextureProgram,
     texture_rectangle_program: Option<TextureProgram>,
     solid_color_program: SolidColorProgram,
+
+    /// Variants of the programs above that blend with a copy of the framebuffer.
+    texture_2d_blend_program: TextureProgram,
+    texture_rectangle_blend_program: Option<TextureProgram>,
+    solid_color_blend_program: SolidColorProgram,
+
+    filter_programs: FilterPrograms,
     buffers: Buffers,
 
     /// The platform-specific graphics context.
@@ -493,14 +916,20 @@ impl RenderContext {
         gl::enable(gl::BLEND);
         gl::blend_func(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
 
-        let texture_2d_program = TextureProgram::create_2d_program();
-        let solid_color_program = SolidColorProgram::new();
-        let texture_rectangle_program = TextureProgram::create_rectangle_program_if_necessary();
+        let texture_2d_program = TextureProgram::create_2d_program(false);
+        let solid_color_program = SolidColorProgram::new(false);
+        let texture_rectangle_program =
+            TextureProgram::create_rectangle_program_if_necessary(false);
 
         RenderContext {
             texture_2d_program: texture_2d_program,
             texture_rectangle_program: texture_rectangle_program,
             solid_color_program: solid_color_program,
+            texture_2d_blend_program: TextureProgram::create_2d_program(true),
+            texture_rectangle_blend_program:
+                TextureProgram::create_rectangle_program_if_necessary(true),
+            solid_color_blend_program: SolidColorProgram::new(true),
+            filter_programs: FilterPrograms::new(),
             buffers: RenderContext::init_buffers(),
             compositing_display: compositing_display,
             show_debug_borders: show_debug_borders,
@@ -521,32 +950,58 @@ impl RenderContext {
         }
     }
 
+    /// Draws a convex polygon filled with a solid color. The vertices are drawn as a
+    /// triangle fan. If a backdrop is given, the color is blended with it in the shader.
     fn bind_and_render_solid_quad(&self,
-                                  vertices: &[ColorVertex; 4],
+                                  vertices: &[ColorVertex],
                                   transform: &Matrix4,
                                   projection: &Matrix4,
-                                  color: &Color) {
-        self.solid_color_program.enable_attribute_arrays();
-        gl::use_program(self.solid_color_program.program.id);
-        self.solid_color_program.bind_uniforms_and_attributes_for_quad(vertices,
-                                                                       transform,
-                                                                       projection,
-                                                                       &self.buffers,
-                                                                       color);
-        gl::draw_arrays(gl::TRIANGLE_STRIP, 0, 4);
-        self.solid_color_program.disable_attribute_arrays();
+                                  color: &Color,
+                                  blend_mode: BlendMode,
+                                  backdrop: Option<&Backdrop>,
+                                  rounded_clips: &RoundedClipValues) {
+        let program = match backdrop {
+            Some(_) => self.solid_color_blend_program,
+            None => self.solid_color_program,
+        };
+        program.enable_attribute_arrays();
+        gl::use_program(program.program.id);
+        program.bind_uniforms_and_attributes_for_quad(vertices,
+                                                      transform,
+                                                      projection,
+                                                      &self.buffers,
+                                                      color);
+        program.rounded_clip_uniforms.bind(rounded_clips);
+        if let Some(backdrop) = backdrop {
+            program.backdrop_uniforms.bind(backdrop, blend_mode);
+        }
+        gl::draw_arrays(gl::TRIANGLE_FAN, 0, vertices.len() as GLsizei);
+        if backdrop.is_some() {
+            program.backdrop_uniforms.unbind();
+        }
+        program.disable_attribute_arrays();
     }
 
+    /// Draws a convex textured polygon. The vertices are drawn as a triangle fan. If a
+    /// backdrop is given, the texture is blended with it in the shader.
     fn bind_and_render_quad(&self,
-                            vertices: &[TextureVertex; 4],
+                            vertices: &[TextureVertex],
                             texture: &Texture,
                             transform: &Matrix4,
                             projection_matrix: &Matrix4,
-                            opacity: f32) {
+                            opacity: f32,
+                            blend_mode: BlendMode,
+                            backdrop: Option<&Backdrop>,
+                            rounded_clips: &RoundedClipValues) {
+        let (texture_2d_program, texture_rectangle_program) = match backdrop {
+            Some(_) => (self.texture_2d_blend_program, self.texture_rectangle_blend_program),
+            None => (self.texture_2d_program, self.texture_rectangle_program),
+        };
+
         let mut texture_coordinates_need_to_be_scaled_by_size = false;
         let program = match texture.target {
-            TextureTarget2D => self.texture_2d_program,
-            TextureTargetRectangle(..) => match self.texture_rectangle_program {
+            TextureTarget2D => texture_2d_program,
+            TextureTargetRectangle(..) => match texture_rectangle_program {
                 Some(program) => {
                     texture_coordinates_need_to_be_scaled_by_size = true;
                     program
@@ -590,10 +1045,17 @@ impl RenderContext {
                                              &texture_transform,
                                              &self.buffers,
                                              opacity);
+        program.rounded_clip_uniforms.bind(rounded_clips);
+        if let Some(backdrop) = backdrop {
+            program.backdrop_uniforms.bind(backdrop, blend_mode);
+        }
 
         // Draw!
-        gl::draw_arrays(gl::TRIANGLE_STRIP, 0, 4);
+        gl::draw_arrays(gl::TRIANGLE_FAN, 0, vertices.len() as GLsizei);
         gl::bind_texture(gl::TEXTURE_2D, 0);
+        if backdrop.is_some() {
+            program.backdrop_uniforms.unbind();
+        }
 
         gl::bind_texture(texture.target.as_gl_target(), 0);
         program.disable_attribute_arrays()
@@ -612,233 +1074,699 @@ impl RenderContext {
                                                                         projection,
                                                                         &self.buffers,
                                                                         color);
+        self.solid_color_program.rounded_clip_uniforms.bind(&RoundedClipValues::none());
         gl::line_width(line_thickness as GLfloat);
         gl::draw_arrays(gl::LINE_STRIP, 0, 5);
         self.solid_color_program.disable_attribute_arrays();
     }
 
-    fn render_layer<T>(&self,
-                       layer: Rc<Layer<T>>,
-                       transform: &Matrix4,
-                       projection: &Matrix4,
-                       clip_rect: Option<Rect<f32>>,
-                       gfx_context: &NativeDisplay) {
-        let ts = layer.transform_state.borrow();
-        let transform = transform.mul(&ts.final_transform);
-        let background_color = *layer.background_color.borrow();
+    /// Covers the whole of the bound surface with a filter pass. `bind_uniforms` is called
+    /// once the program is in use, with the source texture bound to texture unit 0.
+    fn bind_and_render_filter_pass<F>(&self,
+                                      program: &ShaderProgram,
+                                      source: &Texture,
+                                      bind_uniforms: F)
+                                      where F: FnOnce() {
+        let vertex_position_attr = program.get_attribute_location("aVertexPosition");
+        gl::use_program(program.id);
+        gl::enable_vertex_attrib_array(vertex_position_attr as GLuint);
 
-        // Create native textures for this layer
-        layer.create_textures(gfx_context);
+        gl::active_texture(gl::TEXTURE0);
+        gl::bind_texture(gl::TEXTURE_2D, source.native_texture());
+        gl::uniform_1i(program.get_uniform_location("uSampler"), 0);
+        bind_uniforms();
+
+        let vertices = [
+            ColorVertex::new(Point2D::new(0.0, 0.0)),
+            ColorVertex::new(Point2D::new(1.0, 0.0)),
+            ColorVertex::new(Point2D::new(1.0, 1.0)),
+            ColorVertex::new(Point2D::new(0.0, 1.0)),
+        ];
+        gl::bind_buffer(gl::ARRAY_BUFFER, self.buffers.quad_vertex_buffer);
+        gl::buffer_data(gl::ARRAY_BUFFER, &vertices, gl::DYNAMIC_DRAW);
+        gl::vertex_attrib_pointer_f32(vertex_position_attr as GLuint, 2, false, 0, 0);
+        gl::draw_arrays(gl::TRIANGLE_FAN, 0, 4);
+
+        gl::bind_texture(gl::TEXTURE_2D, 0);
+        gl::disable_vertex_attrib_array(vertex_position_attr as GLuint);
+    }
+}
 
-        let layer_rect = clip_rect.map_or(ts.world_rect, |clip_rect| {
-            match clip_rect.intersection(&ts.world_rect) {
-                Some(layer_rect) => layer_rect,
-                None => Rect::zero(),
+/// An offscreen surface that can be drawn into and then sampled from.
+struct Surface {
+    framebuffer: GLuint,
+    texture: Texture,
+
+    /// The depth buffer, or 0 if the surface has none.
+    depth_buffer: GLuint,
+
+    size: Size2D<GLsizei>,
+}
+
+impl Surface {
+    fn new(size: Size2D<GLsizei>, with_depth_buffer: bool) -> Surface {
+        let mut texture = Texture::new(TextureTarget2D,
+                                       Size2D::new(size.width as usize, size.height as usize));
+        // Rows of a framebuffer are stored from the bottom up.
+        texture.flip = VerticalFlip;
+        {
+            let _bound_texture = texture.bind();
+            gl::tex_image_2d(gl::TEXTURE_2D, 0, gl::RGBA as GLint, size.width, size.height, 0,
+                             gl::RGBA, gl::UNSIGNED_BYTE, None);
+        }
+
+        let framebuffer = gl::gen_framebuffers(1)[0];
+        gl::bind_framebuffer(gl::FRAMEBUFFER, framebuffer);
+        gl::framebuffer_texture_2d(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D,
+                                   texture.native_texture(), 0);
+
+        let depth_buffer = if with_depth_buffer {
+            let depth_buffer = gl::gen_renderbuffers(1)[0];
+            gl::bind_renderbuffer(gl::RENDERBUFFER, depth_buffer);
+            gl::renderbuffer_storage(gl::RENDERBUFFER, gl::DEPTH_COMPONENT16,
+                                     size.width, size.height);
+            gl::bind_renderbuffer(gl::RENDERBUFFER, 0);
+            gl::framebuffer_renderbuffer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT,
+                                         gl::RENDERBUFFER, depth_buffer);
+            depth_buffer
+        } else {
+            0
+        };
+
+        Surface {
+            framebuffer: framebuffer,
+            texture: texture,
+            depth_buffer: depth_buffer,
+            size: size,
+        }
+    }
+
+    /// Makes this surface the destination of drawing, and clears it to transparent.
+    fn bind_and_clear(&self) {
+        gl::disable(gl::SCISSOR_TEST);
+        gl::bind_framebuffer(gl::FRAMEBUFFER, self.framebuffer);
+        gl::viewport(0, 0, self.size.width, self.size.height);
+        gl::clear_color(0.0, 0.0, 0.0, 0.0);
+        gl::clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+    }
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        gl::delete_framebuffers(&[self.framebuffer]);
+        if self.depth_buffer != 0 {
+            gl::delete_renderbuffers(&[self.depth_buffer]);
+        }
+    }
+}
+
+/// What is being composited into: the window, or the offscreen surface of a group.
+struct RenderTarget {
+    /// The offscreen surface, or `None` for the window.
+    surface: Option<Surface>,
+
+    /// The area covered by this target, in device pixels relative to the viewport.
+    device_rect: Rect<f32>,
+
+    /// The window coordinates of the bottom left corner of this target.
+    window_origin: Point2D<GLint>,
+
+    /// The framebuffer copy for this target, allocated the first time a layer needs it.
+    backdrop: Option<Backdrop>,
+
+    /// The mask of a group, once it has been drawn.
+    mask: Option<Surface>,
+
+    /// If set, drawing is restricted to this part of the target, in device pixels relative to
+    /// the viewport.
+    scissor_rect: Option<Rect<f32>>,
+}
+
+impl RenderTarget {
+    fn bind(&self) {
+        let framebuffer = self.surface.as_ref().map_or(0, |surface| surface.framebuffer);
+        gl::bind_framebuffer(gl::FRAMEBUFFER, framebuffer);
+        gl::viewport(self.window_origin.x, self.window_origin.y,
+                     self.device_rect.size.width as GLsizei,
+                     self.device_rect.size.height as GLsizei);
+
+        match self.scissor_rect {
+            Some(ref rect) => {
+                // Window coordinates run from the bottom up.
+                let x = (rect.min_x() - self.device_rect.min_x()) as GLint;
+                let y = (self.device_rect.max_y() - rect.max_y()) as GLint;
+                gl::enable(gl::SCISSOR_TEST);
+                gl::scissor(self.window_origin.x + x,
+                            self.window_origin.y + y,
+                            rect.size.width as GLsizei,
+                            rect.size.height as GLsizei);
             }
+            None => gl::disable(gl::SCISSOR_TEST),
+        }
+    }
+
+    fn projection(&self) -> Matrix4 {
+        let rect = &self.device_rect;
+        Matrix4::ortho(rect.min_x(), rect.max_x(), rect.max_y(), rect.min_y(),
+                       ORTHO_NEAR_PLANE, ORTHO_FAR_PLANE)
+    }
+}
+
+impl Drop for RenderTarget {
+    fn drop(&mut self) {
+        if let Some(backdrop) = self.backdrop.take() {
+            gl::delete_textures(&[backdrop.texture]);
+        }
+    }
+}
+
+/// Draws the items of a single frame.
+struct GLRenderer {
+    context: RenderContext,
+
+    /// The scene scale as a matrix.
+    scale_transform: Matrix4,
+    scale: f32,
+
+    /// The window, followed by the surfaces of the groups being drawn.
+    targets: Vec<RenderTarget>,
+
+    /// The part of the viewport that is redrawn, in device pixels relative to the viewport.
+    damage_rect: Rect<f32>,
+
+    /// Textures that tiles no longer use, kept for new tiles.
+    texture_pool: TexturePool,
+}
+
+impl GLRenderer {
+    fn target(&self) -> &RenderTarget {
+        self.targets.last().unwrap()
+    }
+
+    /// Starts drawing into a new, transparent surface covering `device_rect`.
+    fn push_offscreen_target(&mut self, device_rect: Rect<f32>) {
+        let surface = Surface::new(Size2D::new(device_rect.size.width as GLsizei,
+                                               device_rect.size.height as GLsizei),
+                                   true);
+        surface.bind_and_clear();
+        self.targets.push(RenderTarget {
+            surface: Some(surface),
+            device_rect: device_rect,
+            window_origin: Point2D::zero(),
+            backdrop: None,
+            mask: None,
+            scissor_rect: None,
         });
+    }
 
-        if layer_rect.is_empty() {
-            return;
+    /// Returns the area covered by `rect` transformed by `transform`, in device pixels.
+    fn device_rect(&self, rect: &Rect<f32>, transform: &Matrix4) -> Option<Rect<f32>> {
+        project_rect_to_screen(rect, transform).map(|screen_rect| {
+            let rect = screen_rect.rect;
+            Rect::new(Point2D::new(rect.origin.x * self.scale, rect.origin.y * self.scale),
+                      Size2D::new(rect.size.width * self.scale, rect.size.height * self.scale))
+        })
+    }
+
+    /// Returns the uniform values for drawing into the current target with the given rounded
+    /// clips.
+    fn rounded_clip_values(&self, rounded_clips: &[RoundedClip]) -> RoundedClipValues {
+        if rounded_clips.is_empty() {
+            return RoundedClipValues::none();
         }
 
-        if background_color.a != 0.0 {
-            let bg_vertices = [
-                ColorVertex::new(layer_rect.origin),
-                ColorVertex::new(layer_rect.top_right()),
-                ColorVertex::new(layer_rect.bottom_left()),
-                ColorVertex::new(layer_rect.bottom_right()),
-            ];
+        // Window coordinates have their origin at the bottom left of the target's viewport.
+        let target = self.target();
+        let scale = self.scale;
+        let origin = target.device_rect.origin;
+        let window_origin = target.window_origin;
+        let window_to_screen = [
+            1.0 / scale, 0.0, (origin.x - window_origin.x as f32) / scale,
+            0.0, -1.0 / scale,
+            (origin.y + target.device_rect.size.height + window_origin.y as f32) / scale,
+            0.0, 0.0, 1.0,
+        ];
+        RoundedClipValues::new(rounded_clips, &window_to_screen)
+    }
 
-            self.bind_and_render_solid_quad(&bg_vertices,
-                                            &transform,
-                                            &projection,
-                                            &background_color);
+    /// Returns the backdrop that a draw with the given blend mode should read from, if any.
+    fn backdrop_for_blend_mode(&self, blend_mode: BlendMode) -> Option<&Backdrop> {
+        match blend_strategy(blend_mode) {
+            BlendStrategy::FixedFunction(..) => None,
+            BlendStrategy::Backdrop => self.target().backdrop.as_ref(),
         }
+    }
 
-        layer.do_for_all_tiles(|tile: &Tile| {
-           self.render_tile(tile,
-                            &ts.world_rect.origin,
-                            &transform,
-                            projection,
-                            clip_rect,
-                            *layer.opacity.borrow());
-        });
+    /// Sets up blending for drawing into `device_rect` with the given blend mode. For modes
+    /// that read the destination, the part of the current target that the rect covers is
+    /// copied into the backdrop texture. Returns false if nothing would be drawn.
+    fn begin_blend(&mut self, blend_mode: BlendMode, device_rect: Option<Rect<f32>>) -> bool {
+        match blend_strategy(blend_mode) {
+            BlendStrategy::FixedFunction(source_factor, destination_factor) => {
+                gl::blend_func(source_factor, destination_factor);
+                return true;
+            }
+            BlendStrategy::Backdrop => {}
+        }
 
-        if self.show_debug_borders {
-            let debug_vertices = [
-                ColorVertex::new(layer_rect.origin),
-                ColorVertex::new(layer_rect.top_right()),
-                ColorVertex::new(layer_rect.bottom_right()),
-                ColorVertex::new(layer_rect.bottom_left()),
-                ColorVertex::new(layer_rect.origin),
-            ];
-            self.bind_and_render_quad_lines(&debug_vertices,
-                                            &transform,
-                                            projection,
-                                            &LAYER_DEBUG_BORDER_COLOR,
-                                            LAYER_DEBUG_BORDER_THICKNESS);
-
-            let aabb = ts.screen_rect.as_ref().unwrap().rect;
-            let debug_vertices = [
-                ColorVertex::new(aabb.origin),
-                ColorVertex::new(aabb.top_right()),
-                ColorVertex::new(aabb.bottom_right()),
-                ColorVertex::new(aabb.bottom_left()),
-                ColorVertex::new(aabb.origin),
-            ];
-            self.bind_and_render_quad_lines(&debug_vertices,
-                                            &Matrix4::identity(),
-                                            projection,
-                                            &LAYER_AABB_DEBUG_BORDER_COLOR,
-                                            LAYER_AABB_DEBUG_BORDER_THICKNESS);
-        }
-    }
-
-    fn render_tile(&self,
-                   tile: &Tile,
-                   layer_origin: &Point2D<f32>,
-                   transform: &Matrix4,
-                   projection: &Matrix4,
-                   clip_rect: Option<Rect<f32>>,
-                   opacity: f32) {
-        if tile.texture.is_zero() || !tile.bounds.is_some() {
-            return;
+        let target = self.targets.last_mut().unwrap();
+        let device_rect = match device_rect {
+            Some(device_rect) => match device_rect.intersection(&target.device_rect) {
+                Some(device_rect) => device_rect,
+                None => return false,
+            },
+            None => return false,
+        };
+
+        // Work in pixels relative to the target.
+        let target_origin = target.device_rect.origin;
+        let x0 = (device_rect.min_x() - target_origin.x).floor() as GLint;
+        let y0 = (device_rect.min_y() - target_origin.y).floor() as GLint;
+        let x1 = (device_rect.max_x() - target_origin.x).ceil() as GLint;
+        let y1 = (device_rect.max_y() - target_origin.y).ceil() as GLint;
+        if x1 <= x0 || y1 <= y0 {
+            return false;
         }
 
-        let tile_rect = tile.bounds.unwrap().to_untyped().translate(layer_origin);
-        let clipped_tile_rect = clip_rect.map_or(tile_rect, |clip_rect| {
-            match clip_rect.intersection(&tile_rect) {
-                Some(clipped_tile_rect) => clipped_tile_rect,
-                None => Rect::zero(),
+        let mut backdrop = match target.backdrop {
+            Some(backdrop) => backdrop,
+            None => create_backdrop(&target.device_rect.size),
+        };
+
+        // Window coordinates have their origin at the bottom left of the target.
+        backdrop.origin = Point2D::new(target.window_origin.x + x0,
+                                       target.window_origin.y +
+                                       target.device_rect.size.height as GLint - y1);
+        gl::bind_texture(gl::TEXTURE_2D, backdrop.texture);
+        gl::copy_tex_sub_image_2d(gl::TEXTURE_2D, 0, 0, 0,
+                                  backdrop.origin.x, backdrop.origin.y,
+                                  x1 - x0, y1 - y0);
+        gl::bind_texture(gl::TEXTURE_2D, 0);
+        target.backdrop = Some(backdrop);
+
+        // The shader produces the final color itself.
+        gl::disable(gl::BLEND);
+        true
+    }
+
+    fn end_blend(&mut self, blend_mode: BlendMode) {
+        match blend_strategy(blend_mode) {
+            BlendStrategy::FixedFunction(..) => {}
+            BlendStrategy::Backdrop => gl::enable(gl::BLEND),
+        }
+        gl::blend_func(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+    }
+
+    /// Runs a filter on the contents of `source`, returning a surface with the result.
+    fn apply_filter(&self, source: Surface, filter: &Filter) -> Surface {
+        if let Some(matrix) = filter.color_matrix() {
+            return self.apply_color_matrix(&source, &matrix, false);
+        }
+
+        match *filter {
+            Filter::Blur(std_deviation) => self.apply_blur(source, std_deviation * self.scale),
+            Filter::DropShadow(ref offset, std_deviation, ref color) => {
+                let programs = &self.context.filter_programs;
+                let shadow = Surface::new(source.size, false);
+                shadow.bind_and_clear();
+                gl::disable(gl::BLEND);
+
+                // Texture coordinates run from the bottom up.
+                let texture_offset =
+                    Point2D::new(-offset.x * self.scale / source.size.width as f32,
+                                 offset.y * self.scale / source.size.height as f32);
+                let program = &programs.drop_shadow_program;
+                self.context.bind_and_render_filter_pass(program, &source.texture, || {
+                    gl::uniform_2f(program.get_uniform_location("uOffset"),
+                                   texture_offset.x,
+                                   texture_offset.y);
+                    gl::uniform_4f(program.get_uniform_location("uColor"),
+                                   color.r * color.a,
+                                   color.g * color.a,
+                                   color.b * color.a,
+                                   color.a);
+                });
+                gl::enable(gl::BLEND);
+
+                // Draw the content over its blurred shadow.
+                let shadow = self.apply_blur(shadow, std_deviation * self.scale);
+                self.apply_color_matrix_into(&source, &shadow, &IDENTITY_COLOR_MATRIX, true);
+                shadow
             }
+            _ => source,
+        }
+    }
+
+    fn apply_color_matrix(&self, source: &Surface, matrix: &ColorMatrix, blend: bool) -> Surface {
+        let destination = Surface::new(source.size, false);
+        destination.bind_and_clear();
+        self.apply_color_matrix_into(source, &destination, matrix, blend);
+        destination
+    }
+
+    /// Draws `source` into the bound `destination` through a color matrix, either replacing
+    /// its contents or blending over them.
+    fn apply_color_matrix_into(&self,
+                               source: &Surface,
+                               destination: &Surface,
+                               matrix: &ColorMatrix,
+                               blend: bool) {
+        gl::bind_framebuffer(gl::FRAMEBUFFER, destination.framebuffer);
+        if !blend {
+            gl::disable(gl::BLEND);
+        }
+
+        // GLSL matrices are column-major.
+        let mut columns = [0.0; 16];
+        for row in 0..4 {
+            for column in 0..4 {
+                columns[column * 4 + row] = matrix[row * 5 + column];
+            }
+        }
+
+        let program = &self.context.filter_programs.color_matrix_program;
+        self.context.bind_and_render_filter_pass(program, &source.texture, || {
+            gl::uniform_matrix_4fv(program.get_uniform_location("uMatrix"), false, &columns);
+            gl::uniform_4f(program.get_uniform_location("uOffset"),
+                           matrix[4], matrix[9], matrix[14], matrix[19]);
         });
 
-        if clipped_tile_rect.is_empty() {
-           return;
+        if !blend {
+            gl::enable(gl::BLEND);
         }
+    }
 
-        let texture_rect_origin = clipped_tile_rect.origin - tile_rect.origin;
-        let texture_rect = Rect::new(
-            Point2D::new(texture_rect_origin.x / tile_rect.size.width,
-                         texture_rect_origin.y / tile_rect.size.height),
-            Size2D::new(clipped_tile_rect.size.width / tile_rect.size.width,
-                        clipped_tile_rect.size.height / tile_rect.size.height));
+    /// Multiplies `source` by the alpha channel of `mask`, returning a surface with the result.
+    fn apply_mask(&self, source: Surface, mask: &Surface) -> Surface {
+        let destination = Surface::new(source.size, false);
+        destination.bind_and_clear();
+        gl::disable(gl::BLEND);
+
+        let program = &self.context.filter_programs.mask_program;
+        self.context.bind_and_render_filter_pass(program, &source.texture, || {
+            gl::active_texture(gl::TEXTURE1);
+            gl::bind_texture(gl::TEXTURE_2D, mask.texture.native_texture());
+            gl::active_texture(gl::TEXTURE0);
+            gl::uniform_1i(program.get_uniform_location("uMask"), 1);
+        });
 
-        let tile_vertices: [TextureVertex; 4] = [
-            TextureVertex::new(clipped_tile_rect.origin, texture_rect.origin),
-            TextureVertex::new(clipped_tile_rect.top_right(), texture_rect.top_right()),
-            TextureVertex::new(clipped_tile_rect.bottom_left(), texture_rect.bottom_left()),
-            TextureVertex::new(clipped_tile_rect.bottom_right(), texture_rect.bottom_right()),
-        ];
+        gl::active_texture(gl::TEXTURE1);
+        gl::bind_texture(gl::TEXTURE_2D, 0);
+        gl::active_texture(gl::TEXTURE0);
+        gl::enable(gl::BLEND);
+        destination
+    }
 
-        if self.show_debug_borders {
-            let debug_vertices = [
-                // The weird ordering is converting from triangle-strip into a line-strip.
-                ColorVertex::new(clipped_tile_rect.origin),
-                ColorVertex::new(clipped_tile_rect.top_right()),
-                ColorVertex::new(clipped_tile_rect.bottom_right()),
-                ColorVertex::new(clipped_tile_rect.bottom_left()),
-                ColorVertex::new(clipped_tile_rect.origin),
-            ];
-            self.bind_and_render_quad_lines(&debug_vertices,
-                                            &transform,
-                                            projection,
-                                            &TILE_DEBUG_BORDER_COLOR,
-                                            TILE_DEBUG_BORDER_THICKNESS);
-        }
-
-        self.bind_and_render_quad(&tile_vertices,
-                                  &tile.texture,
-                                  &transform,
-                                  projection,
-                                  opacity);
-    }
-
-    fn render_3d_context<T>(&self,
-                            context: &RenderContext3D<T>,
-                            transform: &Matrix4,
-                            projection: &Matrix4,
-                            gfx_context: &NativeDisplay) {
-        if context.children.is_empty() {
-            return;
+    /// Blurs `source` with a separable gaussian kernel, with a standard deviation in device
+    /// pixels.
+    fn apply_blur(&self, source: Surface, std_deviation: f32) -> Surface {
+        if std_deviation <= 0.0 {
+            return source;
+        }
+
+        // Wide blurs run on a downscaled copy, so that their kernel isn't truncated.
+        let downscale_steps = blur_downscale_steps(std_deviation);
+        if downscale_steps > 0 {
+            let size = source.size;
+            let mut current = source;
+            for _ in 0..downscale_steps {
+                let half_size = Size2D::new((current.size.width + 1) / 2,
+                                            (current.size.height + 1) / 2);
+                current = self.resample(&current, half_size);
+            }
+            let small_std_deviation = std_deviation / (1 << downscale_steps) as f32;
+            let blurred = self.apply_blur(current, small_std_deviation);
+            return self.resample(&blurred, size);
+        }
+
+        let weights = gaussian_weights(std_deviation);
+        let program = &self.context.filter_programs.blur_program;
+        let texel_size = Size2D::new(1.0 / source.size.width as f32,
+                                     1.0 / source.size.height as f32);
+
+        gl::disable(gl::BLEND);
+        let mut current = source;
+        for step in [Point2D::new(texel_size.width, 0.0),
+                     Point2D::new(0.0, texel_size.height)].iter() {
+            let destination = Surface::new(current.size, false);
+            destination.bind_and_clear();
+            self.context.bind_and_render_filter_pass(program, &current.texture, || {
+                gl::uniform_2f(program.get_uniform_location("uStep"), step.x, step.y);
+                gl::uniform_1i(program.get_uniform_location("uRadius"),
+                               (weights.len() - 1) as GLint);
+                gl::uniform_1fv(program.get_uniform_location("uWeights"), &weights);
+            });
+            current = destination;
         }
+        gl::enable(gl::BLEND);
+        current
+    }
 
+    /// Draws `source` into a new surface of the given size, with linear filtering.
+    fn resample(&self, source: &Surface, size: Size2D<GLsizei>) -> Surface {
+        let destination = Surface::new(size, false);
+        destination.bind_and_clear();
+        self.apply_color_matrix_into(source, &destination, &IDENTITY_COLOR_MATRIX, false);
+        destination
+    }
+}
+
+fn create_backdrop(size: &Size2D<f32>) -> Backdrop {
+    let size = Size2D::new(size.width as GLsizei, size.height as GLsizei);
+    let texture = gl::gen_textures(1)[0];
+    gl::bind_texture(gl::TEXTURE_2D, texture);
+    gl::tex_image_2d(gl::TEXTURE_2D, 0, gl::RGBA as GLint, size.width, size.height, 0,
+                     gl::RGBA, gl::UNSIGNED_BYTE, None);
+    gl::tex_parameter_i(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+    gl::tex_parameter_i(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+    gl::tex_parameter_i(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+    gl::tex_parameter_i(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+    gl::bind_texture(gl::TEXTURE_2D, 0);
+
+    Backdrop {
+        texture: texture,
+        size: size,
+        origin: Point2D::zero(),
+    }
+}
+
+impl Renderer for GLRenderer {
+    fn begin_frame(&mut self, viewport: &Rect<f32>, scale: f32) {
+        self.scale = scale;
+        self.scale_transform = Matrix4::identity().scale(scale, scale, 1.0);
+        self.targets = vec!(RenderTarget {
+            surface: None,
+            device_rect: Rect::new(Point2D::zero(), viewport.size),
+            window_origin: Point2D::new(viewport.origin.x as GLint, viewport.origin.y as GLint),
+            backdrop: None,
+            mask: None,
+            scissor_rect: Some(self.damage_rect),
+        });
+
+        // Set the viewport.
+        self.target().bind();
+
+        // Enable depth testing for 3d transforms. Set z-mode to LESS-EQUAL
+        // so that layers with equal Z are able to paint correctly in
+        // the order they are specified.
+        gl::enable(gl::DEPTH_TEST);
+        gl::clear_color(1.0, 1.0, 1.0, 1.0);
+        gl::clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        gl::depth_func(gl::LEQUAL);
+    }
+
+    fn begin_3d_context(&mut self) {
         // Clear the z-buffer for each 3d render context
-        // TODO(gw): Potential optimization here if there are no
-        //           layer intersections to disable z-buffering and
-        //           avoid clear.
         gl::clear(gl::DEPTH_BUFFER_BIT);
+    }
 
-        // Render child layers with z-testing.
-        for child in &context.children {
-            if let Some(ref layer) = child.layer {
-                // TODO(gw): Disable clipping on 3d layers for now.
-                // Need to implement proper polygon clipping to
-                // make this work correctly.
-                let clip_rect = context.clip_rect.and_then(|cr| {
-                    let m = layer.transform_state.borrow().final_transform;
-
-                    // See https://drafts.csswg.org/css-transforms/#2d-matrix
-                    let is_3d_transform = m.m31 != 0.0 || m.m32 != 0.0 ||
-                                          m.m13 != 0.0 || m.m23 != 0.0 ||
-                                          m.m43 != 0.0 || m.m14 != 0.0 ||
-                                          m.m24 != 0.0 || m.m34 != 0.0 ||
-                                          m.m33 != 1.0 || m.m44 != 1.0;
-
-                    if is_3d_transform {
-                        None
-                    } else {
-                        // If the transform is 2d, invert it and back-transform
-                        // the clip rect into world space.
-                        let transform = m.invert();
-                        let xform_2d = Matrix2D::new(transform.m11, transform.m12,
-                                                     transform.m21, transform.m22,
-                                                     transform.m41, transform.m42);
-                        Some(xform_2d.transform_rect(&cr))
-                    }
+    fn prepare_layer(&mut self, tile_grid: &mut TileGrid) {
+        // Create native textures for this layer
+        tile_grid.create_textures(&self.context.compositing_display, &mut self.texture_pool);
+    }
 
-                });
-                self.render_layer(layer.clone(),
-                                  transform,
-                                  projection,
-                                  clip_rect,
-                                  gfx_context);
-            }
+    fn draw_solid_quad(&mut self, quad: &SolidQuad) {
+        let vertices: Vec<ColorVertex> =
+            quad_polygon(&quad.rect, quad.clip_polygon.as_ref()).into_iter().map(|point| {
+                ColorVertex::new(point)
+            }).collect();
+        let device_rect = self.device_rect(&quad.rect, &quad.transform);
+        if vertices.is_empty() || !self.begin_blend(quad.blend_mode, device_rect) {
+            return;
+        }
+
+        self.context.bind_and_render_solid_quad(&vertices,
+                                                &self.scale_transform.mul(&quad.transform),
+                                                &self.target().projection(),
+                                                &quad.color,
+                                                quad.blend_mode,
+                                                self.backdrop_for_blend_mode(quad.blend_mode),
+                                                &self.rounded_clip_values(&quad.rounded_clips));
+        self.end_blend(quad.blend_mode);
+    }
 
-            if let Some(ref context) = child.context {
-                self.render_3d_context(context,
-                                       transform,
-                                       projection,
-                                       gfx_context);
+    fn draw_tile_quad(&mut self, quad: &TileQuad) {
+        if quad.tile.texture.is_zero() {
+            return;
+        }
 
+        // Texture coordinates vary linearly across the quad, so they can be computed for
+        // each vertex of a clipped polygon.
+        let rect = quad.rect;
+        let texture_rect = match quad.tile.atlas_rect {
+            Some(atlas_rect) => {
+                Rect::new(Point2D::new(atlas_rect.origin.x +
+                                           quad.texture_rect.origin.x * atlas_rect.size.width,
+                                       atlas_rect.origin.y +
+                                           quad.texture_rect.origin.y * atlas_rect.size.height),
+                          Size2D::new(quad.texture_rect.size.width * atlas_rect.size.width,
+                                      quad.texture_rect.size.height * atlas_rect.size.height))
             }
+            None => quad.texture_rect,
+        };
+        let vertices: Vec<TextureVertex> =
+            quad_polygon(&rect, quad.clip_polygon.as_ref()).into_iter().map(|point| {
+                let u = (point.x - rect.origin.x) / rect.size.width;
+                let v = (point.y - rect.origin.y) / rect.size.height;
+                let texture_coordinates =
+                    Point2D::new(texture_rect.origin.x + u * texture_rect.size.width,
+                                 texture_rect.origin.y + v * texture_rect.size.height);
+                TextureVertex::new(point, texture_coordinates)
+            }).collect();
+        let device_rect = self.device_rect(&rect, &quad.transform);
+        if vertices.is_empty() || !self.begin_blend(quad.blend_mode, device_rect) {
+            return;
         }
+
+        self.context.bind_and_render_quad(&vertices,
+                                          &quad.tile.texture,
+                                          &self.scale_transform.mul(&quad.transform),
+                                          &self.target().projection(),
+                                          quad.opacity,
+                                          quad.blend_mode,
+                                          self.backdrop_for_blend_mode(quad.blend_mode),
+                                          &self.rounded_clip_values(&quad.rounded_clips));
+        self.end_blend(quad.blend_mode);
+    }
+
+    fn begin_group(&mut self, bounds: &Rect<f32>) {
+        let x0 = (bounds.min_x() * self.scale).floor();
+        let y0 = (bounds.min_y() * self.scale).floor();
+        let x1 = (bounds.max_x() * self.scale).ceil().max(x0 + 1.0);
+        let y1 = (bounds.max_y() * self.scale).ceil().max(y0 + 1.0);
+        self.push_offscreen_target(Rect::new(Point2D::new(x0, y0),
+                                             Size2D::new(x1 - x0, y1 - y0)));
+    }
+
+    fn begin_mask(&mut self) {
+        let device_rect = self.target().device_rect;
+        self.push_offscreen_target(device_rect);
+    }
+
+    fn end_mask(&mut self) {
+        let mut target = self.targets.pop().unwrap();
+        self.targets.last_mut().unwrap().mask = target.surface.take();
+    }
+
+    fn end_group(&mut self, effects: &GroupEffects) {
+        let mut target = self.targets.pop().unwrap();
+        let mut surface = target.surface.take().unwrap();
+        for filter in effects.filters.iter() {
+            surface = self.apply_filter(surface, filter);
+        }
+        if let Some(mask) = target.mask.take() {
+            surface = self.apply_mask(surface, &mask);
+        }
+        self.target().bind();
+
+        // The group is already flattened, so it is drawn in paint order without depth testing.
+        let rect = target.device_rect;
+        let vertices: Vec<TextureVertex> = rect_to_polygon(&rect).into_iter().map(|point| {
+            let texture_coordinates = Point2D::new((point.x - rect.origin.x) / rect.size.width,
+                                                   (point.y - rect.origin.y) / rect.size.height);
+            TextureVertex::new(point, texture_coordinates)
+        }).collect();
+        if !self.begin_blend(effects.blend_mode, Some(rect)) {
+            return;
+        }
+
+        gl::disable(gl::DEPTH_TEST);
+        self.context.bind_and_render_quad(&vertices,
+                                          &surface.texture,
+                                          &Matrix4::identity(),
+                                          &self.target().projection(),
+                                          effects.opacity,
+                                          effects.blend_mode,
+                                          self.backdrop_for_blend_mode(effects.blend_mode),
+                                          &RoundedClipValues::none());
+        gl::enable(gl::DEPTH_TEST);
+        self.end_blend(effects.blend_mode);
+    }
+
+    fn draw_debug_lines(&mut self, lines: &DebugLines) {
+        let vertices = [
+            // The weird ordering is converting from triangle-strip into a line-strip.
+            ColorVertex::new(lines.rect.origin),
+            ColorVertex::new(lines.rect.top_right()),
+            ColorVertex::new(lines.rect.bottom_right()),
+            ColorVertex::new(lines.rect.bottom_left()),
+            ColorVertex::new(lines.rect.origin),
+        ];
+
+        self.context.bind_and_render_quad_lines(&vertices,
+                                                &self.scale_transform.mul(&lines.transform),
+                                                &self.target().projection(),
+                                                &lines.color,
+                                                lines.thickness);
+    }
+
+    fn show_debug_borders(&self) -> bool {
+        self.context.show_debug_borders
+    }
+
+    fn end_frame(&mut self) {
+        self.targets.clear();
+        gl::disable(gl::SCISSOR_TEST);
+    }
+}
+
+/// Returns the vertices of `rect`, or of its intersection with `clip_polygon`, in triangle fan
+/// order.
+fn quad_polygon(rect: &Rect<f32>, clip_polygon: Option<&Vec<Point2D<f32>>>) -> Vec<Point2D<f32>> {
+    match clip_polygon {
+        Some(clip_polygon) => clip_convex_polygon_to_rect(clip_polygon, rect),
+        None => rect_to_polygon(rect),
     }
 }
 
+/// Composites the part of the scene that has to be redrawn, and returns that part in device
+/// pixels relative to the viewport. Embedders can pass it on to swap-with-damage extensions.
+///
+/// `buffer_age` is the age of the back buffer, as reported by `EGL_EXT_buffer_age` or
+/// `GLX_EXT_buffer_age`. Only the damage of the frames since the back buffer was drawn is
+/// redrawn; if the age is `None` or 0, the whole viewport is. If nothing changed, nothing is
+/// drawn and `None` is returned, in which case the buffers must not be swapped.
 pub fn render_scene<T>(root_layer: Rc<Layer<T>>,
                        render_context: RenderContext,
-                       scene: &Scene<T>) {
-    // Set the viewport.
-    let v = scene.viewport.to_untyped();
-    gl::viewport(v.origin.x as GLint, v.origin.y as GLint,
-                 v.size.width as GLsizei, v.size.height as GLsizei);
-
-    // Enable depth testing for 3d transforms. Set z-mode to LESS-EQUAL
-    // so that layers with equal Z are able to paint correctly in
-    // the order they are specified.
-    gl::enable(gl::DEPTH_TEST);
-    gl::clear_color(1.0, 1.0, 1.0, 1.0);
-    gl::clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
-    gl::depth_func(gl::LEQUAL);
-
-    // Set up the initial modelview matrix.
-    let transform = Matrix4::identity().scale(scene.scale.get(), scene.scale.get(), 1.0);
-    let projection = create_ortho(&scene.viewport.size.to_untyped());
-
-    // Build the list of render items
-    render_context.render_3d_context(&RenderContext3D::new(root_layer.clone()),
-                                     &transform,
-                                     &projection,
-                                     &render_context.compositing_display);
+                       scene: &Scene<T>,
+                       buffer_age: Option<usize>)
+                       -> Option<TypedRect<DevicePixel, f32>> {
+    let damage_rect = match scene.calculate_damage(&root_layer, buffer_age) {
+        Some(damage_rect) => damage_rect,
+        None => return None,
+    };
+
+    // The renderer borrows the texture pool of the scene for the frame.
+    let texture_pool = mem::replace(&mut *scene.texture_pool.borrow_mut(), TexturePool::new());
+    let mut renderer = GLRenderer {
+        context: render_context,
+        scale_transform: Matrix4::identity(),
+        scale: 1.0,
+        targets: vec!(),
+        damage_rect: damage_rect.to_untyped(),
+        texture_pool: texture_pool,
+    };
+    composite_scene(root_layer, scene, &mut renderer);
+    *scene.texture_pool.borrow_mut() = renderer.texture_pool;
+    Some(damage_rect)
 }
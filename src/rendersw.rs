@@ -0,0 +1,849 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A software implementation of the compositor. This draws the same layer tree as `rendergl`
+//! into a caller-supplied RGBA buffer, so it works without a GPU or a display connection.
+//! Only tiles backed by a `MemoryBufferNativeSurface` can be read back on the CPU; tiles using
+//! other surface types are skipped.
+
+use color::Color;
+use filters::{apply_color_matrix, blur_downscale_steps, Filter, gaussian_weights};
+use layers::{BlendMode, Layer};
+use renderer::{composite_scene, GroupEffects, Renderer, RoundedClip, SolidQuad, TileQuad};
+use scene::Scene;
+use util::{point_in_convex_polygon, project_rect_to_screen, unproject_point_to_plane};
+
+use euclid::matrix::Matrix4;
+use euclid::point::Point2D;
+use euclid::rect::Rect;
+use euclid::size::Size2D;
+use std::f32;
+use std::rc::Rc;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+static CLEAR_COLOR: Color = Color { r: 1., g: 1., b: 1., a: 1. };
+
+/// The pixels being composited into: the viewport, or the surface of an offscreen group.
+/// Pixels are stored in rows from top to bottom, in RGBA order with premultiplied alpha.
+struct RenderTarget {
+    pixels: Vec<u8>,
+
+    /// The device pixel at the top left corner of this target, relative to the viewport.
+    origin: Point2D<isize>,
+    size: Size2D<usize>,
+
+    /// The depth of the frontmost fragment drawn to each pixel, used to emulate the depth
+    /// test that `rendergl` performs inside a 3d rendering context.
+    depth: Vec<f32>,
+
+    /// The alpha of every pixel of the mask of a group, once it has been drawn.
+    mask: Option<Vec<f32>>,
+}
+
+impl RenderTarget {
+    /// Creates a transparent target.
+    fn new(origin: Point2D<isize>, size: Size2D<usize>) -> RenderTarget {
+        RenderTarget {
+            pixels: vec![0; size.width * size.height * BYTES_PER_PIXEL],
+            origin: origin,
+            size: size,
+            depth: vec![f32::MAX; size.width * size.height],
+            mask: None,
+        }
+    }
+
+    fn clear(&mut self, color: &Color) {
+        let color = [color.r, color.g, color.b, color.a];
+        let pixel_count = self.size.width * self.size.height;
+        for pixel in self.pixels.chunks_mut(BYTES_PER_PIXEL).take(pixel_count) {
+            for i in 0..BYTES_PER_PIXEL {
+                pixel[i] = to_byte(color[i]);
+            }
+        }
+        self.clear_depth();
+    }
+
+    fn clear_depth(&mut self) {
+        for depth in self.depth.iter_mut() {
+            *depth = f32::MAX;
+        }
+    }
+
+    fn bounds(&self) -> Rect<f32> {
+        Rect::new(Point2D::new(self.origin.x as f32, self.origin.y as f32),
+                  Size2D::new(self.size.width as f32, self.size.height as f32))
+    }
+
+    /// Returns the premultiplied color of every pixel.
+    fn colors(&self) -> Vec<[f32; 4]> {
+        self.pixels.chunks(BYTES_PER_PIXEL).map(|pixel| {
+            [pixel[0] as f32 / 255.0,
+             pixel[1] as f32 / 255.0,
+             pixel[2] as f32 / 255.0,
+             pixel[3] as f32 / 255.0]
+        }).collect()
+    }
+
+    /// Blends a premultiplied color into a pixel, given relative to the origin of the target.
+    fn blend_pixel(&mut self, x: usize, y: usize, color: [f32; 4], blend_mode: BlendMode) {
+        let offset = (y * self.size.width + x) * BYTES_PER_PIXEL;
+        let pixel = &mut self.pixels[offset..offset + BYTES_PER_PIXEL];
+
+        if blend_mode == BlendMode::Normal {
+            // The same `ONE, ONE_MINUS_SRC_ALPHA` equation that `rendergl` uses.
+            let inverse_alpha = 1.0 - color[3];
+            for i in 0..BYTES_PER_PIXEL {
+                let destination = pixel[i] as f32 / 255.0;
+                pixel[i] = to_byte(color[i] + destination * inverse_alpha);
+            }
+            return;
+        }
+
+        let backdrop = [pixel[0] as f32 / 255.0,
+                        pixel[1] as f32 / 255.0,
+                        pixel[2] as f32 / 255.0,
+                        pixel[3] as f32 / 255.0];
+        let result = blend_with_backdrop(blend_mode, color, backdrop);
+        for i in 0..BYTES_PER_PIXEL {
+            pixel[i] = to_byte(result[i]);
+        }
+    }
+
+    /// Draws `rect` (in world coordinates) transformed by `transform` and scaled by `scale`,
+    /// restricted to `clip_polygon` if one is given and faded out by `rounded_clips`. `shader`
+    /// receives the world space position of each covered pixel and returns its premultiplied
+    /// color.
+    fn fill_rect<F>(&mut self,
+                    rect: &Rect<f32>,
+                    clip_polygon: Option<&Vec<Point2D<f32>>>,
+                    rounded_clips: &[RoundedClip],
+                    transform: &Matrix4,
+                    scale: f32,
+                    blend_mode: BlendMode,
+                    mut shader: F)
+                    where F: FnMut(&Point2D<f32>) -> Option<[f32; 4]> {
+        let screen_rect = match project_rect_to_screen(rect, transform) {
+            Some(screen_rect) => screen_rect.rect,
+            None => return,
+        };
+
+        let device_rect = match scale_rect(&screen_rect, scale).intersection(&self.bounds()) {
+            Some(device_rect) => device_rect,
+            None => return,
+        };
+
+        let x0 = device_rect.min_x().floor() as isize;
+        let y0 = device_rect.min_y().floor() as isize;
+        let x1 =
+            (device_rect.max_x().ceil() as isize).min(self.origin.x + self.size.width as isize);
+        let y1 =
+            (device_rect.max_y().ceil() as isize).min(self.origin.y + self.size.height as isize);
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let screen_point = Point2D::new((x as f32 + 0.5) / scale,
+                                                (y as f32 + 0.5) / scale);
+                let (layer_point, z) = match unproject_point_to_plane(&screen_point, transform) {
+                    Some(result) => result,
+                    None => continue,
+                };
+
+                if layer_point.x < rect.min_x() || layer_point.x >= rect.max_x() ||
+                   layer_point.y < rect.min_y() || layer_point.y >= rect.max_y() {
+                    continue;
+                }
+
+                if let Some(clip_polygon) = clip_polygon {
+                    if !point_in_convex_polygon(&layer_point, clip_polygon) {
+                        continue;
+                    }
+                }
+
+                let coverage = rounded_clips.iter().fold(1.0, |coverage, rounded_clip| {
+                    coverage * rounded_clip.coverage(&screen_point, 1.0 / scale)
+                });
+                if coverage <= 0.0 {
+                    continue;
+                }
+
+                // The orthographic projection in `rendergl` maps larger z values closer to the
+                // viewer, and the depth test there is LEQUAL.
+                let depth = -z;
+                let (target_x, target_y) =
+                    ((x - self.origin.x) as usize, (y - self.origin.y) as usize);
+                let index = target_y * self.size.width + target_x;
+                if depth > self.depth[index] {
+                    continue;
+                }
+
+                if let Some(mut color) = shader(&layer_point) {
+                    for component in color.iter_mut() {
+                        *component *= coverage;
+                    }
+                    self.depth[index] = depth;
+                    self.blend_pixel(target_x, target_y, color, blend_mode);
+                }
+            }
+        }
+    }
+}
+
+/// Runs a filter over the premultiplied colors of a surface. Distances are scaled by `scale`
+/// to get device pixels.
+fn apply_filter(colors: Vec<[f32; 4]>, size: &Size2D<usize>, filter: &Filter, scale: f32)
+                -> Vec<[f32; 4]> {
+    if let Some(matrix) = filter.color_matrix() {
+        return colors.into_iter().map(|color| apply_color_matrix(&matrix, color)).collect();
+    }
+
+    match *filter {
+        Filter::Blur(std_deviation) => blur(&colors, size, std_deviation * scale),
+        Filter::DropShadow(ref offset, std_deviation, ref color) => {
+            let shadow_color = [color.r * color.a, color.g * color.a, color.b * color.a, color.a];
+            let offset_x = (offset.x * scale).round() as isize;
+            let offset_y = (offset.y * scale).round() as isize;
+
+            let mut shadow = vec![[0.0; 4]; colors.len()];
+            for y in 0..size.height as isize {
+                for x in 0..size.width as isize {
+                    let (source_x, source_y) = (x - offset_x, y - offset_y);
+                    if source_x < 0 || source_y < 0 ||
+                       source_x >= size.width as isize || source_y >= size.height as isize {
+                        continue;
+                    }
+                    let alpha = colors[source_y as usize * size.width + source_x as usize][3];
+                    for i in 0..4 {
+                        shadow[y as usize * size.width + x as usize][i] = shadow_color[i] * alpha;
+                    }
+                }
+            }
+
+            // Draw the content over its blurred shadow.
+            let mut shadow = blur(&shadow, size, std_deviation * scale);
+            for (shadow, color) in shadow.iter_mut().zip(colors.iter()) {
+                for i in 0..4 {
+                    shadow[i] = color[i] + shadow[i] * (1.0 - color[3]);
+                }
+            }
+            shadow
+        }
+        _ => colors,
+    }
+}
+
+/// Blurs premultiplied colors with a separable gaussian kernel, with a standard deviation in
+/// device pixels. Pixels outside of the surface are transparent.
+fn blur(colors: &[[f32; 4]], size: &Size2D<usize>, std_deviation: f32) -> Vec<[f32; 4]> {
+    // Wide blurs run on a downscaled copy, so that their kernel isn't truncated.
+    let downscale_steps = blur_downscale_steps(std_deviation);
+    if downscale_steps > 0 {
+        let mut small_colors = colors.to_vec();
+        let mut small_size = Size2D::new(size.width, size.height);
+        for _ in 0..downscale_steps {
+            let half_size = Size2D::new((small_size.width + 1) / 2, (small_size.height + 1) / 2);
+            small_colors = resample(&small_colors, &small_size, &half_size);
+            small_size = half_size;
+        }
+        let small_std_deviation = std_deviation / (1 << downscale_steps) as f32;
+        let blurred = blur(&small_colors, &small_size, small_std_deviation);
+        return resample(&blurred, &small_size, size);
+    }
+
+    let weights = gaussian_weights(std_deviation);
+    let radius = weights.len() as isize - 1;
+    let blur_pass = |colors: &[[f32; 4]], horizontal: bool| -> Vec<[f32; 4]> {
+        let mut result = vec![[0.0; 4]; colors.len()];
+        for y in 0..size.height {
+            for x in 0..size.width {
+                let mut sum = [0.0; 4];
+                for tap in -radius..radius + 1 {
+                    let (tap_x, tap_y) = if horizontal {
+                        (x as isize + tap, y as isize)
+                    } else {
+                        (x as isize, y as isize + tap)
+                    };
+                    if tap_x < 0 || tap_y < 0 ||
+                       tap_x >= size.width as isize || tap_y >= size.height as isize {
+                        continue;
+                    }
+                    let weight = weights[tap.abs() as usize];
+                    let color = &colors[tap_y as usize * size.width + tap_x as usize];
+                    for i in 0..4 {
+                        sum[i] += color[i] * weight;
+                    }
+                }
+                result[y * size.width + x] = sum;
+            }
+        }
+        result
+    };
+
+    let horizontal = blur_pass(colors, true);
+    blur_pass(&horizontal, false)
+}
+
+/// Scales premultiplied colors to a new size with bilinear filtering, clamping samples to the
+/// edges like linear texture filtering in OpenGL does.
+fn resample(colors: &[[f32; 4]], size: &Size2D<usize>, new_size: &Size2D<usize>)
+            -> Vec<[f32; 4]> {
+    let mut result = Vec::with_capacity(new_size.width * new_size.height);
+    if size.width == 0 || size.height == 0 {
+        result.resize(new_size.width * new_size.height, [0.0; 4]);
+        return result;
+    }
+
+    // Returns the two samples around a pixel center and the weight of the second one.
+    let taps = |position: usize, length: usize, new_length: usize| -> (usize, usize, f32) {
+        let source_position = (position as f32 + 0.5) * length as f32 / new_length as f32 - 0.5;
+        let source_position = source_position.max(0.0).min((length - 1) as f32);
+        let first = source_position.floor() as usize;
+        (first, (first + 1).min(length - 1), source_position - first as f32)
+    };
+
+    for y in 0..new_size.height {
+        let (top, bottom, bottom_weight) = taps(y, size.height, new_size.height);
+        for x in 0..new_size.width {
+            let (left, right, right_weight) = taps(x, size.width, new_size.width);
+            let mut color = [0.0; 4];
+            for i in 0..4 {
+                let upper = colors[top * size.width + left][i] * (1.0 - right_weight) +
+                            colors[top * size.width + right][i] * right_weight;
+                let lower = colors[bottom * size.width + left][i] * (1.0 - right_weight) +
+                            colors[bottom * size.width + right][i] * right_weight;
+                color[i] = upper * (1.0 - bottom_weight) + lower * bottom_weight;
+            }
+            result.push(color);
+        }
+    }
+    result
+}
+
+fn luminosity(color: &[f32; 3]) -> f32 {
+    0.3 * color[0] + 0.59 * color[1] + 0.11 * color[2]
+}
+
+fn saturation(color: &[f32; 3]) -> f32 {
+    color[0].max(color[1]).max(color[2]) - color[0].min(color[1]).min(color[2])
+}
+
+fn clip_color(color: [f32; 3]) -> [f32; 3] {
+    let l = luminosity(&color);
+    let n = color[0].min(color[1]).min(color[2]);
+    let x = color[0].max(color[1]).max(color[2]);
+    let mut result = color;
+    for c in result.iter_mut() {
+        if n < 0.0 {
+            *c = l + (*c - l) * l / (l - n);
+        }
+        if x > 1.0 {
+            *c = l + (*c - l) * (1.0 - l) / (x - l);
+        }
+    }
+    result
+}
+
+fn set_luminosity(color: &[f32; 3], l: f32) -> [f32; 3] {
+    let d = l - luminosity(color);
+    clip_color([color[0] + d, color[1] + d, color[2] + d])
+}
+
+fn set_saturation(color: &[f32; 3], s: f32) -> [f32; 3] {
+    let min = color[0].min(color[1]).min(color[2]);
+    let max = color[0].max(color[1]).max(color[2]);
+    if max <= min {
+        return [0.0; 3];
+    }
+    [(color[0] - min) * s / (max - min),
+     (color[1] - min) * s / (max - min),
+     (color[2] - min) * s / (max - min)]
+}
+
+/// Applies a separable blend function to one channel of unpremultiplied colors.
+fn blend_channel(blend_mode: BlendMode, backdrop: f32, source: f32) -> f32 {
+    let multiply = |b: f32, s: f32| b * s;
+    let screen = |b: f32, s: f32| b + s - b * s;
+    let hard_light = |b: f32, s: f32| {
+        if s <= 0.5 { multiply(b, 2.0 * s) } else { screen(b, 2.0 * s - 1.0) }
+    };
+
+    match blend_mode {
+        BlendMode::Multiply => multiply(backdrop, source),
+        BlendMode::Screen => screen(backdrop, source),
+        BlendMode::Overlay => hard_light(source, backdrop),
+        BlendMode::Darken => backdrop.min(source),
+        BlendMode::Lighten => backdrop.max(source),
+        BlendMode::ColorDodge => {
+            if backdrop == 0.0 {
+                0.0
+            } else if source >= 1.0 {
+                1.0
+            } else {
+                (backdrop / (1.0 - source)).min(1.0)
+            }
+        }
+        BlendMode::ColorBurn => {
+            if backdrop >= 1.0 {
+                1.0
+            } else if source <= 0.0 {
+                0.0
+            } else {
+                1.0 - ((1.0 - backdrop) / source).min(1.0)
+            }
+        }
+        BlendMode::HardLight => hard_light(backdrop, source),
+        BlendMode::SoftLight => {
+            if source <= 0.5 {
+                backdrop - (1.0 - 2.0 * source) * backdrop * (1.0 - backdrop)
+            } else {
+                let d = if backdrop <= 0.25 {
+                    ((16.0 * backdrop - 12.0) * backdrop + 4.0) * backdrop
+                } else {
+                    backdrop.sqrt()
+                };
+                backdrop + (2.0 * source - 1.0) * (d - backdrop)
+            }
+        }
+        BlendMode::Difference => (backdrop - source).abs(),
+        BlendMode::Exclusion => backdrop + source - 2.0 * backdrop * source,
+        _ => source,
+    }
+}
+
+/// Computes the blended color of unpremultiplied backdrop and source colors.
+fn blend_colors(blend_mode: BlendMode, backdrop: &[f32; 3], source: &[f32; 3]) -> [f32; 3] {
+    match blend_mode {
+        BlendMode::Hue => {
+            set_luminosity(&set_saturation(source, saturation(backdrop)), luminosity(backdrop))
+        }
+        BlendMode::Saturation => {
+            set_luminosity(&set_saturation(backdrop, saturation(source)), luminosity(backdrop))
+        }
+        BlendMode::Color => set_luminosity(source, luminosity(backdrop)),
+        BlendMode::Luminosity => set_luminosity(backdrop, luminosity(source)),
+        _ => [blend_channel(blend_mode, backdrop[0], source[0]),
+              blend_channel(blend_mode, backdrop[1], source[1]),
+              blend_channel(blend_mode, backdrop[2], source[2])],
+    }
+}
+
+/// Composites a premultiplied source color over a premultiplied backdrop color with the given
+/// blend mode, following the general formula from the compositing specification.
+fn blend_with_backdrop(blend_mode: BlendMode, source: [f32; 4], backdrop: [f32; 4]) -> [f32; 4] {
+    let unpremultiply = |color: &[f32; 4]| {
+        if color[3] > 0.0 {
+            [color[0] / color[3], color[1] / color[3], color[2] / color[3]]
+        } else {
+            [0.0; 3]
+        }
+    };
+
+    let source_alpha = source[3];
+    let backdrop_alpha = backdrop[3];
+    let blended = blend_colors(blend_mode, &unpremultiply(&backdrop), &unpremultiply(&source));
+
+    let mut result = [0.0; 4];
+    for i in 0..3 {
+        result[i] = source[i] * (1.0 - backdrop_alpha) +
+                    backdrop[i] * (1.0 - source_alpha) +
+                    source_alpha * backdrop_alpha * blended[i];
+    }
+    result[3] = source_alpha + backdrop_alpha - source_alpha * backdrop_alpha;
+    result
+}
+
+fn to_byte(value: f32) -> u8 {
+    (value.max(0.0).min(1.0) * 255.0 + 0.5) as u8
+}
+
+fn scale_rect(rect: &Rect<f32>, scale: f32) -> Rect<f32> {
+    Rect::new(Point2D::new(rect.origin.x * scale, rect.origin.y * scale),
+              Size2D::new(rect.size.width * scale, rect.size.height * scale))
+}
+
+/// Reads a texel of a BGRA surface as a premultiplied RGBA color, clamping to the edges.
+fn fetch_texel(bytes: &[u8], size: &Size2D<usize>, x: isize, y: isize) -> [f32; 4] {
+    let x = x.max(0).min(size.width as isize - 1) as usize;
+    let y = y.max(0).min(size.height as isize - 1) as usize;
+    let offset = (y * size.width + x) * BYTES_PER_PIXEL;
+    [bytes[offset + 2] as f32 / 255.0,
+     bytes[offset + 1] as f32 / 255.0,
+     bytes[offset] as f32 / 255.0,
+     bytes[offset + 3] as f32 / 255.0]
+}
+
+#[derive(Copy, Clone)]
+pub struct SoftwareRenderContext {
+    force_near_texture_filter: bool,
+}
+
+impl SoftwareRenderContext {
+    pub fn new(force_near_texture_filter: bool) -> SoftwareRenderContext {
+        SoftwareRenderContext {
+            force_near_texture_filter: force_near_texture_filter,
+        }
+    }
+
+    /// Samples a texel from a surface at the given texel-space coordinates.
+    fn sample(&self, bytes: &[u8], size: &Size2D<usize>, x: f32, y: f32) -> [f32; 4] {
+        if self.force_near_texture_filter {
+            return fetch_texel(bytes, size, x.floor() as isize, y.floor() as isize);
+        }
+
+        // Bilinear filtering between the four nearest texel centers.
+        let x = x - 0.5;
+        let y = y - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        let top_left = fetch_texel(bytes, size, x0, y0);
+        let top_right = fetch_texel(bytes, size, x0 + 1, y0);
+        let bottom_left = fetch_texel(bytes, size, x0, y0 + 1);
+        let bottom_right = fetch_texel(bytes, size, x0 + 1, y0 + 1);
+
+        let mut result = [0.0; 4];
+        for i in 0..4 {
+            let top = top_left[i] + (top_right[i] - top_left[i]) * fx;
+            let bottom = bottom_left[i] + (bottom_right[i] - bottom_left[i]) * fx;
+            result[i] = top + (bottom - top) * fy;
+        }
+        result
+    }
+}
+
+/// Draws the items of a single frame into the caller's buffer.
+struct SoftwareRenderer<'a> {
+    context: &'a SoftwareRenderContext,
+    output: &'a mut [u8],
+
+    /// The viewport, followed by the surfaces of the groups being drawn.
+    targets: Vec<RenderTarget>,
+    scale: f32,
+}
+
+impl<'a> SoftwareRenderer<'a> {
+    fn target(&mut self) -> &mut RenderTarget {
+        self.targets.last_mut().unwrap()
+    }
+}
+
+impl<'a> Renderer for SoftwareRenderer<'a> {
+    fn begin_frame(&mut self, viewport: &Rect<f32>, scale: f32) {
+        let size = Size2D::new(viewport.size.width as usize, viewport.size.height as usize);
+        assert!(self.output.len() >= size.width * size.height * BYTES_PER_PIXEL);
+
+        self.scale = scale;
+        let mut target = RenderTarget::new(Point2D::zero(), size);
+        target.clear(&CLEAR_COLOR);
+        self.targets = vec!(target);
+    }
+
+    fn begin_3d_context(&mut self) {
+        // Each 3d rendering context gets a fresh depth buffer, like in `rendergl`.
+        self.target().clear_depth();
+    }
+
+    fn draw_solid_quad(&mut self, quad: &SolidQuad) {
+        let color = [quad.color.r, quad.color.g, quad.color.b, quad.color.a];
+        let scale = self.scale;
+        self.target().fill_rect(&quad.rect,
+                                quad.clip_polygon.as_ref(),
+                                &quad.rounded_clips,
+                                &quad.transform,
+                                scale,
+                                quad.blend_mode,
+                                |_| Some(color));
+    }
+
+    fn draw_tile_quad(&mut self, quad: &TileQuad) {
+        let buffer = match quad.tile.buffer() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+
+        let surface = match buffer.native_surface.as_memory_buffer() {
+            Some(surface) => surface,
+            None => {
+                debug!("Software compositor skipping a tile without a memory buffer surface");
+                return;
+            }
+        };
+
+        let texture_size = Size2D::new(surface.size.width as usize, surface.size.height as usize);
+        let bytes = surface.bytes();
+        if bytes.len() < texture_size.width * texture_size.height * BYTES_PER_PIXEL ||
+           texture_size.width == 0 || texture_size.height == 0 {
+            return;
+        }
+
+        // Map world coordinates inside the quad to texel coordinates.
+        let rect = quad.rect;
+        let texels_per_unit_x =
+            quad.texture_rect.size.width * texture_size.width as f32 / rect.size.width;
+        let texels_per_unit_y =
+            quad.texture_rect.size.height * texture_size.height as f32 / rect.size.height;
+        let texel_origin_x = quad.texture_rect.origin.x * texture_size.width as f32;
+        let texel_origin_y = quad.texture_rect.origin.y * texture_size.height as f32;
+
+        let context = self.context;
+        let opacity = quad.opacity;
+        let scale = self.scale;
+        let shader = |point: &Point2D<f32>| {
+            let mut color =
+                context.sample(bytes,
+                               &texture_size,
+                               texel_origin_x + (point.x - rect.origin.x) * texels_per_unit_x,
+                               texel_origin_y + (point.y - rect.origin.y) * texels_per_unit_y);
+            for component in color.iter_mut() {
+                *component *= opacity;
+            }
+            Some(color)
+        };
+        self.target().fill_rect(&rect,
+                                quad.clip_polygon.as_ref(),
+                                &quad.rounded_clips,
+                                &quad.transform,
+                                scale,
+                                quad.blend_mode,
+                                shader);
+    }
+
+    fn begin_group(&mut self, bounds: &Rect<f32>) {
+        let x0 = (bounds.min_x() * self.scale).floor() as isize;
+        let y0 = (bounds.min_y() * self.scale).floor() as isize;
+        let x1 = ((bounds.max_x() * self.scale).ceil() as isize).max(x0 + 1);
+        let y1 = ((bounds.max_y() * self.scale).ceil() as isize).max(y0 + 1);
+        self.targets.push(RenderTarget::new(Point2D::new(x0, y0),
+                                            Size2D::new((x1 - x0) as usize, (y1 - y0) as usize)));
+    }
+
+    fn begin_mask(&mut self) {
+        let (origin, size) = {
+            let group = self.target();
+            (group.origin, group.size)
+        };
+        self.targets.push(RenderTarget::new(origin, size));
+    }
+
+    fn end_mask(&mut self) {
+        let mask = self.targets.pop().unwrap();
+        let alpha = mask.colors().iter().map(|color| color[3]).collect();
+        self.target().mask = Some(alpha);
+    }
+
+    fn end_group(&mut self, effects: &GroupEffects) {
+        let group = self.targets.pop().unwrap();
+        let mut colors = group.colors();
+        for filter in effects.filters.iter() {
+            colors = apply_filter(colors, &group.size, filter, self.scale);
+        }
+        if let Some(ref mask) = group.mask {
+            for (color, alpha) in colors.iter_mut().zip(mask.iter()) {
+                for component in color.iter_mut() {
+                    *component *= *alpha;
+                }
+            }
+        }
+
+        // The group is already flattened, so it is drawn in paint order without depth testing.
+        let target = self.target();
+        for y in 0..group.size.height {
+            for x in 0..group.size.width {
+                let target_x = group.origin.x + x as isize - target.origin.x;
+                let target_y = group.origin.y + y as isize - target.origin.y;
+                if target_x < 0 || target_y < 0 ||
+                   target_x >= target.size.width as isize ||
+                   target_y >= target.size.height as isize {
+                    continue;
+                }
+
+                let mut color = colors[y * group.size.width + x];
+                for component in color.iter_mut() {
+                    *component *= effects.opacity;
+                }
+                if color[3] <= 0.0 && effects.blend_mode == BlendMode::Normal {
+                    continue;
+                }
+                target.blend_pixel(target_x as usize, target_y as usize, color, effects.blend_mode);
+            }
+        }
+    }
+
+    fn end_frame(&mut self) {
+        let target = self.targets.pop().unwrap();
+        let length = target.pixels.len();
+        self.output[..length].clone_from_slice(&target.pixels);
+    }
+}
+
+/// Composites the scene into `pixels`, which must hold at least as many RGBA pixels as the
+/// scene viewport. The buffer represents the viewport, so its origin is ignored.
+pub fn render_scene<T>(root_layer: Rc<Layer<T>>,
+                       render_context: &SoftwareRenderContext,
+                       scene: &Scene<T>,
+                       pixels: &mut [u8]) {
+    // The whole viewport is always drawn, but the damage is still consumed so that the scene
+    // knows this frame was composited.
+    scene.calculate_damage(&root_layer, None);
+
+    let mut renderer = SoftwareRenderer {
+        context: render_context,
+        output: pixels,
+        targets: vec!(),
+        scale: scene.scale.get(),
+    };
+    composite_scene(root_layer, scene, &mut renderer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{blur, BYTES_PER_PIXEL, render_scene, resample, SoftwareRenderContext};
+    use filters::MAX_BLUR_RADIUS;
+    use color::Color;
+    use layers::Layer;
+    use scene::Scene;
+
+    use euclid::matrix::Matrix4;
+    use euclid::point::Point2D;
+    use euclid::rect::Rect;
+    use euclid::size::Size2D;
+    use std::rc::Rc;
+
+    const VIEWPORT_SIZE: usize = 8;
+
+    static TRANSPARENT: Color = Color { r: 0., g: 0., b: 0., a: 0. };
+    static RED: Color = Color { r: 1., g: 0., b: 0., a: 1. };
+    static GREEN: Color = Color { r: 0., g: 1., b: 0., a: 1. };
+    static BLUE: Color = Color { r: 0., g: 0., b: 1., a: 1. };
+
+    static WHITE_PIXEL: [u8; 4] = [255, 255, 255, 255];
+    static RED_PIXEL: [u8; 4] = [255, 0, 0, 255];
+    static GREEN_PIXEL: [u8; 4] = [0, 255, 0, 255];
+    static BLUE_PIXEL: [u8; 4] = [0, 0, 255, 255];
+
+    fn layer(x: f32, y: f32, width: f32, height: f32, color: Color, opacity: f32)
+             -> Rc<Layer<()>> {
+        Rc::new(Layer::new(Rect::from_untyped(&Rect::new(Point2D::new(x, y),
+                                                         Size2D::new(width, height))),
+                           256,
+                           color,
+                           opacity,
+                           false,
+                           ()))
+    }
+
+    fn render(root_layer: Rc<Layer<()>>) -> Vec<u8> {
+        root_layer.update_transform_state(&Matrix4::identity(),
+                                          &Matrix4::identity(),
+                                          &Point2D::zero());
+
+        let viewport_size = VIEWPORT_SIZE as f32;
+        let mut scene = Scene::new(Rect::from_untyped(&Rect::new(Point2D::zero(),
+                                                                 Size2D::new(viewport_size,
+                                                                             viewport_size))));
+        scene.root = Some(root_layer.clone());
+
+        let mut pixels = vec![0; VIEWPORT_SIZE * VIEWPORT_SIZE * BYTES_PER_PIXEL];
+        render_scene(root_layer, &SoftwareRenderContext::new(false), &scene, &mut pixels);
+        pixels
+    }
+
+    fn assert_pixel(pixels: &[u8], x: usize, y: usize, expected: [u8; 4]) {
+        let offset = (y * VIEWPORT_SIZE + x) * BYTES_PER_PIXEL;
+        let actual = &pixels[offset..offset + BYTES_PER_PIXEL];
+        for i in 0..BYTES_PER_PIXEL {
+            assert!((actual[i] as i32 - expected[i] as i32).abs() <= 1,
+                    "pixel ({}, {}) is {:?} instead of {:?}", x, y, actual, expected);
+        }
+    }
+
+    #[test]
+    fn background_colors() {
+        let root_layer = layer(0.0, 0.0, 8.0, 8.0, RED, 1.0);
+        root_layer.add_child(layer(2.0, 2.0, 4.0, 4.0, BLUE, 1.0));
+
+        let pixels = render(root_layer);
+        assert_pixel(&pixels, 0, 0, RED_PIXEL);
+        assert_pixel(&pixels, 7, 7, RED_PIXEL);
+        assert_pixel(&pixels, 2, 2, BLUE_PIXEL);
+        assert_pixel(&pixels, 5, 5, BLUE_PIXEL);
+        assert_pixel(&pixels, 6, 5, RED_PIXEL);
+    }
+
+    #[test]
+    fn translucent_layers_blend_with_what_is_behind() {
+        let root_layer = layer(0.0, 0.0, 8.0, 8.0, TRANSPARENT, 1.0);
+        root_layer.add_child(layer(0.0, 0.0, 4.0, 8.0, RED, 0.5));
+
+        let pixels = render(root_layer);
+        assert_pixel(&pixels, 1, 4, [255, 128, 128, 255]);
+        assert_pixel(&pixels, 6, 4, WHITE_PIXEL);
+    }
+
+    #[test]
+    fn layers_that_mask_to_bounds_clip_their_children() {
+        let root_layer = layer(0.0, 0.0, 8.0, 8.0, TRANSPARENT, 1.0);
+        let clip_layer = layer(0.0, 0.0, 4.0, 8.0, TRANSPARENT, 1.0);
+        *clip_layer.masks_to_bounds.borrow_mut() = true;
+        clip_layer.add_child(layer(0.0, 0.0, 8.0, 8.0, GREEN, 1.0));
+        root_layer.add_child(clip_layer);
+
+        let pixels = render(root_layer);
+        assert_pixel(&pixels, 2, 4, GREEN_PIXEL);
+        assert_pixel(&pixels, 3, 0, GREEN_PIXEL);
+        assert_pixel(&pixels, 4, 4, WHITE_PIXEL);
+        assert_pixel(&pixels, 7, 7, WHITE_PIXEL);
+    }
+
+    #[test]
+    fn layers_rotated_in_3d_are_foreshortened() {
+        let root_layer = layer(0.0, 0.0, 8.0, 8.0, TRANSPARENT, 1.0);
+        let rotated_layer = layer(0.0, 0.0, 8.0, 8.0, RED, 1.0);
+
+        // A rotation of 60 degrees around the y axis halves the width of the layer.
+        let (sin, cos) = 60.0f32.to_radians().sin_cos();
+        *rotated_layer.transform.borrow_mut() = Matrix4::new(cos, 0.0, -sin, 0.0,
+                                                             0.0, 1.0, 0.0, 0.0,
+                                                             sin, 0.0, cos, 0.0,
+                                                             0.0, 0.0, 0.0, 1.0);
+        root_layer.add_child(rotated_layer);
+
+        let pixels = render(root_layer);
+        assert_pixel(&pixels, 0, 4, RED_PIXEL);
+        assert_pixel(&pixels, 3, 7, RED_PIXEL);
+        assert_pixel(&pixels, 4, 4, WHITE_PIXEL);
+        assert_pixel(&pixels, 7, 0, WHITE_PIXEL);
+    }
+
+    #[test]
+    fn resampling_keeps_uniform_colors() {
+        let colors = vec![[0.25, 0.5, 0.0, 0.5]; 5 * 3];
+        for color in resample(&colors, &Size2D::new(5, 3), &Size2D::new(2, 7)).iter() {
+            for i in 0..4 {
+                assert!((color[i] - colors[0][i]).abs() < 1.0e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn wide_blurs_reach_beyond_the_largest_kernel() {
+        let width = 200;
+        let mut colors = vec![[0.0; 4]; width];
+        for color in colors[..8].iter_mut() {
+            *color = [1.0, 0.0, 0.0, 1.0];
+        }
+
+        let blurred = blur(&colors, &Size2D::new(width, 1), 30.0);
+        assert!(blurred[8 + MAX_BLUR_RADIUS + 20][3] > 0.0);
+        assert!(blurred[0][3] < 1.0);
+    }
+}
@@ -10,9 +10,18 @@
 use euclid::rect::{Rect, TypedRect};
 use euclid::scale_factor::ScaleFactor;
 use euclid::size::TypedSize2D;
-use euclid::point::Point2D;
+use euclid::point::{Point2D, TypedPoint2D};
+use damage::DamageTracker;
 use geometry::{DevicePixel, LayerPixel};
-use layers::{BufferRequest, Layer, LayerBuffer};
+use layers::{BufferRequest, BufferRequestPriority, Layer, LayerBuffer};
+use renderer::{CheckerboardCounters, CheckerboardStyle, DrawStep, RenderContext3D, RoundedClip};
+use texturegl::TexturePool;
+use tiling::PrepaintPolicy;
+use util::{point_in_convex_polygon, unproject_point_to_plane};
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::mem;
 use std::rc::Rc;
 
 pub struct Scene<T> {
@@ -21,6 +30,43 @@ pub struct Scene<T> {
 
     /// The scene scale, to allow for zooming and high-resolution painting.
     pub scale: ScaleFactor<LayerPixel, DevicePixel, f32>,
+
+    /// How far beyond the viewport tiles are requested ahead of being visible.
+    pub prepaint_policy: PrepaintPolicy,
+
+    /// How fast the viewport is scrolling over the content, in device pixels per second. This
+    /// skews the prepainted area towards the content that is about to become visible.
+    pub scroll_velocity: TypedPoint2D<DevicePixel, f32>,
+
+    /// The most buffer requests to hand out per frame, or `None` for no limit. The most
+    /// urgent requests are kept, so that a flood of requests doesn't delay visible tiles.
+    pub buffer_request_budget: Option<usize>,
+
+    /// The most memory that tile buffers may use, in bytes, or `None` for no limit. Tiles
+    /// that are visible in the current frame are never evicted, so what's on screen can
+    /// exceed the budget.
+    pub memory_budget: Option<usize>,
+
+    /// Called with the layers that lost tiles when tiles were evicted to stay within the
+    /// memory budget.
+    pub eviction_callback: Option<Box<Fn(&[Rc<Layer<T>>])>>,
+
+    /// How visible tiles without content are drawn, or `None` to leave them out.
+    pub checkerboard_style: Option<CheckerboardStyle>,
+
+    /// How much content was missing in the last composited frame.
+    checkerboard_counters: Cell<CheckerboardCounters>,
+
+    /// Textures that tiles no longer use, kept so that new tiles don't have to allocate
+    /// their own. Replace it with `TexturePool::with_atlas` to pack small tiles together.
+    pub texture_pool: RefCell<TexturePool>,
+
+    /// Works out which part of the viewport changed between frames.
+    damage_tracker: RefCell<DamageTracker>,
+
+    /// The current frame, which is advanced every time a frame is composited. Tiles remember
+    /// the last frame in which they were visible.
+    frame_number: Cell<u64>,
 }
 
 impl<T> Scene<T> {
@@ -29,6 +75,16 @@ impl<T> Scene<T> {
             root: None,
             viewport: viewport,
             scale: ScaleFactor::new(1.0),
+            prepaint_policy: PrepaintPolicy::new(),
+            scroll_velocity: TypedPoint2D::zero(),
+            buffer_request_budget: None,
+            memory_budget: None,
+            eviction_callback: None,
+            checkerboard_style: None,
+            checkerboard_counters: Cell::new(CheckerboardCounters::new()),
+            texture_pool: RefCell::new(TexturePool::new()),
+            damage_tracker: RefCell::new(DamageTracker::new()),
+            frame_number: Cell::new(1),
         }
     }
 
@@ -40,12 +96,27 @@ impl<T> Scene<T> {
                                                                         Vec<BufferRequest>)>,
                                          unused_buffers: &mut Vec<Box<LayerBuffer>>) {
         // Get buffers for this layer, in global (screen) coordinates.
-        let requests = layer.get_buffer_requests(dirty_rect, viewport_rect, self.scale);
+        let prepaint_margins = self.prepaint_policy.margins(&self.scroll_velocity.to_untyped());
+        let requests = layer.get_buffer_requests(dirty_rect,
+                                                 viewport_rect,
+                                                 self.scale,
+                                                 &prepaint_margins,
+                                                 self.frame_number.get());
         if !requests.is_empty() {
             layers_and_requests.push((layer.clone(), requests));
         }
         unused_buffers.extend(layer.collect_unused_buffers().into_iter());
 
+        // The mask is only drawn where the layer is, so it shares the layer's dirty rect.
+        let mask_layer = layer.mask_layer.borrow().clone();
+        if let Some(mask_layer) = mask_layer {
+            self.get_buffer_requests_for_layer(mask_layer,
+                                               dirty_rect,
+                                               viewport_rect,
+                                               layers_and_requests,
+                                               unused_buffers);
+        }
+
         // If this layer masks its children, we don't need to ask for tiles outside the
         // boundaries of this layer.
         let child_dirty_rect = if !*layer.masks_to_bounds.borrow() {
@@ -79,15 +150,186 @@ impl<T> Scene<T> {
             None => return,
         };
 
+        let mut new_requests = Vec::new();
         self.get_buffer_requests_for_layer(root_layer.clone(),
                                            *root_layer.bounds.borrow(),
                                            *root_layer.bounds.borrow(),
-                                           requests,
+                                           &mut new_requests,
                                            unused_buffers);
+
+        self.prioritize_buffer_requests(&mut new_requests);
+        requests.extend(new_requests.into_iter());
+
+        self.enforce_memory_budget(unused_buffers);
+        self.recycle_unused_buffers(requests, unused_buffers);
+    }
+
+    /// Hands the native surfaces of unused buffers to requests for tiles of the same size, so
+    /// that painters can reuse them instead of creating new ones. Buffers whose surfaces are
+    /// handed out are removed from `unused_buffers`, and their surfaces are marked as not
+    /// leaking since the painters that receive them are responsible for destroying them.
+    pub fn recycle_unused_buffers(&self,
+                                  requests: &mut Vec<(Rc<Layer<T>>, Vec<BufferRequest>)>,
+                                  unused_buffers: &mut Vec<Box<LayerBuffer>>) {
+        for &mut (_, ref mut layer_requests) in requests.iter_mut() {
+            for request in layer_requests.iter_mut() {
+                if request.native_surface.is_some() {
+                    continue;
+                }
+
+                let size = request.screen_rect.size;
+                let position = unused_buffers.iter().position(|buffer| {
+                    buffer.screen_pos.size == size
+                });
+                if let Some(position) = position {
+                    let mut buffer = unused_buffers.swap_remove(position);
+                    buffer.mark_wont_leak();
+                    request.native_surface = Some(buffer.native_surface);
+                }
+            }
+        }
+    }
+
+    /// Evicts tiles until the memory used by tile buffers fits in `memory_budget`. Fallback
+    /// tiles from a previous resolution go first, then tiles that were never visible, like
+    /// prefetched ones, followed by the tiles that were visible least recently. The buffers of
+    /// evicted tiles are added to `unused_buffers`, and `eviction_callback` is told which layers
+    /// lost tiles.
+    pub fn enforce_memory_budget(&self, unused_buffers: &mut Vec<Box<LayerBuffer>>) {
+        let memory_budget = match self.memory_budget {
+            Some(memory_budget) => memory_budget,
+            None => return,
+        };
+        let root_layer = match self.root {
+            Some(ref root_layer) => root_layer.clone(),
+            None => return,
+        };
+
+        let mut memory_usage = root_layer.get_memory_usage();
+        if memory_usage <= memory_budget {
+            return;
+        }
+
+        let mut evicted_layers: Vec<Rc<Layer<T>>> = Vec::new();
+        evict_fallback_tiles(&root_layer, memory_budget, &mut memory_usage, &mut evicted_layers);
+
+        let mut candidates = Vec::new();
+        collect_evictable_tiles(&root_layer, self.frame_number.get(), &mut candidates);
+        candidates.sort_by(|&(_, _, a, _), &(_, _, b, _)| a.cmp(&b));
+
+        for (layer, tile_index, _, tile_memory_usage) in candidates.into_iter() {
+            if memory_usage <= memory_budget {
+                break;
+            }
+
+            layer.evict_tile(&tile_index);
+            memory_usage -= tile_memory_usage;
+            add_evicted_layer(&mut evicted_layers, &layer);
+        }
+
+        for layer in evicted_layers.iter() {
+            unused_buffers.extend(layer.collect_unused_buffers().into_iter());
+        }
+
+        if evicted_layers.is_empty() {
+            return;
+        }
+        if let Some(ref eviction_callback) = self.eviction_callback {
+            (*eviction_callback)(&evicted_layers[..]);
+        }
+    }
+
+    /// Orders buffer requests so that the most urgent come first, and drops those beyond
+    /// `buffer_request_budget`. Prefetch requests whose buffers wouldn't fit in
+    /// `memory_budget` are dropped too. Dropped requests are requested again in later frames.
+    /// Requests for the same layer stay grouped while they are adjacent in the new order.
+    pub fn prioritize_buffer_requests(&self,
+                                      requests: &mut Vec<(Rc<Layer<T>>, Vec<BufferRequest>)>) {
+        let mut flattened_requests = Vec::new();
+        for (layer, layer_requests) in mem::replace(requests, Vec::new()).into_iter() {
+            for request in layer_requests.into_iter() {
+                flattened_requests.push((layer.clone(), request));
+            }
+        }
+
+        flattened_requests.sort_by(|&(_, ref a), &(_, ref b)| {
+            match a.priority.cmp(&b.priority) {
+                Ordering::Equal => {
+                    a.distance_to_viewport.partial_cmp(&b.distance_to_viewport)
+                                          .unwrap_or(Ordering::Equal)
+                }
+                ordering => ordering,
+            }
+        });
+
+        // Prefetched tiles are the first to be evicted, so only prefetch as much as fits in
+        // the memory budget. Otherwise they would be evicted and requested again every frame.
+        let mut prefetch_memory = match (self.memory_budget, self.root.as_ref()) {
+            (Some(memory_budget), Some(root_layer)) => {
+                Some(memory_budget.saturating_sub(root_layer.get_memory_usage()))
+            }
+            _ => None,
+        };
+
+        let budget = self.buffer_request_budget.unwrap_or(flattened_requests.len());
+        for (index, (layer, request)) in flattened_requests.into_iter().enumerate() {
+            if index >= budget {
+                layer.cancel_buffer_request(&request);
+                continue;
+            }
+
+            if request.priority == BufferRequestPriority::Prefetch {
+                if let Some(ref mut prefetch_memory) = prefetch_memory {
+                    let request_memory = estimated_buffer_memory(&request);
+                    if request_memory > *prefetch_memory {
+                        layer.cancel_buffer_request(&request);
+                        continue;
+                    }
+                    *prefetch_memory -= request_memory;
+                }
+            }
+
+            let same_layer = match requests.last() {
+                Some(&(ref last_layer, _)) => {
+                    &**last_layer as *const Layer<T> == &*layer as *const Layer<T>
+                }
+                None => false,
+            };
+            if same_layer {
+                requests.last_mut().unwrap().1.push(request);
+            } else {
+                requests.push((layer, vec!(request)));
+            }
+        }
+    }
+
+    fn tick_layer(&self, layer: &Rc<Layer<T>>, time: f64) -> bool {
+        let mut running = layer.tick_animations(time);
+        if let Some(ref mask_layer) = *layer.mask_layer.borrow() {
+            running = self.tick_layer(mask_layer, time) || running;
+        }
+        for kid in layer.children().iter() {
+            running = self.tick_layer(kid, time) || running;
+        }
+        running
+    }
+
+    /// Advances the animations of all layers to `time`, in seconds. This must be called before
+    /// `update_transform_state` so that animated transforms are taken into account. Returns
+    /// true if any animation is still running, in which case the scene should be composited
+    /// again on the next frame.
+    pub fn tick(&self, time: f64) -> bool {
+        match self.root {
+            Some(ref root_layer) => self.tick_layer(root_layer, time),
+            None => false,
+        }
     }
 
     pub fn mark_layer_contents_as_changed_recursively_for_layer(&self, layer: Rc<Layer<T>>) {
         layer.contents_changed();
+        if let Some(ref mask_layer) = *layer.mask_layer.borrow() {
+            self.mark_layer_contents_as_changed_recursively_for_layer(mask_layer.clone());
+        }
         for kid in layer.children().iter() {
             self.mark_layer_contents_as_changed_recursively_for_layer(kid.clone());
         }
@@ -110,6 +352,83 @@ impl<T> Scene<T> {
         }
     }
 
+    /// Returns the part of the viewport that has to be redrawn, in device pixels relative to
+    /// the viewport, or `None` if nothing changed. `root_layer` is the layer being composited,
+    /// which is normally `root`, and `buffer_age` is the age of the back buffer as described
+    /// for `DamageTracker::calculate_damage`. This is called by the compositing backends once
+    /// per frame.
+    pub fn calculate_damage(&self, root_layer: &Rc<Layer<T>>, buffer_age: Option<usize>)
+                            -> Option<TypedRect<DevicePixel, f32>> {
+        let damage = self.damage_tracker.borrow_mut().calculate_damage(root_layer,
+                                                                       &self.viewport.to_untyped(),
+                                                                       self.scale.get(),
+                                                                       buffer_age);
+        self.frame_number.set(self.frame_number.get() + 1);
+        damage.map(|damage| Rect::from_untyped(&damage))
+    }
+
+    /// Whether anything visible changed since the last frame was composited, including layer
+    /// properties, child lists, tile buffers, the viewport and the scale. Embedders can skip
+    /// compositing while this is false. `render_scene` clears it.
+    pub fn needs_composite(&self) -> bool {
+        match self.root {
+            Some(ref root_layer) => {
+                self.damage_tracker.borrow().needs_composite(root_layer,
+                                                             &self.viewport.to_untyped(),
+                                                             self.scale.get())
+            }
+            None => false,
+        }
+    }
+
+    /// Makes the next frame redraw the whole viewport, for instance after the contents of the
+    /// window were lost.
+    pub fn damage_all(&self) {
+        self.damage_tracker.borrow_mut().damage_all();
+    }
+
+    /// Returns the layers under a point in device pixels, relative to the top left of the
+    /// viewport, from the topmost to the bottommost. Layers are tested in the order that they
+    /// are composited in. The transform state of the layers must be up to date.
+    pub fn hit_test(&self, point: TypedPoint2D<DevicePixel, f32>) -> Vec<Rc<Layer<T>>> {
+        let root_layer = match self.root {
+            Some(ref root_layer) => root_layer.clone(),
+            None => return vec!(),
+        };
+
+        let point = point.to_untyped();
+        let viewport_size = self.viewport.to_untyped().size;
+        if point.x < 0.0 || point.x >= viewport_size.width ||
+           point.y < 0.0 || point.y >= viewport_size.height {
+            return vec!();
+        }
+
+        let scale = self.scale.get();
+        let screen_point = Point2D::new(point.x / scale, point.y / scale);
+
+        let mut unclipped_layers = HashSet::new();
+        collect_unclipped_layers(&root_layer, &screen_point, &mut unclipped_layers);
+
+        let mut layers = vec!();
+        hit_test_context(&RenderContext3D::new_for_hit_testing(root_layer),
+                         &screen_point,
+                         &unclipped_layers,
+                         &mut layers);
+        layers.reverse();
+        layers
+    }
+
+    /// Returns how many visible tiles had nothing to draw in the last composited frame, and
+    /// how much of the viewport they covered.
+    pub fn checkerboard_counters(&self) -> CheckerboardCounters {
+        self.checkerboard_counters.get()
+    }
+
+    /// Records the counters of a frame. This is called by `composite_scene`.
+    pub fn set_checkerboard_counters(&self, counters: CheckerboardCounters) {
+        self.checkerboard_counters.set(counters);
+    }
+
     /// Calculate the amount of memory used by all the layers in the
     /// scene graph. The memory may be allocated on the heap or in GPU memory.
     pub fn get_memory_usage(&self) -> usize {
@@ -120,3 +439,146 @@ impl<T> Scene<T> {
     }
 }
 
+/// The memory that the buffer painted for `request` will use, assuming 32 bits per pixel.
+fn estimated_buffer_memory(request: &BufferRequest) -> usize {
+    let size = request.screen_rect.size;
+    size.width.max(0) as usize * size.height.max(0) as usize * 4
+}
+
+fn add_evicted_layer<T>(evicted_layers: &mut Vec<Rc<Layer<T>>>, layer: &Rc<Layer<T>>) {
+    let already_evicted = evicted_layers.iter().any(|evicted_layer| {
+        &**evicted_layer as *const Layer<T> == &**layer as *const Layer<T>
+    });
+    if !already_evicted {
+        evicted_layers.push(layer.clone());
+    }
+}
+
+/// Evicts the fallback tiles of a layer and its descendants until `memory_usage` fits in
+/// `memory_budget`. Fallback tiles only stand in for tiles that are still being painted, so
+/// they are cheaper to lose than any current tile.
+fn evict_fallback_tiles<T>(layer: &Rc<Layer<T>>,
+                           memory_budget: usize,
+                           memory_usage: &mut usize,
+                           evicted_layers: &mut Vec<Rc<Layer<T>>>) {
+    if *memory_usage <= memory_budget {
+        return;
+    }
+
+    let freed_memory = layer.evict_fallback_tiles();
+    if freed_memory > 0 {
+        *memory_usage -= freed_memory;
+        add_evicted_layer(evicted_layers, layer);
+    }
+
+    if let Some(ref mask_layer) = *layer.mask_layer.borrow() {
+        evict_fallback_tiles(mask_layer, memory_budget, memory_usage, evicted_layers);
+    }
+    for child in layer.children().iter() {
+        evict_fallback_tiles(child, memory_budget, memory_usage, evicted_layers);
+    }
+}
+
+/// Collects the tiles of a layer and its descendants that weren't visible in `frame_number`,
+/// along with their layer, last visible frame and memory usage.
+fn collect_evictable_tiles<T>(layer: &Rc<Layer<T>>,
+                              frame_number: u64,
+                              tiles: &mut Vec<(Rc<Layer<T>>, Point2D<i32>, u64, usize)>) {
+    for (tile_index, last_visible_frame, memory_usage) in
+            layer.evictable_tiles(frame_number).into_iter() {
+        tiles.push((layer.clone(), tile_index, last_visible_frame, memory_usage));
+    }
+    if let Some(ref mask_layer) = *layer.mask_layer.borrow() {
+        collect_evictable_tiles(mask_layer, frame_number, tiles);
+    }
+    for child in layer.children().iter() {
+        collect_evictable_tiles(child, frame_number, tiles);
+    }
+}
+
+/// Collects the layers that are not clipped away at `screen_point` by ancestors that mask to
+/// their bounds.
+fn collect_unclipped_layers<T>(layer: &Rc<Layer<T>>,
+                               screen_point: &Point2D<f32>,
+                               unclipped_layers: &mut HashSet<*const Layer<T>>) {
+    unclipped_layers.insert(&**layer as *const Layer<T>);
+    if *layer.masks_to_bounds.borrow() && !clip_contains_point(&**layer, screen_point) {
+        return;
+    }
+
+    for child in layer.children().iter() {
+        collect_unclipped_layers(child, screen_point, unclipped_layers);
+    }
+}
+
+/// Appends the layers of a context that are hit at `screen_point` to `layers`, in paint order.
+/// A layer that is hit more than once only keeps its last position.
+fn hit_test_context<T>(context: &RenderContext3D<T>,
+                       screen_point: &Point2D<f32>,
+                       unclipped_layers: &HashSet<*const Layer<T>>,
+                       layers: &mut Vec<Rc<Layer<T>>>) {
+    // Masked groups are invisible outside of their mask layer.
+    if let Some(ref mask_layer) = context.mask_layer {
+        if !layer_contains_point(&**mask_layer, screen_point, None) {
+            return;
+        }
+    }
+
+    for step in context.draw_order.iter() {
+        match *step {
+            DrawStep::Layer(index, ref fragment) => {
+                let layer = match context.children[index].layer {
+                    Some(ref layer) => layer,
+                    None => continue,
+                };
+
+                let pointer = &**layer as *const Layer<T>;
+                if !unclipped_layers.contains(&pointer) ||
+                   !layer_contains_point(&**layer, screen_point, fragment.as_ref()) {
+                    continue;
+                }
+
+                layers.retain(|hit| &**hit as *const Layer<T> != pointer);
+                layers.push(layer.clone());
+            }
+            DrawStep::Context(index) => {
+                if let Some(ref child_context) = context.children[index].context {
+                    hit_test_context(child_context, screen_point, unclipped_layers, layers);
+                }
+            }
+        }
+    }
+}
+
+/// Whether a point in unscaled screen coordinates falls on a layer. If a BSP fragment of the
+/// layer is given, the point must also fall on the fragment.
+fn layer_contains_point<T>(layer: &Layer<T>,
+                           screen_point: &Point2D<f32>,
+                           fragment: Option<&Vec<Point2D<f32>>>)
+                           -> bool {
+    let ts = layer.transform_state.borrow();
+    let layer_point = match unproject_point_to_plane(screen_point, &ts.final_transform) {
+        Some((layer_point, _)) => layer_point,
+        None => return false,
+    };
+
+    let rect = &ts.world_rect;
+    if layer_point.x < rect.min_x() || layer_point.x >= rect.max_x() ||
+       layer_point.y < rect.min_y() || layer_point.y >= rect.max_y() {
+        return false;
+    }
+
+    fragment.map_or(true, |fragment| point_in_convex_polygon(&layer_point, fragment))
+}
+
+/// Whether a point in unscaled screen coordinates is inside the area that a layer clips its
+/// children to, including its rounded corners.
+fn clip_contains_point<T>(layer: &Layer<T>, screen_point: &Point2D<f32>) -> bool {
+    if !layer_contains_point(layer, screen_point, None) {
+        return false;
+    }
+    if layer.border_radii.borrow().is_zero() {
+        return true;
+    }
+    RoundedClip::new(layer).map_or(false, |rounded_clip| rounded_clip.contains(screen_point))
+}
@@ -10,10 +10,16 @@
 //! OpenGL-specific implementation of texturing.
 
 use layers::LayerBuffer;
+use platform::surface::MemoryBufferNativeSurface;
 
+use euclid::point::Point2D;
+use euclid::rect::Rect;
 use euclid::size::Size2D;
 use gleam::gl;
 use gleam::gl::{GLenum, GLint, GLuint};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 #[derive(Copy, Clone)]
 pub enum Format {
@@ -28,7 +34,7 @@ pub enum FilterMode {
 }
 
 /// The texture target.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum TextureTarget {
     /// TEXTURE_2D.
     TextureTarget2D,
@@ -127,7 +133,7 @@ impl Texture {
 
     pub fn new_with_buffer(buffer: &Box<LayerBuffer>) -> Texture {
         let (flip, target) = Texture::texture_flip_and_target(buffer.painted_with_cpu);
-        let mut texture = Texture::new(target, buffer.screen_pos.size);
+        let mut texture = Texture::new(target, buffer.get_size_2d());
         texture.flip = flip;
         return texture;
     }
@@ -208,3 +214,207 @@ pub enum Flip {
     /// The texture should be flipped vertically.
     VerticalFlip,
 }
+
+/// The number of unused textures kept for each size and target.
+const MAX_POOLED_TEXTURES_PER_KEY: usize = 16;
+
+/// The number of texels around the contents of each atlas slot that repeat their edges.
+const ATLAS_SLOT_GUTTER: usize = 1;
+
+/// Keeps textures that are no longer used, so that new buffers of the same size and target
+/// can reuse them instead of allocating new ones. Buffers are always bound as ARGB32, so the
+/// format doesn't need to be part of the key.
+pub struct TexturePool {
+    textures: HashMap<(usize, usize, TextureTarget), Vec<Texture>>,
+
+    /// If set, small buffers painted into memory are uploaded into this atlas instead of
+    /// getting a texture of their own.
+    atlas: Option<TextureAtlas>,
+}
+
+impl TexturePool {
+    pub fn new() -> TexturePool {
+        TexturePool {
+            textures: HashMap::new(),
+            atlas: None,
+        }
+    }
+
+    /// Creates a pool that also packs buffers of at most `slot_size` pixels in each direction
+    /// into an atlas of `atlas_size` pixels in each direction.
+    pub fn with_atlas(atlas_size: usize, slot_size: usize) -> TexturePool {
+        TexturePool {
+            textures: HashMap::new(),
+            atlas: Some(TextureAtlas::new(atlas_size, slot_size)),
+        }
+    }
+
+    /// Returns a texture suitable for binding the surface of `buffer`, reusing an unused one
+    /// if possible.
+    pub fn texture_for_buffer(&mut self, buffer: &Box<LayerBuffer>) -> Texture {
+        let (flip, target) = Texture::texture_flip_and_target(buffer.painted_with_cpu);
+        let size = buffer.get_size_2d();
+        let key = (size.width, size.height, target);
+        let mut texture = match self.textures.get_mut(&key).and_then(|textures| textures.pop()) {
+            Some(texture) => texture,
+            None => Texture::new(target, size),
+        };
+        texture.flip = flip;
+        texture
+    }
+
+    /// Keeps `texture` for reuse, unless enough textures like it are kept already.
+    pub fn recycle(&mut self, texture: Texture) {
+        if texture.weak || texture.is_zero() {
+            return;
+        }
+
+        let key = (texture.size.width, texture.size.height, texture.target);
+        let textures = self.textures.entry(key).or_insert_with(Vec::new);
+        if textures.len() < MAX_POOLED_TEXTURES_PER_KEY {
+            textures.push(texture);
+        }
+    }
+
+    /// Uploads `surface` into the atlas if there is one and the surface fits in a slot.
+    /// Returns a texture referring to the atlas, the slot, which is freed when dropped, and
+    /// the part of the atlas texture that holds the surface in texture coordinates.
+    pub fn upload_to_atlas(&mut self, surface: &MemoryBufferNativeSurface)
+                           -> Option<(Texture, AtlasSlot, Rect<f32>)> {
+        match self.atlas {
+            Some(ref mut atlas) => atlas.upload(surface),
+            None => None,
+        }
+    }
+
+    /// Deletes all unused textures.
+    pub fn clear(&mut self) {
+        self.textures.clear();
+    }
+}
+
+/// A texture split into square slots of equal size, each holding the contents of a small
+/// buffer surrounded by a one texel gutter.
+pub struct TextureAtlas {
+    texture: Texture,
+    slot_size: usize,
+    slots_per_row: usize,
+
+    /// The indices of unused slots, shared with the `AtlasSlot`s so that they can free their
+    /// slot when dropped.
+    free_slots: Rc<RefCell<Vec<usize>>>,
+}
+
+impl TextureAtlas {
+    pub fn new(atlas_size: usize, slot_size: usize) -> TextureAtlas {
+        let texture = Texture::new(TextureTarget::TextureTarget2D,
+                                   Size2D::new(atlas_size, atlas_size));
+        {
+            let _bound_texture = texture.bind();
+            gl::tex_image_2d(gl::TEXTURE_2D,
+                             0,
+                             gl::RGBA as GLint,
+                             atlas_size as i32,
+                             atlas_size as i32,
+                             0,
+                             gl::BGRA,
+                             gl::UNSIGNED_BYTE,
+                             None);
+        }
+
+        let slots_per_row = atlas_size / (slot_size + 2 * ATLAS_SLOT_GUTTER);
+        TextureAtlas {
+            texture: texture,
+            slot_size: slot_size,
+            slots_per_row: slots_per_row,
+            free_slots: Rc::new(RefCell::new((0..slots_per_row * slots_per_row).rev().collect())),
+        }
+    }
+
+    fn upload(&mut self, surface: &MemoryBufferNativeSurface)
+              -> Option<(Texture, AtlasSlot, Rect<f32>)> {
+        let width = surface.size.width as usize;
+        let height = surface.size.height as usize;
+        if width == 0 || height == 0 || width > self.slot_size || height > self.slot_size ||
+           surface.bytes().len() < width * height * 4 {
+            return None;
+        }
+
+        let index = match self.free_slots.borrow_mut().pop() {
+            Some(index) => index,
+            None => return None,
+        };
+        let slot_stride = self.slot_size + 2 * ATLAS_SLOT_GUTTER;
+        let origin = Point2D::new((index % self.slots_per_row) * slot_stride,
+                                  (index / self.slots_per_row) * slot_stride);
+
+        // Surround the contents with copies of their edge texels, so that linear filtering at
+        // the edges of the contents doesn't pick up the neighbouring slots.
+        let padded_width = width + 2 * ATLAS_SLOT_GUTTER;
+        let padded_height = height + 2 * ATLAS_SLOT_GUTTER;
+        let bytes = surface.bytes();
+        let mut padded_bytes = Vec::with_capacity(padded_width * padded_height * 4);
+        for padded_y in 0..padded_height {
+            let y = clamp_to_contents(padded_y, height);
+            for padded_x in 0..padded_width {
+                let offset = (y * width + clamp_to_contents(padded_x, width)) * 4;
+                padded_bytes.push_all(&bytes[offset..offset + 4]);
+            }
+        }
+
+        {
+            let _bound_texture = self.texture.bind();
+            gl::tex_sub_image_2d(gl::TEXTURE_2D,
+                                 0,
+                                 origin.x as i32,
+                                 origin.y as i32,
+                                 padded_width as i32,
+                                 padded_height as i32,
+                                 gl::BGRA,
+                                 gl::UNSIGNED_BYTE,
+                                 &padded_bytes);
+        }
+
+        // The texture is weak, so only the atlas deletes it.
+        let texture = Texture {
+            id: self.texture.id,
+            target: TextureTarget::TextureTarget2D,
+            weak: true,
+            flip: Flip::NoFlip,
+            size: self.texture.size,
+        };
+
+        let atlas_size = self.texture.size.width as f32;
+        let gutter = ATLAS_SLOT_GUTTER as f32;
+        let rect = Rect::new(Point2D::new((origin.x as f32 + gutter) / atlas_size,
+                                          (origin.y as f32 + gutter) / atlas_size),
+                             Size2D::new(width as f32 / atlas_size, height as f32 / atlas_size));
+
+        let slot = AtlasSlot {
+            free_slots: self.free_slots.clone(),
+            index: index,
+        };
+        Some((texture, slot, rect))
+    }
+}
+
+/// Maps a coordinate of a slot, gutter included, to the closest coordinate of its contents.
+fn clamp_to_contents(padded_coordinate: usize, size: usize) -> usize {
+    if padded_coordinate < ATLAS_SLOT_GUTTER {
+        0
+    } else {
+        (padded_coordinate - ATLAS_SLOT_GUTTER).min(size - 1)
+    }
+}
+
+/// A slot in a `TextureAtlas`, which becomes free again when this is dropped.
+pub struct AtlasSlot {
+    free_slots: Rc<RefCell<Vec<usize>>>,
+    index: usize,
+}
+
+impl Drop for AtlasSlot {
+    fn drop(&mut self) {
+        self.free_slots.borrow_mut().push(self.index);
+    }
+}
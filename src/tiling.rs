@@ -8,16 +8,18 @@
 // except according to those terms.
 
 use geometry::{DevicePixel, LayerPixel};
-use layers::{BufferRequest, ContentAge, LayerBuffer};
+use layers::{BufferRequest, BufferRequestPriority, ContentAge, LayerBuffer, SolidColorTile};
 use platform::surface::NativeDisplay;
-use texturegl::Texture;
-use util::project_rect_to_screen;
+use texturegl::{AtlasSlot, Texture, TexturePool};
+use util::{clip_layer_polygon_to_screen_polygon, polygon_bounding_rect, project_rect_to_screen};
+use util::{rect_to_polygon, unproject_point_to_plane};
 
+use euclid::Matrix2D;
 use euclid::length::Length;
 use euclid::matrix::Matrix4;
 use euclid::point::Point2D;
 use euclid::rect::{Rect, TypedRect};
-use euclid::size::{Size2D, TypedSize2D};
+use euclid::size::Size2D;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::mem;
@@ -26,48 +28,131 @@ pub struct Tile {
     /// The buffer displayed by this tile.
     buffer: Option<Box<LayerBuffer>>,
 
+    /// The color displayed by this tile instead of a buffer, if the painter found it to be a
+    /// single color.
+    solid_color: Option<SolidColorTile>,
+
     /// The content age of any pending buffer request to avoid re-requesting
     /// a buffer while waiting for it to come back from rendering.
     content_age_of_pending_buffer: Option<ContentAge>,
 
+    /// The content age that the buffer must have to be up to date. This is raised when the
+    /// content under the tile is invalidated.
+    content_age: ContentAge,
+
     /// A handle to the GPU texture.
     pub texture: Texture,
 
+    /// If the buffer was uploaded into a texture atlas, the part of `texture` holding it in
+    /// texture coordinates.
+    pub atlas_rect: Option<Rect<f32>>,
+
+    /// The atlas slot holding the buffer, which is freed when it is dropped.
+    atlas_slot: Option<AtlasSlot>,
+
     /// The tile boundaries in the parent layer coordinates.
     pub bounds: Option<TypedRect<LayerPixel,f32>>,
+
+    /// The last frame in which the tile was inside the viewport, or zero if it never was.
+    last_visible_frame: u64,
 }
 
 impl Tile {
     fn new() -> Tile {
         Tile {
             buffer: None,
+            solid_color: None,
             texture: Texture::zero(),
+            atlas_rect: None,
+            atlas_slot: None,
             content_age_of_pending_buffer: None,
+            content_age: ContentAge::new(),
             bounds: None,
+            last_visible_frame: 0,
         }
     }
 
-    fn should_use_new_buffer(&self, new_buffer: &Box<LayerBuffer>) -> bool {
-        match self.buffer {
-            Some(ref buffer) => new_buffer.content_age >= buffer.content_age,
+    /// The buffer currently displayed by this tile, if any.
+    pub fn buffer(&self) -> Option<&Box<LayerBuffer>> {
+        self.buffer.as_ref()
+    }
+
+    /// The solid color displayed by this tile instead of a buffer, if any.
+    pub fn solid_color(&self) -> Option<&SolidColorTile> {
+        self.solid_color.as_ref()
+    }
+
+    /// Whether the tile has a buffer or a solid color to draw.
+    pub fn has_content(&self) -> bool {
+        self.buffer.is_some() || self.solid_color.is_some()
+    }
+
+    /// The rect of the buffer or solid color displayed by this tile, in the coordinates of
+    /// buffer rects.
+    fn content_rect(&self) -> Option<Rect<f32>> {
+        match (&self.buffer, &self.solid_color) {
+            (&Some(ref buffer), _) => Some(buffer.rect),
+            (&None, &Some(ref solid_color)) => Some(solid_color.rect),
+            (&None, &None) => None,
+        }
+    }
+
+    /// The content age of the buffer or solid color displayed by this tile.
+    fn displayed_content_age(&self) -> Option<ContentAge> {
+        match (&self.buffer, &self.solid_color) {
+            (&Some(ref buffer), _) => Some(buffer.content_age),
+            (&None, &Some(ref solid_color)) => Some(solid_color.content_age),
+            (&None, &None) => None,
+        }
+    }
+
+    fn should_use_new_content(&self, content_age: ContentAge) -> bool {
+        match self.displayed_content_age() {
+            Some(displayed_content_age) => content_age >= displayed_content_age,
             None => true,
         }
     }
 
-    fn replace_buffer(&mut self, buffer: Box<LayerBuffer>) -> Option<Box<LayerBuffer>> {
-        if !self.should_use_new_buffer(&buffer) {
+    /// Replaces the buffer, returning the old buffer and its texture.
+    fn replace_buffer(&mut self, buffer: Box<LayerBuffer>) -> (Option<Box<LayerBuffer>>, Texture) {
+        if !self.should_use_new_content(buffer.content_age) {
             warn!("Layer received an old buffer.");
-            return Some(buffer);
+            return (Some(buffer), Texture::zero());
         }
 
         let old_buffer = self.buffer.take();
         self.buffer = Some(buffer);
-        self.texture = Texture::zero(); // The old texture is bound to the old buffer.
+        self.solid_color = None;
+        let old_texture = self.take_texture(); // The old texture is bound to the old buffer.
+        self.content_age_of_pending_buffer = None;
+        return (old_buffer, old_texture);
+    }
+
+    /// Replaces the buffer or solid color with a solid color, returning the old buffer and
+    /// its texture.
+    fn replace_with_solid_color(&mut self, solid_color: SolidColorTile)
+                                -> (Option<Box<LayerBuffer>>, Texture) {
+        if !self.should_use_new_content(solid_color.content_age) {
+            warn!("Layer received an old solid color tile.");
+            return (None, Texture::zero());
+        }
+
+        let old_buffer = self.buffer.take();
+        self.solid_color = Some(solid_color);
+        self.bounds = Some(Rect::from_untyped(&solid_color.rect));
+        let old_texture = self.take_texture();
         self.content_age_of_pending_buffer = None;
-        return old_buffer;
+        return (old_buffer, old_texture);
     }
 
-    fn create_texture(&mut self, display: &NativeDisplay) {
+    /// Takes the texture of the tile, freeing its atlas slot if it has one.
+    fn take_texture(&mut self) -> Texture {
+        self.atlas_rect = None;
+        self.atlas_slot = None;
+        mem::replace(&mut self.texture, Texture::zero())
+    }
+
+    fn create_texture(&mut self, display: &NativeDisplay, texture_pool: &mut TexturePool) {
         match self.buffer {
             Some(ref buffer) => {
                 // If we already have a texture it should still be valid.
@@ -75,8 +160,21 @@ impl Tile {
                     return;
                 }
 
-                // Make a new texture and bind the LayerBuffer's surface to it.
-                self.texture = Texture::new_with_buffer(buffer);
+                // Small buffers painted into memory can share the atlas texture.
+                let atlas_upload = buffer.native_surface.as_memory_buffer().and_then(|surface| {
+                    texture_pool.upload_to_atlas(surface)
+                });
+                if let Some((texture, atlas_slot, atlas_rect)) = atlas_upload {
+                    self.texture = texture;
+                    self.atlas_slot = Some(atlas_slot);
+                    self.atlas_rect = Some(atlas_rect);
+                    self.bounds = Some(Rect::from_untyped(&buffer.rect));
+                    return;
+                }
+
+                // Make a new texture, or reuse an unused one, and bind the LayerBuffer's
+                // surface to it.
+                self.texture = texture_pool.texture_for_buffer(buffer);
                 debug!("Tile: binding to native surface {}",
                        buffer.native_surface.get_id() as isize);
                 buffer.native_surface.bind_to_texture(display, &self.texture);
@@ -88,33 +186,131 @@ impl Tile {
         }
     }
 
-    fn should_request_buffer(&self, content_age: ContentAge) -> bool {
-        // Don't resend a request if our buffer's content age matches the current content age.
-        match self.buffer {
-            Some(ref buffer) => {
-                if buffer.content_age >= content_age {
+    fn should_request_buffer(&self) -> bool {
+        // Don't resend a request if our buffer is as new as the content under the tile.
+        match self.displayed_content_age() {
+            Some(displayed_content_age) => {
+                if displayed_content_age >= self.content_age {
                     return false;
                 }
             }
             None => {}
         }
 
-        // Don't resend a request, if we already have one pending.
+        // Don't resend a request, if we already have one pending that is new enough.
         match self.content_age_of_pending_buffer {
-            Some(pending_content_age) => pending_content_age != content_age,
+            Some(pending_content_age) => pending_content_age < self.content_age,
             None => true,
         }
     }
 }
 
+/// How far beyond the viewport tiles are requested, so that they are painted before they
+/// become visible.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PrepaintPolicy {
+    /// The margin around the viewport in every direction, in device pixels.
+    pub margin: f32,
+
+    /// How many seconds of scrolling to look ahead in the direction of the scroll.
+    pub velocity_lookahead: f32,
+
+    /// The largest extra margin in the direction of the scroll, in device pixels.
+    pub max_velocity_margin: f32,
+}
+
+impl PrepaintPolicy {
+    pub fn new() -> PrepaintPolicy {
+        PrepaintPolicy {
+            margin: 256.0,
+            velocity_lookahead: 0.25,
+            max_velocity_margin: 1024.0,
+        }
+    }
+
+    /// A policy that only requests tiles inside the viewport.
+    pub fn none() -> PrepaintPolicy {
+        PrepaintPolicy {
+            margin: 0.0,
+            velocity_lookahead: 0.0,
+            max_velocity_margin: 0.0,
+        }
+    }
+
+    /// Returns the margins to prepaint for the given scroll velocity, in device pixels per
+    /// second. The velocity is positive when the viewport moves towards larger coordinates
+    /// of the content, so that new content appears on the bottom and right.
+    pub fn margins(&self, scroll_velocity: &Point2D<f32>) -> PrepaintMargins {
+        let lookahead = |velocity: f32| {
+            (velocity.abs() * self.velocity_lookahead).min(self.max_velocity_margin)
+        };
+        let x = lookahead(scroll_velocity.x);
+        let y = lookahead(scroll_velocity.y);
+        PrepaintMargins {
+            top: self.margin + if scroll_velocity.y < 0.0 { y } else { 0.0 },
+            right: self.margin + if scroll_velocity.x > 0.0 { x } else { 0.0 },
+            bottom: self.margin + if scroll_velocity.y > 0.0 { y } else { 0.0 },
+            left: self.margin + if scroll_velocity.x < 0.0 { x } else { 0.0 },
+        }
+    }
+}
+
+/// The margins around the viewport that are prepainted, in device pixels.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PrepaintMargins {
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+    pub left: f32,
+}
+
+impl PrepaintMargins {
+    pub fn zero() -> PrepaintMargins {
+        PrepaintMargins {
+            top: 0.0,
+            right: 0.0,
+            bottom: 0.0,
+            left: 0.0,
+        }
+    }
+
+    pub fn inflate_rect(&self, rect: &Rect<f32>) -> Rect<f32> {
+        Rect::new(Point2D::new(rect.origin.x - self.left, rect.origin.y - self.top),
+                  Size2D::new(rect.size.width + self.left + self.right,
+                              rect.size.height + self.top + self.bottom))
+    }
+}
+
 pub struct TileGrid {
-    pub tiles: HashMap<Point2D<usize>, Tile>,
+    pub tiles: HashMap<Point2D<i32>, Tile>,
 
     /// The size of tiles in this grid in device pixels.
     tile_size: Length<DevicePixel, usize>,
 
     // Buffers that are currently unused.
     unused_buffers: Vec<Box<LayerBuffer>>,
+
+    /// The area where the displayed buffers changed since `take_damaged_rect` was last called,
+    /// in the coordinates of buffer rects.
+    damaged_rect: Option<Rect<f32>>,
+
+    /// Textures of tiles that were replaced or thrown out, to be returned to the texture pool.
+    unused_textures: Vec<Texture>,
+
+    /// The scale that tiles are currently requested at.
+    resolution: f32,
+
+    /// Tiles painted at a previous resolution. They are drawn scaled wherever the current
+    /// tiles don't have a buffer yet, and released once current tiles cover them.
+    fallback_tiles: Vec<Tile>,
+}
+
+/// Returns the distance between the closest points of two rects, which is zero if they
+/// intersect.
+fn distance_between_rects(a: &Rect<f32>, b: &Rect<f32>) -> f32 {
+    let dx = (b.min_x() - a.max_x()).max(a.min_x() - b.max_x()).max(0.0);
+    let dy = (b.min_y() - a.max_y()).max(a.min_y() - b.max_y()).max(0.0);
+    (dx * dx + dy * dy).sqrt()
 }
 
 pub fn rect_uint_as_rect_f32(rect: Rect<usize>) -> Rect<f32> {
@@ -122,32 +318,133 @@ pub fn rect_uint_as_rect_f32(rect: Rect<usize>) -> Rect<f32> {
               Size2D::new(rect.size.width as f32, rect.size.height as f32))
 }
 
+pub fn rect_int_as_rect_f32(rect: Rect<i32>) -> Rect<f32> {
+    Rect::new(Point2D::new(rect.origin.x as f32, rect.origin.y as f32),
+              Size2D::new(rect.size.width as f32, rect.size.height as f32))
+}
+
+/// The largest tile index in either direction. Coordinates beyond it, such as the edges of
+/// content with an infinite extent, are clamped to it so that they convert safely to integers.
+const MAX_TILE_INDEX: f32 = 1073741824.0;
+
+/// Converts a coordinate in units of tiles to a tile index, rounding towards negative infinity.
+fn tile_index_floor(value: f32) -> i32 {
+    value.floor().max(-MAX_TILE_INDEX).min(MAX_TILE_INDEX) as i32
+}
+
+/// Converts a coordinate in units of tiles to a tile index, rounding towards positive infinity.
+fn tile_index_ceil(value: f32) -> i32 {
+    value.ceil().max(-MAX_TILE_INDEX).min(MAX_TILE_INDEX) as i32
+}
+
+/// How far content without finite bounds is considered to extend from the layer origin when
+/// the visible tiles can't be bounded otherwise, in device pixels.
+const MAX_CLIPPED_CONTENT_EXTENT: f32 = 1048576.0;
+
+/// A range of tile indices, from `start` up to but not including `end`.
+struct TileRange {
+    start: Point2D<i32>,
+    end: Point2D<i32>,
+
+    /// Whether every tile in the range is known to overlap the rect the range was computed
+    /// for, so that tiles don't need to be projected to the screen to check. This is the case
+    /// for layers with axis-aligned 2d transforms.
+    exact: bool,
+}
+
+impl TileRange {
+    fn empty() -> TileRange {
+        TileRange {
+            start: Point2D::new(0, 0),
+            end: Point2D::new(0, 0),
+            exact: true,
+        }
+    }
+
+    /// Returns the range of tiles of `tile_size` device pixels that overlap `rect`.
+    fn for_rect(rect: &Rect<f32>, tile_size: f32, exact: bool) -> TileRange {
+        TileRange {
+            start: Point2D::new(tile_index_floor(rect.min_x() / tile_size),
+                                tile_index_floor(rect.min_y() / tile_size)),
+            end: Point2D::new(tile_index_ceil(rect.max_x() / tile_size),
+                              tile_index_ceil(rect.max_y() / tile_size)),
+            exact: exact,
+        }
+    }
+
+    fn contains(&self, tile_index: &Point2D<i32>) -> bool {
+        tile_index.x >= self.start.x && tile_index.x < self.end.x &&
+        tile_index.y >= self.start.y && tile_index.y < self.end.y
+    }
+}
+
+fn rect_is_finite(rect: &Rect<f32>) -> bool {
+    rect.min_x().is_finite() && rect.min_y().is_finite() &&
+    rect.max_x().is_finite() && rect.max_y().is_finite()
+}
+
+/// Divides `value` by `divisor`, rounding towards negative infinity.
+fn floor_div(value: i32, divisor: i32) -> i32 {
+    let quotient = value / divisor;
+    if value % divisor != 0 && (value < 0) != (divisor < 0) {
+        quotient - 1
+    } else {
+        quotient
+    }
+}
+
 impl TileGrid {
     pub fn new(tile_size: usize) -> TileGrid {
         TileGrid {
             tiles: HashMap::new(),
             tile_size: Length::new(tile_size),
             unused_buffers: Vec::new(),
+            damaged_rect: None,
+            unused_textures: Vec::new(),
+            resolution: 1.0,
+            fallback_tiles: Vec::new(),
         }
     }
 
-    pub fn get_rect_for_tile_index(&self,
-                                   tile_index: Point2D<usize>,
-                                   current_layer_size: TypedSize2D<DevicePixel, f32>)
-                                   -> TypedRect<DevicePixel, usize> {
+    fn add_damage(&mut self, rect: &Rect<f32>) {
+        self.damaged_rect = Some(match self.damaged_rect {
+            Some(damaged_rect) => damaged_rect.union(rect),
+            None => *rect,
+        });
+    }
+
+    /// Returns the area where the displayed buffers changed since `take_damaged_rect` was last
+    /// called, in the coordinates of buffer rects.
+    pub fn damaged_rect(&self) -> Option<Rect<f32>> {
+        self.damaged_rect
+    }
 
-        let origin = Point2D::new(self.tile_size.get() * tile_index.x,
-                             self.tile_size.get() * tile_index.y);
+    /// Returns the area where the displayed buffers changed since the last call, in the
+    /// coordinates of buffer rects.
+    pub fn take_damaged_rect(&mut self) -> Option<Rect<f32>> {
+        self.damaged_rect.take()
+    }
 
-        // Don't let tiles extend beyond the layer boundaries.
+    /// Returns the rect of the tile at `tile_index` in layer device pixels, clipped to
+    /// `content_rect`. Tiles at negative indices lie above and to the left of the layer origin.
+    pub fn get_rect_for_tile_index(&self,
+                                   tile_index: Point2D<i32>,
+                                   content_rect: TypedRect<DevicePixel, f32>)
+                                   -> TypedRect<DevicePixel, i32> {
         let tile_size = self.tile_size.get() as f32;
-        let size = Size2D::new(tile_size.min(current_layer_size.width.get() - origin.x as f32),
-                          tile_size.min(current_layer_size.height.get() - origin.y as f32));
+        let content_rect = content_rect.to_untyped();
+        let origin = Point2D::new(tile_index.x as f32 * tile_size,
+                                  tile_index.y as f32 * tile_size);
 
-        // Round up to texture pixels.
-        let size = Size2D::new(size.width.ceil() as usize, size.height.ceil() as usize);
+        // Don't let tiles extend beyond the content boundaries, rounding out to texture pixels.
+        let min_x = origin.x.max(content_rect.min_x().floor());
+        let min_y = origin.y.max(content_rect.min_y().floor());
+        let max_x = (origin.x + tile_size).min(content_rect.max_x().ceil()).max(min_x);
+        let max_y = (origin.y + tile_size).min(content_rect.max_y().ceil()).max(min_y);
 
-        Rect::from_untyped(&Rect::new(origin, size))
+        Rect::from_untyped(&Rect::new(Point2D::new(min_x as i32, min_y as i32),
+                                      Size2D::new((max_x - min_x) as i32,
+                                                  (max_y - min_y) as i32)))
     }
 
     pub fn take_unused_buffers(&mut self) -> Vec<Box<LayerBuffer>> {
@@ -163,60 +460,98 @@ impl TileGrid {
         }
     }
 
+    /// Throws out a tile that is no longer displayed, keeping its buffer and texture for reuse.
+    fn discard_tile(&mut self, mut tile: Tile) {
+        if let Some(rect) = tile.content_rect() {
+            self.add_damage(&rect);
+        }
+        self.add_unused_buffer(tile.buffer.take());
+        self.add_unused_texture(tile.take_texture());
+    }
+
+    fn add_unused_texture(&mut self, texture: Texture) {
+        if !texture.is_zero() {
+            self.unused_textures.push(texture);
+        }
+    }
+
+    /// Returns the bounding rect of the tile at `tile_index` on the screen, or `None` if the
+    /// tile is behind the viewer.
+    fn tile_screen_rect(&self,
+                        tile_index: &Point2D<i32>,
+                        content_rect: TypedRect<DevicePixel, f32>,
+                        layer_world_origin: &Point2D<f32>,
+                        layer_transform: &Matrix4) -> Option<Rect<f32>> {
+        let tile_rect = self.get_rect_for_tile_index(*tile_index, content_rect);
+        let tile_rect = rect_int_as_rect_f32(tile_rect.to_untyped()).translate(layer_world_origin);
+
+        project_rect_to_screen(&tile_rect, layer_transform).map(|screen_rect| screen_rect.rect)
+    }
+
     pub fn tile_intersects_rect(&self,
-                                tile_index: &Point2D<usize>,
+                                tile_index: &Point2D<i32>,
                                 test_rect: &Rect<f32>,
-                                current_layer_size: TypedSize2D<DevicePixel, f32>,
+                                content_rect: TypedRect<DevicePixel, f32>,
                                 layer_world_origin: &Point2D<f32>,
                                 layer_transform: &Matrix4) -> bool {
-        let tile_rect = self.get_rect_for_tile_index(*tile_index,
-                                                     current_layer_size);
-        let tile_rect = tile_rect.as_f32()
-                                 .to_untyped()
-                                 .translate(layer_world_origin);
-
-        let screen_rect = project_rect_to_screen(&tile_rect, layer_transform);
+        match self.tile_screen_rect(tile_index,
+                                    content_rect,
+                                    layer_world_origin,
+                                    layer_transform) {
+            Some(screen_rect) => screen_rect.intersection(&test_rect).is_some(),
+            None => false,
+        }
+    }
 
-        if let Some(screen_rect) = screen_rect {
-            if screen_rect.rect.intersection(&test_rect).is_some() {
-                return true;
+    /// Forgets that a buffer was requested for a tile, so that it's requested again. This is
+    /// used for requests that were dropped before reaching the painter.
+    pub fn cancel_buffer_request(&mut self, request: &BufferRequest) {
+        let index = self.get_tile_index_for_point(request.screen_rect.origin);
+        if let Some(tile) = self.tiles.get_mut(&index) {
+            if tile.content_age_of_pending_buffer == Some(request.content_age) {
+                tile.content_age_of_pending_buffer = None;
             }
         }
-
-        false
     }
 
     pub fn mark_tiles_outside_of_rect_as_unused(&mut self,
                                                 rect: TypedRect<DevicePixel, f32>,
                                                 layer_world_origin: &Point2D<f32>,
                                                 layer_transform: &Matrix4,
-                                                current_layer_size: TypedSize2D<DevicePixel, f32>) {
+                                                content_rect: TypedRect<DevicePixel, f32>) {
         let mut tile_indexes_to_take = Vec::new();
 
+        // Tiles outside of the range can't be visible. Those inside it only need to be
+        // projected to the screen if the range isn't exact.
+        let tile_range = self.visible_tile_range(&rect.to_untyped(),
+                                                 &content_rect.to_untyped(),
+                                                 layer_world_origin,
+                                                 layer_transform);
         for tile_index in self.tiles.keys() {
-            if !self.tile_intersects_rect(tile_index,
-                                          &rect.to_untyped(),
-                                          current_layer_size,
-                                          layer_world_origin,
-                                          layer_transform) {
+            if !tile_range.contains(tile_index) ||
+               (!tile_range.exact && !self.tile_intersects_rect(tile_index,
+                                                                &rect.to_untyped(),
+                                                                content_rect,
+                                                                layer_world_origin,
+                                                                layer_transform)) {
                 tile_indexes_to_take.push(tile_index.clone());
             }
         }
 
         for tile_index in tile_indexes_to_take.iter() {
             match self.tiles.remove(tile_index) {
-                Some(ref mut tile) => self.add_unused_buffer(tile.buffer.take()),
+                Some(tile) => self.discard_tile(tile),
                 None => {},
             }
         }
     }
 
     pub fn get_buffer_request_for_tile(&mut self,
-                                       tile_index: Point2D<usize>,
-                                       current_layer_size: TypedSize2D<DevicePixel, f32>,
+                                       tile_index: Point2D<i32>,
+                                       content_rect: TypedRect<DevicePixel, f32>,
                                        current_content_age: ContentAge)
                                        -> Option<BufferRequest> {
-        let tile_rect = self.get_rect_for_tile_index(tile_index, current_layer_size);
+        let tile_rect = self.get_rect_for_tile_index(tile_index, content_rect);
         let tile = match self.tiles.entry(tile_index) {
             Entry::Occupied(occupied) => occupied.into_mut(),
             Entry::Vacant(vacant) => vacant.insert(Tile::new()),
@@ -226,70 +561,365 @@ impl TileGrid {
             return None;
         }
 
-        if !tile.should_request_buffer(current_content_age) {
+        if !tile.should_request_buffer() {
             return None;
         }
 
         tile.content_age_of_pending_buffer = Some(current_content_age);
 
-        return Some(BufferRequest::new(tile_rect.to_untyped(),
-                                       tile_rect.as_f32().to_untyped(),
-                                       current_content_age));
+        let mut request = BufferRequest::new(tile_rect.to_untyped(),
+                                             rect_int_as_rect_f32(tile_rect.to_untyped()),
+                                             current_content_age);
+        request.resolution = self.resolution;
+        return Some(request);
     }
 
     /// Returns buffer requests inside the given dirty rect, and simultaneously throws out tiles
-    /// outside the given viewport rect.
+    /// outside the given viewport rect. Both rects are grown by the prepaint margins first, so
+    /// that tiles are ready before they scroll into view.
     pub fn get_buffer_requests_in_rect(&mut self,
                                        dirty_rect: TypedRect<DevicePixel, f32>,
                                        viewport: TypedRect<DevicePixel, f32>,
-                                       current_layer_size: TypedSize2D<DevicePixel, f32>,
+                                       content_rect: TypedRect<DevicePixel, f32>,
                                        layer_world_origin: &Point2D<f32>,
                                        layer_transform: &Matrix4,
-                                       current_content_age: ContentAge)
+                                       current_content_age: ContentAge,
+                                       resolution: f32,
+                                       prepaint_margins: &PrepaintMargins,
+                                       frame_number: u64)
                                        -> Vec<BufferRequest> {
         let mut buffer_requests = Vec::new();
 
-        // Get the range of tiles that can fit into the current layer size.
-        // Step through each, transform/clip them to 2d rect
-        // Check if visible against rect
+        let dirty_rect = prepaint_margins.inflate_rect(&dirty_rect.to_untyped());
+        let prepaint_viewport: TypedRect<DevicePixel, f32> =
+            Rect::from_untyped(&prepaint_margins.inflate_rect(&viewport.to_untyped()));
 
-        let tile_size = self.tile_size.get() as f32;
-        let x_tile_count = ((current_layer_size.to_untyped().width + tile_size - 1.0) / tile_size) as usize;
-        let y_tile_count = ((current_layer_size.to_untyped().height + tile_size - 1.0) / tile_size) as usize;
+        if resolution != self.resolution {
+            self.change_resolution(resolution);
+        }
 
-        for x in 0..x_tile_count {
-            for y in 0..y_tile_count {
+        // Only step through the tiles of the content that may overlap the dirty rect, then
+        // transform/clip each of them to a 2d rect and check if it's visible against the rect.
+        let tile_range = self.visible_tile_range(&dirty_rect,
+                                                 &content_rect.to_untyped(),
+                                                 layer_world_origin,
+                                                 layer_transform);
+
+        for x in tile_range.start.x..tile_range.end.x {
+            for y in tile_range.start.y..tile_range.end.y {
                 let tile_index = Point2D::new(x, y);
-                if self.tile_intersects_rect(&tile_index,
-                                             &dirty_rect.to_untyped(),
-                                             current_layer_size,
-                                             layer_world_origin,
-                                             layer_transform) {
-                    if let Some(buffer) = self.get_buffer_request_for_tile(tile_index,
-                                                                           current_layer_size,
+                let screen_rect = match self.tile_screen_rect(&tile_index,
+                                                              content_rect,
+                                                              layer_world_origin,
+                                                              layer_transform) {
+                    Some(screen_rect) => screen_rect,
+                    None => continue,
+                };
+                if !screen_rect.intersects(&dirty_rect) {
+                    continue;
+                }
+
+                if let Some(mut buffer) = self.get_buffer_request_for_tile(tile_index,
+                                                                           content_rect,
                                                                            current_content_age) {
-                        buffer_requests.push(buffer);
-                    }
+                    let distance = distance_between_rects(&screen_rect, &viewport.to_untyped());
+                    buffer.priority = if distance > 0.0 {
+                        BufferRequestPriority::Prefetch
+                    } else if self.tile_has_fallback(&tile_index) {
+                        BufferRequestPriority::LowResolutionFallback
+                    } else {
+                        BufferRequestPriority::Visible
+                    };
+                    buffer.distance_to_viewport = distance;
+                    buffer_requests.push(buffer);
                 }
             }
         }
 
-        self.mark_tiles_outside_of_rect_as_unused(viewport,
+        self.mark_tiles_outside_of_rect_as_unused(prepaint_viewport,
                                                   layer_world_origin,
                                                   layer_transform,
-                                                  current_layer_size);
+                                                  content_rect);
+        self.release_fallback_tiles(Some((&prepaint_viewport.to_untyped(),
+                                          layer_world_origin,
+                                          layer_transform)));
+
+        let viewport_tile_range = self.visible_tile_range(&viewport.to_untyped(),
+                                                          &content_rect.to_untyped(),
+                                                          layer_world_origin,
+                                                          layer_transform);
+        let visible_tile_indexes: Vec<Point2D<i32>> = self.tiles.keys().filter(|tile_index| {
+            viewport_tile_range.contains(tile_index) &&
+            (viewport_tile_range.exact ||
+             self.tile_intersects_rect(tile_index,
+                                       &viewport.to_untyped(),
+                                       content_rect,
+                                       layer_world_origin,
+                                       layer_transform))
+        }).cloned().collect();
+        for tile_index in visible_tile_indexes.iter() {
+            if let Some(tile) = self.tiles.get_mut(tile_index) {
+                tile.last_visible_frame = frame_number;
+            }
+        }
 
         return buffer_requests;
     }
 
-    pub fn get_tile_index_for_point(&self, point: Point2D<usize>) -> Point2D<usize> {
-        assert!(point.x % self.tile_size.get() == 0);
-        assert!(point.y % self.tile_size.get() == 0);
-        Point2D::new((point.x / self.tile_size.get()) as usize,
-                     (point.y / self.tile_size.get()) as usize)
+    /// Returns the range of tiles of `content_rect` that may be visible in `screen_rect`,
+    /// computed from the transform of the layer rather than by projecting each tile. Layers
+    /// with 2d transforms have the screen rect mapped back through the inverse transform, and
+    /// layers with 3d transforms have the content clipped to the screen rect on the layer
+    /// plane, so that the cost follows the number of visible tiles.
+    fn visible_tile_range(&self,
+                          screen_rect: &Rect<f32>,
+                          content_rect: &Rect<f32>,
+                          layer_world_origin: &Point2D<f32>,
+                          layer_transform: &Matrix4)
+                          -> TileRange {
+        let m = layer_transform;
+        let tile_size = self.tile_size.get() as f32;
+
+        // See https://drafts.csswg.org/css-transforms/#2d-matrix
+        let is_3d_transform = m.m31 != 0.0 || m.m32 != 0.0 ||
+                              m.m13 != 0.0 || m.m23 != 0.0 ||
+                              m.m43 != 0.0 || m.m14 != 0.0 ||
+                              m.m24 != 0.0 || m.m34 != 0.0 ||
+                              m.m33 != 1.0 || m.m44 != 1.0;
+
+        if !is_3d_transform {
+            // A layer seen edge-on covers no area on the screen.
+            if (m.m11 * m.m22 - m.m12 * m.m21).abs() < 1.0e-6 {
+                return TileRange::empty();
+            }
+
+            let inverse = m.invert();
+            let inverse_2d = Matrix2D::new(inverse.m11, inverse.m12,
+                                           inverse.m21, inverse.m22,
+                                           inverse.m41, inverse.m42);
+            let layer_rect = inverse_2d.transform_rect(screen_rect)
+                                       .translate(&Point2D::new(-layer_world_origin.x,
+                                                                -layer_world_origin.y));
+            let is_axis_aligned = m.m12 == 0.0 && m.m21 == 0.0;
+            return match layer_rect.intersection(content_rect) {
+                Some(rect) => TileRange::for_rect(&rect, tile_size, is_axis_aligned),
+                None => TileRange::empty(),
+            };
+        }
+
+        // Clipping needs a finite polygon, so bound infinite content by the part of the layer
+        // plane under the corners of the screen rect if they are all in front of the viewer.
+        let mut content_rect = *content_rect;
+        if !rect_is_finite(&content_rect) {
+            let corners = rect_to_polygon(screen_rect);
+            let layer_corners: Vec<Point2D<f32>> = corners.iter().filter_map(|corner| {
+                unproject_point_to_plane(corner, layer_transform).map(|(point, _)| {
+                    point - *layer_world_origin
+                })
+            }).collect();
+            let bounds = if layer_corners.len() == corners.len() {
+                polygon_bounding_rect(&layer_corners)
+            } else {
+                Rect::new(Point2D::new(-MAX_CLIPPED_CONTENT_EXTENT, -MAX_CLIPPED_CONTENT_EXTENT),
+                          Size2D::new(2.0 * MAX_CLIPPED_CONTENT_EXTENT,
+                                      2.0 * MAX_CLIPPED_CONTENT_EXTENT))
+            };
+            content_rect = match content_rect.intersection(&bounds) {
+                Some(rect) => rect,
+                None => return TileRange::empty(),
+            };
+        }
+
+        let content_polygon = rect_to_polygon(&content_rect.translate(layer_world_origin));
+        let visible_polygon = clip_layer_polygon_to_screen_polygon(&content_polygon,
+                                                                   layer_transform,
+                                                                   &rect_to_polygon(screen_rect));
+        if visible_polygon.is_empty() {
+            return TileRange::empty();
+        }
+
+        let visible_rect = polygon_bounding_rect(&visible_polygon);
+        let visible_rect = visible_rect.translate(&Point2D::new(-layer_world_origin.x,
+                                                                -layer_world_origin.y));
+        TileRange::for_rect(&visible_rect, tile_size, false)
+    }
+
+    /// Marks the tiles overlapping `rect`, in the coordinates of buffer rects, as needing a
+    /// buffer of at least `content_age`. Other tiles keep their buffers.
+    pub fn invalidate_rect(&mut self, rect: &Rect<f32>, content_age: ContentAge) {
+        let (start, end) = self.tile_index_range_for_rect(rect);
+        for (tile_index, tile) in self.tiles.iter_mut() {
+            if tile_index.x >= start.x && tile_index.x < end.x &&
+               tile_index.y >= start.y && tile_index.y < end.y {
+                tile.content_age = content_age;
+            }
+        }
+    }
+
+    /// Marks all tiles as needing a buffer of at least `content_age`.
+    pub fn invalidate_all(&mut self, content_age: ContentAge) {
+        for tile in self.tiles.values_mut() {
+            tile.content_age = content_age;
+        }
+    }
+
+    /// Returns the index, last visible frame and memory usage of each tile with a buffer that
+    /// wasn't visible in `frame_number`.
+    pub fn evictable_tiles(&self, frame_number: u64) -> Vec<(Point2D<i32>, u64, usize)> {
+        self.tiles.iter().filter_map(|(tile_index, tile)| {
+            match tile.buffer {
+                Some(ref buffer) if tile.last_visible_frame < frame_number => {
+                    Some((*tile_index, tile.last_visible_frame, buffer.get_mem()))
+                }
+                _ => None,
+            }
+        }).collect()
+    }
+
+    /// Throws out the tile at `tile_index`, moving its buffer to the unused buffers. The tile
+    /// is requested again once it is needed.
+    pub fn evict_tile(&mut self, tile_index: &Point2D<i32>) {
+        if let Some(tile) = self.tiles.remove(tile_index) {
+            self.discard_tile(tile);
+        }
+    }
+
+    /// Throws out all tiles kept from a previous resolution, moving their buffers to the
+    /// unused buffers. Returns the memory that their buffers used.
+    pub fn evict_fallback_tiles(&mut self) -> usize {
+        let mut fallback_tiles = Vec::new();
+        mem::swap(&mut fallback_tiles, &mut self.fallback_tiles);
+
+        let mut memory_usage = 0;
+        for tile in fallback_tiles.into_iter() {
+            if let Some(ref buffer) = tile.buffer {
+                memory_usage += buffer.get_mem();
+            }
+            self.discard_tile(tile);
+        }
+        memory_usage
+    }
+
+    /// Starts requesting tiles at a new resolution. The tiles painted so far are kept as
+    /// fallbacks until tiles at the new resolution replace them.
+    fn change_resolution(&mut self, resolution: f32) {
+        let mut tiles = HashMap::new();
+        mem::swap(&mut tiles, &mut self.tiles);
+        let new_fallback_tiles: Vec<Tile> =
+            tiles.into_iter().map(|(_, tile)| tile).filter(|tile| tile.has_content()).collect();
+
+        // Fallback tiles must not overlap, so older ones give way to the newer ones.
+        let mut old_fallback_tiles = Vec::new();
+        mem::swap(&mut old_fallback_tiles, &mut self.fallback_tiles);
+        for tile in old_fallback_tiles.into_iter() {
+            let rect = match tile.content_rect() {
+                Some(rect) => rect,
+                None => continue,
+            };
+            let overlaps = new_fallback_tiles.iter().any(|new_tile| {
+                new_tile.content_rect().map_or(false, |new_rect| new_rect.intersects(&rect))
+            });
+            if overlaps {
+                self.discard_tile(tile);
+            } else {
+                self.fallback_tiles.push(tile);
+            }
+        }
+
+        self.fallback_tiles.extend(new_fallback_tiles.into_iter());
+        self.resolution = resolution;
+    }
+
+    /// Returns the range of indices of the current tiles that overlap `rect`, in the
+    /// coordinates of buffer rects, as the first index and one past the last one.
+    fn tile_index_range_for_rect(&self, rect: &Rect<f32>) -> (Point2D<i32>, Point2D<i32>) {
+        let tile_size = self.tile_size.get() as f32 / self.resolution;
+        let range = TileRange::for_rect(rect, tile_size, false);
+        (range.start, range.end)
+    }
+
+    /// Returns the rect of the current tile at `tile_index`, in the coordinates of buffer
+    /// rects. It isn't clipped to the layer boundaries.
+    fn rect_for_tile_index(&self, tile_index: &Point2D<i32>) -> Rect<f32> {
+        let tile_size = self.tile_size.get() as f32 / self.resolution;
+        Rect::new(Point2D::new(tile_index.x as f32 * tile_size, tile_index.y as f32 * tile_size),
+                  Size2D::new(tile_size, tile_size))
+    }
+
+    /// Whether the current tile at `tile_index` is waiting for its first buffer.
+    fn tile_needs_fallback(&self, tile_index: &Point2D<i32>) -> bool {
+        match self.tiles.get(tile_index) {
+            Some(tile) => !tile.has_content(),
+            None => false,
+        }
+    }
+
+    /// Whether a fallback tile is drawn in place of the current tile at `tile_index`.
+    fn tile_has_fallback(&self, tile_index: &Point2D<i32>) -> bool {
+        let tile_rect = self.rect_for_tile_index(tile_index);
+        self.fallback_tiles.iter().any(|tile| {
+            tile.content_rect().map_or(false, |rect| rect.intersects(&tile_rect))
+        })
+    }
+
+    /// Releases the fallback tiles that are no longer needed, either because current tiles
+    /// have buffers everywhere they overlap, or because they are outside of `viewport` (in
+    /// device pixels, along with the layer origin and transform).
+    fn release_fallback_tiles(&mut self,
+                              viewport: Option<(&Rect<f32>, &Point2D<f32>, &Matrix4)>) {
+        if self.fallback_tiles.is_empty() {
+            return;
+        }
+
+        let mut fallback_tiles = Vec::new();
+        mem::swap(&mut fallback_tiles, &mut self.fallback_tiles);
+        for tile in fallback_tiles.into_iter() {
+            let rect = match tile.content_rect() {
+                Some(rect) => rect,
+                None => continue,
+            };
+
+            let visible = viewport.map_or(true, |(viewport, layer_world_origin, transform)| {
+                let device_rect = Rect::new(Point2D::new(rect.origin.x * self.resolution,
+                                                         rect.origin.y * self.resolution),
+                                            Size2D::new(rect.size.width * self.resolution,
+                                                        rect.size.height * self.resolution));
+                let device_rect = device_rect.translate(layer_world_origin);
+                match project_rect_to_screen(&device_rect, transform) {
+                    Some(screen_rect) => screen_rect.rect.intersects(viewport),
+                    None => false,
+                }
+            });
+
+            let (start, end) = self.tile_index_range_for_rect(&rect);
+            let mut needed = false;
+            for x in start.x..end.x {
+                for y in start.y..end.y {
+                    needed = needed || self.tile_needs_fallback(&Point2D::new(x, y));
+                }
+            }
+
+            if visible && needed {
+                self.fallback_tiles.push(tile);
+            } else {
+                self.discard_tile(tile);
+            }
+        }
+    }
+
+    /// Returns the index of the tile containing `point`, in layer device pixels.
+    pub fn get_tile_index_for_point(&self, point: Point2D<i32>) -> Point2D<i32> {
+        let tile_size = self.tile_size.get() as i32;
+        Point2D::new(floor_div(point.x, tile_size), floor_div(point.y, tile_size))
     }
 
     pub fn add_buffer(&mut self, buffer: Box<LayerBuffer>) {
+        // Buffers requested before the resolution changed don't fit the current tiles.
+        if !buffer.is_valid(self.resolution) {
+            self.add_unused_buffer(Some(buffer));
+            return;
+        }
+
         let index = self.get_tile_index_for_point(buffer.screen_pos.origin.clone());
         if !self.tiles.contains_key(&index) {
             warn!("Received buffer for non-existent tile!");
@@ -297,8 +927,44 @@ impl TileGrid {
             return;
         }
 
-        let replaced_buffer = self.tiles.get_mut(&index).unwrap().replace_buffer(buffer);
+        if self.tiles[&index].should_use_new_content(buffer.content_age) {
+            self.add_damage(&buffer.rect);
+            if let Some(rect) = self.tiles[&index].content_rect() {
+                self.add_damage(&rect);
+            }
+        }
+
+        let (replaced_buffer, replaced_texture) =
+            self.tiles.get_mut(&index).unwrap().replace_buffer(buffer);
         self.add_unused_buffer(replaced_buffer);
+        self.add_unused_texture(replaced_texture);
+        self.release_fallback_tiles(None);
+    }
+
+    /// Stores a tile that the painter answered with a single color, in place of a buffer.
+    pub fn add_solid_color_tile(&mut self, solid_color: SolidColorTile) {
+        if !solid_color.is_valid(self.resolution) {
+            return;
+        }
+
+        let index = self.get_tile_index_for_point(solid_color.screen_pos.origin.clone());
+        if !self.tiles.contains_key(&index) {
+            warn!("Received solid color for non-existent tile!");
+            return;
+        }
+
+        if self.tiles[&index].should_use_new_content(solid_color.content_age) {
+            self.add_damage(&solid_color.rect);
+            if let Some(rect) = self.tiles[&index].content_rect() {
+                self.add_damage(&rect);
+            }
+        }
+
+        let (replaced_buffer, replaced_texture) =
+            self.tiles.get_mut(&index).unwrap().replace_with_solid_color(solid_color);
+        self.add_unused_buffer(replaced_buffer);
+        self.add_unused_texture(replaced_texture);
+        self.release_fallback_tiles(None);
     }
 
     pub fn do_for_all_tiles<F>(&self, mut f: F) where F: FnMut(&Tile) {
@@ -307,6 +973,42 @@ impl TileGrid {
         }
     }
 
+    /// Calls `f` with the rect of each tile that has neither a buffer nor a fallback tile to
+    /// draw in its place, in the coordinates of buffer rects. The rects aren't clipped to the
+    /// layer boundaries.
+    pub fn do_for_all_missing_tiles<F>(&self, mut f: F) where F: FnMut(&Rect<f32>) {
+        for (tile_index, tile) in self.tiles.iter() {
+            if !tile.has_content() && !self.tile_has_fallback(tile_index) {
+                f(&self.rect_for_tile_index(tile_index));
+            }
+        }
+    }
+
+    /// Calls `f` for each fallback tile along with each part of it that should be drawn, in
+    /// the coordinates of buffer rects. These are the current tiles that have no buffer yet.
+    pub fn do_for_all_fallback_tiles<F>(&self, mut f: F) where F: FnMut(&Tile, &Rect<f32>) {
+        for tile in self.fallback_tiles.iter() {
+            let rect = match tile.content_rect() {
+                Some(rect) => rect,
+                None => continue,
+            };
+
+            let (start, end) = self.tile_index_range_for_rect(&rect);
+            for x in start.x..end.x {
+                for y in start.y..end.y {
+                    let tile_index = Point2D::new(x, y);
+                    if !self.tile_needs_fallback(&tile_index) {
+                        continue;
+                    }
+                    if let Some(clip_rect) = self.rect_for_tile_index(&tile_index)
+                                                 .intersection(&rect) {
+                        f(tile, &clip_rect);
+                    }
+                }
+            }
+        }
+    }
+
     pub fn collect_buffers(&mut self) -> Vec<Box<LayerBuffer>> {
         let mut collected_buffers = Vec::new();
 
@@ -316,7 +1018,13 @@ impl TileGrid {
         let mut tile_map = HashMap::new();
         mem::swap(&mut tile_map, &mut self.tiles);
 
-        for (_, mut tile) in tile_map.into_iter() {
+        let mut fallback_tiles = Vec::new();
+        mem::swap(&mut fallback_tiles, &mut self.fallback_tiles);
+
+        for mut tile in tile_map.into_iter().map(|(_, tile)| tile).chain(fallback_tiles) {
+            if let Some(rect) = tile.content_rect() {
+                self.add_damage(&rect);
+            }
             match tile.buffer.take() {
                 Some(buffer) => collected_buffers.push(buffer),
                 None => {},
@@ -326,16 +1034,25 @@ impl TileGrid {
         return collected_buffers;
     }
 
-    pub fn create_textures(&mut self, display: &NativeDisplay) {
+    /// Creates textures for the tiles that don't have one yet, after returning the textures
+    /// that are no longer used to `texture_pool`.
+    pub fn create_textures(&mut self, display: &NativeDisplay, texture_pool: &mut TexturePool) {
+        for texture in mem::replace(&mut self.unused_textures, Vec::new()).into_iter() {
+            texture_pool.recycle(texture);
+        }
+
         for (_, ref mut tile) in self.tiles.iter_mut() {
-            tile.create_texture(display);
+            tile.create_texture(display, texture_pool);
+        }
+        for tile in self.fallback_tiles.iter_mut() {
+            tile.create_texture(display, texture_pool);
         }
     }
 
     /// Calculate the amount of memory used by all the tiles in the
     /// tile grid. The memory may be allocated on the heap or in GPU memory.
     pub fn get_memory_usage(&self) -> usize {
-        self.tiles.values().map(|ref tile| {
+        self.tiles.values().chain(self.fallback_tiles.iter()).map(|ref tile| {
             // We cannot use Option::map_or here because rust will
             // complain about moving out of borrowed content.
             match tile.buffer {
@@ -345,3 +1062,172 @@ impl TileGrid {
         }).sum()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{floor_div, MAX_TILE_INDEX, Tile, TileGrid, TileRange};
+    use geometry::DevicePixel;
+    use layers::{ContentAge, LayerBuffer};
+    use platform::surface::{MemoryBufferNativeSurface, NativeDisplay, NativeSurface};
+
+    use euclid::matrix::Matrix4;
+    use euclid::point::Point2D;
+    use euclid::rect::{Rect, TypedRect};
+    use euclid::size::Size2D;
+    use std::f32;
+    #[cfg(target_os="linux")]
+    use std::ptr;
+
+    fn device_rect(x: f32, y: f32, width: f32, height: f32) -> TypedRect<DevicePixel, f32> {
+        Rect::from_untyped(&Rect::new(Point2D::new(x, y), Size2D::new(width, height)))
+    }
+
+    #[cfg(target_os="linux")]
+    fn native_display() -> NativeDisplay {
+        NativeDisplay::new(ptr::null_mut())
+    }
+
+    #[cfg(not(target_os="linux"))]
+    fn native_display() -> NativeDisplay {
+        NativeDisplay::new()
+    }
+
+    fn memory_buffer(screen_rect: Rect<i32>, resolution: f32) -> Box<LayerBuffer> {
+        let surface = MemoryBufferNativeSurface::new(&native_display(), screen_rect.size);
+        let rect = Rect::new(Point2D::new(screen_rect.origin.x as f32 / resolution,
+                                          screen_rect.origin.y as f32 / resolution),
+                             Size2D::new(screen_rect.size.width as f32 / resolution,
+                                         screen_rect.size.height as f32 / resolution));
+        Box::new(LayerBuffer {
+            native_surface: NativeSurface::MemoryBuffer(surface),
+            rect: rect,
+            screen_pos: screen_rect,
+            resolution: resolution,
+            painted_with_cpu: true,
+            content_age: ContentAge::new(),
+        })
+    }
+
+    #[test]
+    fn floor_div_rounds_towards_negative_infinity() {
+        assert_eq!(floor_div(0, 4), 0);
+        assert_eq!(floor_div(7, 4), 1);
+        assert_eq!(floor_div(8, 4), 2);
+        assert_eq!(floor_div(-1, 4), -1);
+        assert_eq!(floor_div(-4, 4), -1);
+        assert_eq!(floor_div(-5, 4), -2);
+    }
+
+    #[test]
+    fn tile_index_for_point_above_and_left_of_origin() {
+        let tile_grid = TileGrid::new(256);
+        assert_eq!(tile_grid.get_tile_index_for_point(Point2D::new(0, 255)), Point2D::new(0, 0));
+        assert_eq!(tile_grid.get_tile_index_for_point(Point2D::new(-1, 256)),
+                   Point2D::new(-1, 1));
+        assert_eq!(tile_grid.get_tile_index_for_point(Point2D::new(-256, -257)),
+                   Point2D::new(-1, -2));
+    }
+
+    #[test]
+    fn tile_rects_are_clipped_to_content() {
+        let tile_grid = TileGrid::new(256);
+        let content_rect = device_rect(-100.0, 0.0, 300.0, 600.0);
+
+        let rect = tile_grid.get_rect_for_tile_index(Point2D::new(-1, 0), content_rect);
+        assert_eq!(rect.to_untyped(), Rect::new(Point2D::new(-100, 0), Size2D::new(100, 256)));
+
+        let rect = tile_grid.get_rect_for_tile_index(Point2D::new(0, 2), content_rect);
+        assert_eq!(rect.to_untyped(), Rect::new(Point2D::new(0, 512), Size2D::new(200, 88)));
+
+        let rect = tile_grid.get_rect_for_tile_index(Point2D::new(1, 0), content_rect);
+        assert!(rect.to_untyped().is_empty());
+    }
+
+    #[test]
+    fn tile_rects_of_infinite_content() {
+        let tile_grid = TileGrid::new(256);
+        let content_rect = device_rect(0.0, 0.0, f32::INFINITY, f32::INFINITY);
+        let rect = tile_grid.get_rect_for_tile_index(Point2D::new(3, 4), content_rect);
+        assert_eq!(rect.to_untyped(), Rect::new(Point2D::new(768, 1024), Size2D::new(256, 256)));
+    }
+
+    #[test]
+    fn tile_range_for_rect() {
+        let rect = Rect::new(Point2D::new(-10.0, 0.0), Size2D::new(266.0, 257.0));
+        let range = TileRange::for_rect(&rect, 256.0, true);
+        assert_eq!(range.start, Point2D::new(-1, 0));
+        assert_eq!(range.end, Point2D::new(1, 2));
+        assert!(range.contains(&Point2D::new(-1, 1)));
+        assert!(!range.contains(&Point2D::new(1, 0)));
+
+        let infinite_rect = Rect::new(Point2D::new(0.0, 0.0),
+                                      Size2D::new(f32::INFINITY, f32::INFINITY));
+        let range = TileRange::for_rect(&infinite_rect, 256.0, true);
+        assert_eq!(range.start, Point2D::new(0, 0));
+        assert_eq!(range.end, Point2D::new(MAX_TILE_INDEX as i32, MAX_TILE_INDEX as i32));
+    }
+
+    #[test]
+    fn visible_tile_range_of_scrolled_layer() {
+        let tile_grid = TileGrid::new(256);
+        let screen_rect = Rect::new(Point2D::new(0.0, 0.0), Size2D::new(100.0, 100.0));
+        let content_rect = Rect::new(Point2D::new(0.0, 0.0), Size2D::new(1000.0, 1000.0));
+        let transform = Matrix4::identity().translate(-300.0, -300.0, 0.0);
+
+        let range = tile_grid.visible_tile_range(&screen_rect,
+                                                 &content_rect,
+                                                 &Point2D::zero(),
+                                                 &transform);
+        assert_eq!(range.start, Point2D::new(1, 1));
+        assert_eq!(range.end, Point2D::new(2, 2));
+        assert!(range.exact);
+    }
+
+    #[test]
+    fn visible_tile_range_of_edge_on_layer_is_empty() {
+        let tile_grid = TileGrid::new(256);
+        let screen_rect = Rect::new(Point2D::new(0.0, 0.0), Size2D::new(100.0, 100.0));
+        let content_rect = Rect::new(Point2D::new(0.0, 0.0), Size2D::new(1000.0, 1000.0));
+        let transform = Matrix4::identity().scale(0.0, 1.0, 1.0);
+
+        let range = tile_grid.visible_tile_range(&screen_rect,
+                                                 &content_rect,
+                                                 &Point2D::zero(),
+                                                 &transform);
+        assert_eq!(range.start, range.end);
+    }
+
+    #[test]
+    fn visible_tile_range_of_infinite_content_in_3d() {
+        let tile_grid = TileGrid::new(256);
+        let screen_rect = Rect::new(Point2D::new(0.0, 0.0), Size2D::new(100.0, 600.0));
+        let content_rect = Rect::new(Point2D::new(0.0, 0.0),
+                                     Size2D::new(f32::INFINITY, f32::INFINITY));
+        let transform = Matrix4::identity().scale(1.0, 1.0, 2.0);
+
+        let range = tile_grid.visible_tile_range(&screen_rect,
+                                                 &content_rect,
+                                                 &Point2D::zero(),
+                                                 &transform);
+        assert_eq!(range.start, Point2D::new(0, 0));
+        assert_eq!(range.end, Point2D::new(1, 3));
+        assert!(!range.exact);
+    }
+
+    #[test]
+    fn fallback_tiles_count_towards_memory_usage_and_can_be_evicted() {
+        let mut tile_grid = TileGrid::new(256);
+        tile_grid.tiles.insert(Point2D::new(0, 0), Tile::new());
+        tile_grid.add_buffer(memory_buffer(Rect::new(Point2D::new(0, 0), Size2D::new(256, 256)),
+                                           1.0));
+        tile_grid.change_resolution(2.0);
+
+        // The old tile is now a fallback, which isn't among the current tiles.
+        assert_eq!(tile_grid.get_memory_usage(), 256 * 256);
+        assert!(tile_grid.evictable_tiles(1).is_empty());
+
+        assert_eq!(tile_grid.evict_fallback_tiles(), 256 * 256);
+        assert_eq!(tile_grid.get_memory_usage(), 0);
+        assert_eq!(tile_grid.take_unused_buffers().len(), 1);
+    }
+}
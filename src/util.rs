@@ -162,3 +162,266 @@ pub fn project_rect_to_screen(rect: &Rect<f32>,
 
     result
 }
+
+/// Returns the largest factor by which `transform` scales lengths on the z = 0 plane of a layer,
+/// ignoring perspective.
+pub fn transform_scale_factor(transform: &Matrix4) -> f32 {
+    let m = transform;
+    let x_scale = (m.m11 * m.m11 + m.m12 * m.m12).sqrt();
+    let y_scale = (m.m21 * m.m21 + m.m22 * m.m22).sqrt();
+    x_scale.max(y_scale)
+}
+
+/// Finds the point on the z = 0 plane of a layer which `transform` projects onto `screen_point`.
+/// This is the inverse of projecting a layer point to the screen, and is needed because a
+/// perspective transform can't simply be inverted in 2d. Returns the point in layer space along
+/// with its depth after the perspective divide, or `None` if the plane is seen edge-on or the
+/// point lies behind the near plane.
+pub fn unproject_point_to_plane(screen_point: &Point2D<f32>,
+                                transform: &Matrix4) -> Option<(Point2D<f32>, f32)> {
+    let m = transform;
+    let sx = screen_point.x;
+    let sy = screen_point.y;
+
+    // Solve x / w = sx and y / w = sy for the layer point (u, v, 0, 1).
+    let a = m.m11 - sx * m.m14;
+    let b = m.m21 - sx * m.m24;
+    let c = m.m12 - sy * m.m14;
+    let d = m.m22 - sy * m.m24;
+    let e = sx * m.m44 - m.m41;
+    let f = sy * m.m44 - m.m42;
+
+    let determinant = a * d - b * c;
+    if determinant.abs() < 1.0e-6 {
+        return None;
+    }
+
+    let u = (e * d - b * f) / determinant;
+    let v = (a * f - e * c) / determinant;
+
+    let w = u * m.m14 + v * m.m24 + m.m44;
+    if w < W_CLIPPING_PLANE {
+        return None;
+    }
+
+    let z = (u * m.m13 + v * m.m23 + m.m43) / w;
+    Some((Point2D::new(u, v), z))
+}
+
+/// Returns the homography that maps screen points `(x, y, 1)` to homogeneous points on the
+/// z = 0 plane of `transform`, as a row-major 3x3 matrix. Dividing by the third component
+/// gives the same point as `unproject_point_to_plane`, and the third component is positive
+/// for points in front of the viewer. Returns `None` if the plane is seen edge-on.
+pub fn screen_to_plane_homography(transform: &Matrix4) -> Option<[f32; 9]> {
+    let m = transform;
+
+    // The homography from the plane to the screen, which maps (u, v, 1) to (x, y, w).
+    let (a, b, c) = (m.m11, m.m21, m.m41);
+    let (d, e, f) = (m.m12, m.m22, m.m42);
+    let (g, h, i) = (m.m14, m.m24, m.m44);
+
+    let determinant = a * (e * i - f * h) - b * (d * i - f * g) + c * (d * h - e * g);
+    if determinant.abs() < 1.0e-6 {
+        return None;
+    }
+
+    let inverse_determinant = 1.0 / determinant;
+    Some([(e * i - f * h) * inverse_determinant,
+          (c * h - b * i) * inverse_determinant,
+          (b * f - c * e) * inverse_determinant,
+          (f * g - d * i) * inverse_determinant,
+          (a * i - c * g) * inverse_determinant,
+          (c * d - a * f) * inverse_determinant,
+          (d * h - e * g) * inverse_determinant,
+          (b * g - a * h) * inverse_determinant,
+          (a * e - b * d) * inverse_determinant])
+}
+
+/// Projects the corners of `rect` to the screen, clipping against the near plane. Unlike
+/// `project_rect_to_screen`, this keeps the projected polygon (with depth) rather than its
+/// bounding box. The vertices are returned in order around the polygon.
+pub fn project_rect_to_polygon(rect: &Rect<f32>,
+                               transform: &Matrix4) -> Option<Vec<Point3D<f32>>> {
+    let vertices_clip_space = [
+        transform.transform_point4d(&Point4D::new(rect.min_x(), rect.min_y(), 0.0, 1.0)),
+        transform.transform_point4d(&Point4D::new(rect.max_x(), rect.min_y(), 0.0, 1.0)),
+        transform.transform_point4d(&Point4D::new(rect.max_x(), rect.max_y(), 0.0, 1.0)),
+        transform.transform_point4d(&Point4D::new(rect.min_x(), rect.max_y(), 0.0, 1.0)),
+    ];
+
+    clip_polygon_to_near_plane(&vertices_clip_space).map(|clipped_vertices| {
+        clipped_vertices.iter().map(|vertex_cs| {
+            let inv_w = 1.0 / vertex_cs.w;
+            Point3D::new(vertex_cs.x * inv_w, vertex_cs.y * inv_w, vertex_cs.z * inv_w)
+        }).collect()
+    })
+}
+
+/// Returns true if `point` lies inside the convex `polygon`, whichever way it is wound.
+pub fn point_in_convex_polygon(point: &Point2D<f32>, polygon: &[Point2D<f32>]) -> bool {
+    let mut has_positive = false;
+    let mut has_negative = false;
+    for i in 0..polygon.len() {
+        let a = &polygon[i];
+        let b = &polygon[(i + 1) % polygon.len()];
+        let cross = (b.x - a.x) * (point.y - a.y) - (b.y - a.y) * (point.x - a.x);
+        if cross > 0.0 {
+            has_positive = true;
+        } else if cross < 0.0 {
+            has_negative = true;
+        }
+        if has_positive && has_negative {
+            return false;
+        }
+    }
+    true
+}
+
+/// Clips a convex polygon against the half-plane where `distance` is non-negative, which is
+/// one step of the Sutherland-Hodgman algorithm. `distance` must vary linearly over the plane.
+fn clip_polygon_to_half_plane<F>(polygon: &[Point2D<f32>], distance: F) -> Vec<Point2D<f32>>
+                                 where F: Fn(&Point2D<f32>) -> f32 {
+    let mut result = vec!();
+    for i in 0..polygon.len() {
+        let current = polygon[i];
+        let next = polygon[(i + 1) % polygon.len()];
+        let current_distance = distance(&current);
+        let next_distance = distance(&next);
+
+        if current_distance >= 0.0 {
+            result.push(current);
+        }
+        if (current_distance >= 0.0) != (next_distance >= 0.0) {
+            let t = current_distance / (current_distance - next_distance);
+            result.push(Point2D::new(current.x + (next.x - current.x) * t,
+                                     current.y + (next.y - current.y) * t));
+        }
+    }
+    result
+}
+
+/// Returns twice the signed area of a polygon, which is positive if the vertices are ordered
+/// counter-clockwise in a y-up coordinate system.
+fn signed_area(polygon: &[Point2D<f32>]) -> f32 {
+    let mut area = 0.0;
+    for i in 0..polygon.len() {
+        let a = &polygon[i];
+        let b = &polygon[(i + 1) % polygon.len()];
+        area += a.x * b.y - b.x * a.y;
+    }
+    area
+}
+
+/// Clips a convex polygon to a rectangle.
+pub fn clip_convex_polygon_to_rect(polygon: &[Point2D<f32>],
+                                   rect: &Rect<f32>) -> Vec<Point2D<f32>> {
+    let mut result = clip_polygon_to_half_plane(polygon, |p| p.x - rect.min_x());
+    result = clip_polygon_to_half_plane(&result, |p| rect.max_x() - p.x);
+    result = clip_polygon_to_half_plane(&result, |p| p.y - rect.min_y());
+    result = clip_polygon_to_half_plane(&result, |p| rect.max_y() - p.y);
+
+    if result.len() < 3 {
+        return vec!();
+    }
+    result
+}
+
+/// Intersects two convex polygons. The result is empty if they don't overlap.
+pub fn intersect_convex_polygons(subject: &[Point2D<f32>],
+                                 clip: &[Point2D<f32>]) -> Vec<Point2D<f32>> {
+    let orientation = if signed_area(clip) < 0.0 { -1.0 } else { 1.0 };
+
+    let mut result = subject.to_vec();
+    for i in 0..clip.len() {
+        let a = clip[i];
+        let b = clip[(i + 1) % clip.len()];
+        result = clip_polygon_to_half_plane(&result, |p| {
+            orientation * ((b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x))
+        });
+        if result.len() < 3 {
+            return vec!();
+        }
+    }
+    result
+}
+
+/// Clips a polygon lying on the z = 0 plane of a layer, keeping only the part that `transform`
+/// projects inside the convex screen space polygon `screen_polygon`. The part behind the near
+/// plane is removed as well. Since a projective transform maps each screen space edge to a
+/// line on the layer plane, this clipping is exact even for perspective transforms.
+pub fn clip_layer_polygon_to_screen_polygon(polygon: &[Point2D<f32>],
+                                            transform: &Matrix4,
+                                            screen_polygon: &[Point2D<f32>])
+                                            -> Vec<Point2D<f32>> {
+    let m = transform;
+    let clip_x = |p: &Point2D<f32>| p.x * m.m11 + p.y * m.m21 + m.m41;
+    let clip_y = |p: &Point2D<f32>| p.x * m.m12 + p.y * m.m22 + m.m42;
+    let clip_w = |p: &Point2D<f32>| p.x * m.m14 + p.y * m.m24 + m.m44;
+
+    let mut result = clip_polygon_to_half_plane(polygon, |p| clip_w(p) - W_CLIPPING_PLANE);
+
+    // With w > 0, a point projects inside an edge from a to b exactly when the cross product
+    // of the edge and the projected point, multiplied through by w, is positive. That product
+    // is linear on the layer plane.
+    let orientation = if signed_area(screen_polygon) < 0.0 { -1.0 } else { 1.0 };
+    for i in 0..screen_polygon.len() {
+        if result.len() < 3 {
+            return vec!();
+        }
+
+        let a = screen_polygon[i];
+        let b = screen_polygon[(i + 1) % screen_polygon.len()];
+        result = clip_polygon_to_half_plane(&result, |p| {
+            let w = clip_w(p);
+            orientation * ((b.x - a.x) * (clip_y(p) - a.y * w) -
+                           (b.y - a.y) * (clip_x(p) - a.x * w))
+        });
+    }
+
+    if result.len() < 3 {
+        return vec!();
+    }
+    result
+}
+
+/// Returns the corners of a rectangle as a polygon.
+pub fn rect_to_polygon(rect: &Rect<f32>) -> Vec<Point2D<f32>> {
+    vec!(rect.origin, rect.top_right(), rect.bottom_right(), rect.bottom_left())
+}
+
+/// Returns the polygon as a rectangle if it is an axis-aligned rectangle, allowing for the
+/// rounding errors introduced by clipping.
+pub fn polygon_as_rect(polygon: &[Point2D<f32>]) -> Option<Rect<f32>> {
+    if polygon.len() < 4 {
+        return None;
+    }
+
+    let rect = polygon_bounding_rect(polygon);
+    let is_on_corner = |point: &Point2D<f32>| {
+        ((point.x - rect.min_x()).abs() < 1.0e-3 || (point.x - rect.max_x()).abs() < 1.0e-3) &&
+        ((point.y - rect.min_y()).abs() < 1.0e-3 || (point.y - rect.max_y()).abs() < 1.0e-3)
+    };
+
+    if polygon.iter().all(|point| is_on_corner(point)) {
+        Some(rect)
+    } else {
+        None
+    }
+}
+
+/// Returns the smallest rectangle containing all points of `polygon`.
+pub fn polygon_bounding_rect(polygon: &[Point2D<f32>]) -> Rect<f32> {
+    let mut min = Point2D::new(f32::MAX, f32::MAX);
+    let mut max = Point2D::new(-f32::MAX, -f32::MAX);
+    for point in polygon.iter() {
+        min.x = min.x.min(point.x);
+        min.y = min.y.min(point.y);
+        max.x = max.x.max(point.x);
+        max.y = max.y.max(point.y);
+    }
+
+    if polygon.is_empty() {
+        return Rect::zero();
+    }
+    Rect::new(min, Size2D::new(max.x - min.x, max.y - min.y))
+}